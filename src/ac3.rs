@@ -0,0 +1,1227 @@
+/* Native AC-3 (ATSC A/52) decoder: one syncframe in, 1536 float samples per channel out */
+use std::f64::consts::PI;
+use anyhow::{bail, ensure, Context};
+
+/// Samples per channel in one AC-3 syncframe (6 audio blocks of 256)
+pub const FRAME_SAMPLES: usize = 1536;
+/// Output channels, in WAVE order: FL FR FC LFE SL SR
+pub const OUT_CHANNELS: usize = 6;
+
+const BLOCKS: usize = 6;
+const BLOCK_SAMPLES: usize = 256;
+const CPL: usize = 5; // coupling channel, after the (at most 5) full bandwidth channels
+const LFE: usize = 6;
+
+pub const SAMPLE_RATES: [u32; 3] = [48_000, 44_100, 32_000];
+pub const BITRATES_KBPS: [usize; 19] = [32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384, 448, 512, 576, 640];
+const FULL_CHANNELS: [usize; 8] = [2, 1, 2, 3, 3, 4, 4, 5];
+
+// Bit allocation tables (A/52 section 7.2)
+const SLOW_DECAY: [i32; 4] = [0x0f, 0x11, 0x13, 0x15];
+const FAST_DECAY: [i32; 4] = [0x3f, 0x53, 0x67, 0x7b];
+const SLOW_GAIN: [i32; 4] = [0x540, 0x4d8, 0x478, 0x410];
+const DB_PER_BIT: [i32; 4] = [0x000, 0x700, 0x900, 0xb00];
+const FLOOR: [i32; 8] = [0x2f0, 0x2b0, 0x270, 0x230, 0x1f0, 0x170, 0x0f0, -0x800];
+const FAST_GAIN: [i32; 8] = [0x080, 0x100, 0x180, 0x200, 0x280, 0x300, 0x380, 0x400];
+
+/// First bin of each of the 50 bit allocation bands, plus the end of the last one
+const BAND_START: [usize; 51] = [
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27,
+    28, 31, 34, 37, 40, 43, 46, 49, 55, 61, 67, 73, 79, 85, 97, 109, 121, 133, 157, 181, 205, 229, 253,
+];
+
+const BAP: [u8; 64] = [
+    0, 1, 1, 1, 1, 1, 2, 2, 3, 3, 3, 4, 4, 5, 5, 6, 6, 6, 6, 7, 7, 7, 7, 8, 8, 8, 8, 9, 9, 9, 9, 10,
+    10, 10, 10, 11, 11, 11, 11, 12, 12, 12, 12, 13, 13, 13, 13, 14, 14, 14, 14, 14, 14, 14, 14, 15, 15, 15, 15, 15, 15, 15, 15, 15,
+];
+
+const LATAB: [u16; 260] = [
+    0x40, 0x3f, 0x3e, 0x3d, 0x3c, 0x3b, 0x3a, 0x39, 0x38, 0x37, 0x36, 0x35, 0x34,
+    0x34, 0x33, 0x32, 0x31, 0x30, 0x2f, 0x2f, 0x2e, 0x2d, 0x2c, 0x2c, 0x2b, 0x2a,
+    0x29, 0x29, 0x28, 0x27, 0x26, 0x26, 0x25, 0x24, 0x24, 0x23, 0x23, 0x22, 0x21,
+    0x21, 0x20, 0x20, 0x1f, 0x1e, 0x1e, 0x1d, 0x1d, 0x1c, 0x1c, 0x1b, 0x1b, 0x1a,
+    0x1a, 0x19, 0x19, 0x18, 0x18, 0x17, 0x17, 0x16, 0x16, 0x15, 0x15, 0x15, 0x14,
+    0x14, 0x13, 0x13, 0x13, 0x12, 0x12, 0x12, 0x11, 0x11, 0x11, 0x10, 0x10, 0x10,
+    0x0f, 0x0f, 0x0f, 0x0e, 0x0e, 0x0e, 0x0d, 0x0d, 0x0d, 0x0d, 0x0c, 0x0c, 0x0c,
+    0x0c, 0x0b, 0x0b, 0x0b, 0x0b, 0x0a, 0x0a, 0x0a, 0x0a, 0x0a, 0x09, 0x09, 0x09,
+    0x09, 0x09, 0x08, 0x08, 0x08, 0x08, 0x08, 0x08, 0x07, 0x07, 0x07, 0x07, 0x07,
+    0x07, 0x06, 0x06, 0x06, 0x06, 0x06, 0x06, 0x06, 0x06, 0x05, 0x05, 0x05, 0x05,
+    0x05, 0x05, 0x05, 0x05, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04,
+    0x04, 0x04, 0x03, 0x03, 0x03, 0x03, 0x03, 0x03, 0x03, 0x03, 0x03, 0x03, 0x03,
+    0x03, 0x03, 0x03, 0x02, 0x02, 0x02, 0x02, 0x02, 0x02, 0x02, 0x02, 0x02, 0x02,
+    0x02, 0x02, 0x02, 0x02, 0x02, 0x02, 0x02, 0x02, 0x02, 0x01, 0x01, 0x01, 0x01,
+    0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01,
+    0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01, 0x01,
+    0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// Hearing threshold per band, columns by fscod (48 kHz, 44.1 kHz, 32 kHz)
+const HEARING_THRESHOLD: [[i32; 3]; 50] = [
+    [0x4d0, 0x4f0, 0x580], [0x4d0, 0x4f0, 0x580], [0x440, 0x460, 0x4b0], [0x400, 0x410, 0x450],
+    [0x3e0, 0x3e0, 0x420], [0x3c0, 0x3d0, 0x3f0], [0x3b0, 0x3c0, 0x3e0], [0x3b0, 0x3b0, 0x3d0],
+    [0x3a0, 0x3b0, 0x3c0], [0x3a0, 0x3a0, 0x3b0], [0x3a0, 0x3a0, 0x3b0], [0x3a0, 0x3a0, 0x3b0],
+    [0x3a0, 0x3a0, 0x3a0], [0x390, 0x3a0, 0x3a0], [0x390, 0x390, 0x3a0], [0x390, 0x390, 0x3a0],
+    [0x380, 0x390, 0x3a0], [0x380, 0x380, 0x3a0], [0x370, 0x380, 0x3a0], [0x370, 0x380, 0x3a0],
+    [0x360, 0x370, 0x390], [0x360, 0x370, 0x390], [0x350, 0x360, 0x390], [0x350, 0x360, 0x390],
+    [0x340, 0x350, 0x380], [0x340, 0x350, 0x380], [0x330, 0x340, 0x380], [0x320, 0x340, 0x370],
+    [0x310, 0x320, 0x360], [0x300, 0x310, 0x350], [0x2f0, 0x300, 0x340], [0x2f0, 0x2f0, 0x330],
+    [0x2f0, 0x2f0, 0x320], [0x2f0, 0x2f0, 0x310], [0x300, 0x2f0, 0x300], [0x310, 0x300, 0x2f0],
+    [0x340, 0x320, 0x2f0], [0x390, 0x350, 0x2f0], [0x3e0, 0x390, 0x300], [0x420, 0x3e0, 0x310],
+    [0x460, 0x420, 0x330], [0x490, 0x450, 0x350], [0x4a0, 0x4a0, 0x3c0], [0x460, 0x490, 0x410],
+    [0x440, 0x460, 0x470], [0x440, 0x440, 0x4a0], [0x520, 0x480, 0x460], [0x800, 0x630, 0x440],
+    [0x840, 0x840, 0x450], [0x840, 0x840, 0x4e0],
+];
+
+/// Start bins of the stereo rematrixing bands
+const REMAT_BANDS: [usize; 5] = [13, 25, 37, 61, 253];
+
+/// What the sync info and bit stream information of a frame say
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ac3Header {
+    pub sample_rate: u32,
+    pub frame_bytes: usize,
+    pub bsid: u8,
+    pub acmod: u8,
+    pub lfe: bool,
+    fscod: usize,
+}
+
+impl Ac3Header {
+    /// Full bandwidth channels, without LFE
+    pub fn full_channels(&self) -> usize {
+        FULL_CHANNELS[self.acmod as usize]
+    }
+
+    /// Syncinfo and BSI of the frame starting at `frame[0]`
+    pub fn parse(frame: &[u8]) -> anyhow::Result<Self> {
+        Self::read(&mut BitReader::new(frame))
+    }
+
+    /// Syncinfo and BSI, leaves `br` at the first audio block
+    fn read(br: &mut BitReader) -> anyhow::Result<Self> {
+        ensure!(br.read(16)? == 0x0B77, "no AC-3 sync word");
+        br.skip(16)?; // crc1
+        let fscod = br.read(2)? as usize;
+        let frmsizecod = br.read(6)? as usize;
+        ensure!(fscod < 3, "reserved AC-3 sample rate code");
+        ensure!(frmsizecod < 38, "reserved AC-3 frame size code {frmsizecod}");
+        let kbps = BITRATES_KBPS[frmsizecod / 2];
+        let words = match fscod {
+            0 => 2 * kbps,
+            1 => kbps * 96_000 / 44_100 + (frmsizecod & 1),
+            _ => 3 * kbps,
+        };
+
+        let bsid = br.read(5)? as u8;
+        ensure!(bsid <= 8, "unsupported AC-3 bsid {bsid} (E-AC-3?)");
+        br.skip(3)?; // bsmod
+        let acmod = br.read(3)? as u8;
+        if acmod & 1 != 0 && acmod != 1 {
+            br.skip(2)?; // cmixlev
+        }
+        if acmod & 4 != 0 {
+            br.skip(2)?; // surmixlev
+        }
+        if acmod == 2 {
+            br.skip(2)?; // dsurmod
+        }
+        let lfe = br.flag()?;
+        // dialnorm, compr, langcod, audprodinfo, and their second set for dual mono
+        for _ in 0..if acmod == 0 { 2 } else { 1 } {
+            br.skip(5)?;
+            if br.flag()? { br.skip(8)?; }
+            if br.flag()? { br.skip(8)?; }
+            if br.flag()? { br.skip(7)?; }
+        }
+        br.skip(2)?; // copyrightb, origbs
+        if br.flag()? { br.skip(14)?; } // timecod1
+        if br.flag()? { br.skip(14)?; } // timecod2
+        if br.flag()? {
+            let addbsil = br.read(6)? as usize;
+            br.skip((addbsil + 1) * 8)?;
+        }
+
+        Ok(Self { sample_rate: SAMPLE_RATES[fscod], frame_bytes: words * 2, bsid, acmod, lfe, fscod })
+    }
+}
+
+/// MSB-first reader over one frame
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn read(&mut self, bits: usize) -> anyhow::Result<u32> {
+        let mut v = 0u32;
+        for _ in 0..bits {
+            let byte = *self.data.get(self.pos / 8).context("AC-3 frame truncated")?;
+            v = v << 1 | ((byte >> (7 - self.pos % 8)) & 1) as u32;
+            self.pos += 1;
+        }
+        Ok(v)
+    }
+
+    fn flag(&mut self) -> anyhow::Result<bool> {
+        Ok(self.read(1)? == 1)
+    }
+
+    fn skip(&mut self, bits: usize) -> anyhow::Result<()> {
+        ensure!(self.pos + bits <= self.data.len() * 8, "AC-3 frame truncated");
+        self.pos += bits;
+        Ok(())
+    }
+}
+
+/// Delta bit allocation segment: (band offset, length in bands, delta code)
+type DeltaSegment = (usize, usize, i32);
+
+/// Side information that later blocks of a frame may reuse. Arrays indexed by channel
+/// are laid out as the full bandwidth channels, then `CPL`, then `LFE`.
+struct AudioBlock {
+    blksw: [bool; 5],
+    dithflag: [bool; 5],
+    dynrng: [f32; 2],
+
+    cplinu: bool,
+    chincpl: [bool; 5],
+    phsflginu: bool,
+    cplbegf: usize,
+    cpl_bands: Vec<(usize, usize)>, // bin range of each coupling band
+    cplco: [[f32; 18]; 5],
+    phsflg: [bool; 18],
+
+    rematflg: [bool; 4],
+    nrematbd: usize,
+
+    expstr: [u32; 7],
+    exps: [[u8; 256]; 7],
+    start: [usize; 7],
+    end: [usize; 7],
+
+    sdcycod: usize,
+    fdcycod: usize,
+    sgaincod: usize,
+    dbpbcod: usize,
+    floorcod: usize,
+    csnroffst: i32,
+    fsnroffst: [i32; 7],
+    fgaincod: [usize; 7],
+    cplfleak: i32,
+    cplsleak: i32,
+    delta: [Vec<DeltaSegment>; 7],
+
+    bap: [[u8; 256]; 7],
+    mant: [[f32; 256]; 7], // dequantized mantissas, before exponents
+}
+
+impl AudioBlock {
+    fn new() -> Self {
+        Self {
+            blksw: [false; 5],
+            dithflag: [false; 5],
+            dynrng: [1.0; 2],
+            cplinu: false,
+            chincpl: [false; 5],
+            phsflginu: false,
+            cplbegf: 0,
+            cpl_bands: Vec::new(),
+            cplco: [[0.0; 18]; 5],
+            phsflg: [false; 18],
+            rematflg: [false; 4],
+            nrematbd: 0,
+            expstr: [0; 7],
+            exps: [[0; 256]; 7],
+            start: [0; 7],
+            end: [0; 7],
+            sdcycod: 0,
+            fdcycod: 0,
+            sgaincod: 0,
+            dbpbcod: 0,
+            floorcod: 0,
+            csnroffst: 0,
+            fsnroffst: [0; 7],
+            fgaincod: [0; 7],
+            cplfleak: 0,
+            cplsleak: 0,
+            delta: Default::default(),
+            bap: [[0; 256]; 7],
+            mant: [[0.0; 256]; 7],
+        }
+    }
+}
+
+/// Grouped mantissas (bap 1, 2 and 4) pack several values in one code, and a group
+/// may be shared by consecutive channels of the same block.
+#[derive(Default)]
+struct MantissaGroups {
+    bap1: ([f32; 3], usize),
+    bap2: ([f32; 3], usize),
+    bap4: ([f32; 2], usize),
+}
+
+impl MantissaGroups {
+    fn read(&mut self, br: &mut BitReader, bap: u8) -> anyhow::Result<f32> {
+        fn next<const N: usize>(group: &mut ([f32; N], usize)) -> f32 {
+            group.1 -= 1;
+            group.0[N - 1 - group.1]
+        }
+        Ok(match bap {
+            0 => 0.0,
+            1 => {
+                if self.bap1.1 == 0 {
+                    let c = br.read(5)?;
+                    self.bap1 = ([symmetric(c / 9, 3), symmetric(c % 9 / 3, 3), symmetric(c % 3, 3)], 3);
+                }
+                next(&mut self.bap1)
+            }
+            2 => {
+                if self.bap2.1 == 0 {
+                    let c = br.read(7)?;
+                    self.bap2 = ([symmetric(c / 25, 5), symmetric(c % 25 / 5, 5), symmetric(c % 5, 5)], 3);
+                }
+                next(&mut self.bap2)
+            }
+            3 => symmetric(br.read(3)?, 7),
+            4 => {
+                if self.bap4.1 == 0 {
+                    let c = br.read(7)?;
+                    self.bap4 = ([symmetric(c / 11, 11), symmetric(c % 11, 11)], 2);
+                }
+                next(&mut self.bap4)
+            }
+            5 => symmetric(br.read(4)?, 15),
+            _ => {
+                // two's complement fractions
+                let bits = match bap { 14 => 14, 15 => 16, _ => bap as usize - 1 };
+                let shift = 32 - bits as u32;
+                ((br.read(bits)? << shift) as i32 >> shift) as f32 / (1u32 << (bits - 1)) as f32
+            }
+        })
+    }
+}
+
+/// Value of code `k` of a symmetric quantizer with `levels` levels
+fn symmetric(k: u32, levels: u32) -> f32 {
+    (2 * k as i32 - (levels as i32 - 1)) as f32 / levels as f32
+}
+
+/// Gain of a dynrng code: 2^X * (32 + Y) / 32 with X signed in the top 3 bits
+fn dynrng_gain(code: u32) -> f32 {
+    let x = (code as u8 as i8) >> 5;
+    2f32.powi(x as i32) * (32 + (code & 0x1f)) as f32 / 32.0
+}
+
+pub struct Ac3Decoder {
+    imdct: Imdct,
+    delay: [[f32; BLOCK_SAMPLES]; 7],
+    pcm: [[f32; FRAME_SAMPLES]; OUT_CHANNELS],
+    masktab: [usize; 256],
+    dither: u32,
+}
+
+impl Default for Ac3Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Ac3Decoder {
+    pub fn new() -> Self {
+        let mut masktab = [0; 256];
+        for band in 0..50 {
+            masktab[BAND_START[band]..BAND_START[band + 1]].fill(band);
+        }
+        Self {
+            imdct: Imdct::new(),
+            delay: [[0.0; BLOCK_SAMPLES]; 7],
+            pcm: [[0.0; FRAME_SAMPLES]; OUT_CHANNELS],
+            masktab,
+            dither: 1,
+        }
+    }
+
+    /// Output of the last decoded frame, in WAVE order (FL FR FC LFE SL SR).
+    /// Channels missing from the stream are silent.
+    pub fn pcm(&self) -> &[[f32; FRAME_SAMPLES]; OUT_CHANNELS] {
+        &self.pcm
+    }
+
+    /// Decode one syncframe. On error the overlap state is kept, the caller
+    /// should fill the frame with silence.
+    pub fn decode(&mut self, frame: &[u8]) -> anyhow::Result<Ac3Header> {
+        let mut br = BitReader::new(frame);
+        let header = Ac3Header::read(&mut br)?;
+        ensure!(frame.len() >= header.frame_bytes, "AC-3 frame truncated");
+        br.data = &frame[..header.frame_bytes];
+        // crc1 covers the first 5/8 of the frame after the sync word, crc2 the rest
+        let split = 2 * ((header.frame_bytes >> 2) + (header.frame_bytes >> 4));
+        ensure!(crc16(&br.data[2..split]) == 0, "AC-3 crc1 mismatch");
+        ensure!(crc16(&br.data[split..]) == 0, "AC-3 crc2 mismatch");
+
+        let nfch = header.full_channels();
+        let mut ab = AudioBlock::new();
+        let mut out = [[0.0f32; FRAME_SAMPLES]; 7]; // decoded channel order, LFE at `LFE`
+        for blk in 0..BLOCKS {
+            self.audio_block(&mut br, &header, blk, &mut ab)?;
+
+            let mut coeffs = [[0.0f32; 256]; 7];
+            self.coefficients(&header, &ab, &mut coeffs);
+
+            let samples = blk * BLOCK_SAMPLES..(blk + 1) * BLOCK_SAMPLES;
+            for ch in (0..nfch).chain(header.lfe.then_some(LFE)) {
+                let short = ch < 5 && ab.blksw[ch];
+                self.imdct.run(&coeffs[ch], short, &mut self.delay[ch], &mut out[ch][samples.clone()]);
+            }
+        }
+
+        self.pcm = [[0.0; FRAME_SAMPLES]; OUT_CHANNELS];
+        for (ch, targets) in wave_channels(header.acmod).iter().enumerate() {
+            let gain = if targets.len() > 1 { std::f32::consts::FRAC_1_SQRT_2 } else { 1.0 };
+            for &t in targets.iter() {
+                self.pcm[t].iter_mut().zip(&out[ch]).for_each(|(o, s)| *o = s * gain);
+            }
+        }
+        if header.lfe {
+            self.pcm[3] = out[LFE];
+        }
+        Ok(header)
+    }
+
+    fn next_dither(&mut self) -> f32 {
+        // xorshift32, scaled to +-0.707 like the reference decoder
+        self.dither ^= self.dither << 13;
+        self.dither ^= self.dither >> 17;
+        self.dither ^= self.dither << 5;
+        (self.dither as i32 as f32 / i32::MAX as f32) * std::f32::consts::FRAC_1_SQRT_2
+    }
+
+    /// Parse the side information and the mantissas of one audio block (A/52 5.4.3)
+    fn audio_block(&mut self, br: &mut BitReader, header: &Ac3Header, blk: usize, ab: &mut AudioBlock) -> anyhow::Result<()> {
+        let nfch = header.full_channels();
+        let acmod = header.acmod;
+        let first = blk == 0;
+
+        for ch in 0..nfch { ab.blksw[ch] = br.flag()?; }
+        for ch in 0..nfch { ab.dithflag[ch] = br.flag()?; }
+        for i in 0..if acmod == 0 { 2 } else { 1 } {
+            if br.flag()? {
+                ab.dynrng[i] = dynrng_gain(br.read(8)?);
+            }
+        }
+
+        // coupling strategy
+        if br.flag()? {
+            ab.cplinu = br.flag()?;
+            ab.chincpl = [false; 5];
+            ab.phsflginu = false;
+            if ab.cplinu {
+                for ch in 0..nfch { ab.chincpl[ch] = br.flag()?; }
+                if acmod == 2 { ab.phsflginu = br.flag()?; }
+                let cplbegf = br.read(4)? as usize;
+                let cplendf = br.read(4)? as usize;
+                ensure!(cplendf + 3 > cplbegf, "AC-3 coupling range is empty");
+                ab.cplbegf = cplbegf;
+                ab.start[CPL] = cplbegf * 12 + 37;
+                ab.end[CPL] = (cplendf + 3) * 12 + 37;
+                ab.cpl_bands = vec![(ab.start[CPL], ab.start[CPL] + 12)];
+                for _ in 1..3 + cplendf - cplbegf {
+                    let last = ab.cpl_bands.last().map_or(0, |b| b.1);
+                    if br.flag()? {
+                        ab.cpl_bands.last_mut().unwrap().1 += 12;
+                    } else {
+                        ab.cpl_bands.push((last, last + 12));
+                    }
+                }
+            }
+        } else {
+            ensure!(!first, "AC-3 block 0 without coupling strategy");
+        }
+
+        // coupling coordinates
+        if ab.cplinu {
+            let mut new_coords = false;
+            for ch in (0..nfch).filter(|&ch| ab.chincpl[ch]) {
+                if br.flag()? {
+                    new_coords = true;
+                    let mstrcplco = br.read(2)? as i32;
+                    for bnd in 0..ab.cpl_bands.len() {
+                        let exp = br.read(4)? as i32;
+                        let mant = br.read(4)?;
+                        let mant = if exp == 15 { mant as f32 / 16.0 } else { (mant + 16) as f32 / 32.0 };
+                        ab.cplco[ch][bnd] = mant * 2f32.powi(-(exp + 3 * mstrcplco)) * 8.0;
+                    }
+                }
+            }
+            if acmod == 2 && ab.phsflginu && new_coords {
+                for bnd in 0..ab.cpl_bands.len() { ab.phsflg[bnd] = br.flag()?; }
+            }
+        }
+
+        // rematrixing, over bands that depend on where coupling starts in this block
+        if acmod == 2 {
+            ab.nrematbd = match (ab.cplinu, ab.cplbegf) {
+                (false, _) | (true, 3..) => 4,
+                (true, 1..=2) => 3,
+                (true, _) => 2,
+            };
+            if br.flag()? {
+                for band in 0..ab.nrematbd { ab.rematflg[band] = br.flag()?; }
+            } else {
+                ensure!(!first, "AC-3 block 0 without rematrixing strategy");
+            }
+        }
+
+        // exponent strategies and bandwidth
+        if ab.cplinu { ab.expstr[CPL] = br.read(2)?; }
+        for ch in 0..nfch { ab.expstr[ch] = br.read(2)?; }
+        if header.lfe { ab.expstr[LFE] = br.read(1)?; }
+        if first {
+            let reused = (0..nfch).chain(ab.cplinu.then_some(CPL)).chain(header.lfe.then_some(LFE))
+                .any(|ch| ab.expstr[ch] == 0);
+            ensure!(!reused, "AC-3 block 0 reuses exponents");
+        }
+        for ch in 0..nfch {
+            if ab.expstr[ch] != 0 {
+                ab.end[ch] = if ab.chincpl[ch] {
+                    ab.start[CPL]
+                } else {
+                    let chbwcod = br.read(6)? as usize;
+                    ensure!(chbwcod <= 60, "AC-3 bandwidth code {chbwcod} out of range");
+                    chbwcod * 3 + 73
+                };
+            }
+        }
+
+        // exponents
+        if ab.cplinu && ab.expstr[CPL] != 0 {
+            let absexp = br.read(4)? << 1;
+            let groups = (ab.end[CPL] - ab.start[CPL]) / (3 << (ab.expstr[CPL] - 1));
+            let start = ab.start[CPL];
+            read_exponents(br, absexp, groups, ab.expstr[CPL], &mut ab.exps[CPL][start..])?;
+        }
+        for ch in 0..nfch {
+            if ab.expstr[ch] != 0 {
+                let group_size = 3 << (ab.expstr[ch] - 1);
+                let groups = (ab.end[ch] - 1 + group_size - 3) / group_size;
+                let absexp = br.read(4)?;
+                ab.exps[ch][0] = absexp as u8;
+                read_exponents(br, absexp, groups, ab.expstr[ch], &mut ab.exps[ch][1..])?;
+                br.skip(2)?; // gainrng
+            }
+        }
+        if header.lfe && ab.expstr[LFE] != 0 {
+            let absexp = br.read(4)?;
+            ab.exps[LFE][0] = absexp as u8;
+            read_exponents(br, absexp, 2, 1, &mut ab.exps[LFE][1..])?;
+            ab.end[LFE] = 7;
+        }
+
+        // bit allocation parametric information
+        if br.flag()? {
+            ab.sdcycod = br.read(2)? as usize;
+            ab.fdcycod = br.read(2)? as usize;
+            ab.sgaincod = br.read(2)? as usize;
+            ab.dbpbcod = br.read(2)? as usize;
+            ab.floorcod = br.read(3)? as usize;
+        } else {
+            ensure!(!first, "AC-3 block 0 without bit allocation info");
+        }
+        if br.flag()? {
+            ab.csnroffst = br.read(6)? as i32;
+            for ch in ab.cplinu.then_some(CPL).into_iter().chain(0..nfch).chain(header.lfe.then_some(LFE)) {
+                ab.fsnroffst[ch] = br.read(4)? as i32;
+                ab.fgaincod[ch] = br.read(3)? as usize;
+            }
+        } else {
+            ensure!(!first, "AC-3 block 0 without SNR offsets");
+        }
+        if ab.cplinu && br.flag()? {
+            ab.cplfleak = br.read(3)? as i32;
+            ab.cplsleak = br.read(3)? as i32;
+        }
+
+        // delta bit allocation
+        if br.flag()? {
+            let channels: Vec<usize> = ab.cplinu.then_some(CPL).into_iter().chain(0..nfch).collect();
+            let mut deltbae = [0; 7];
+            for &ch in &channels { deltbae[ch] = br.read(2)?; }
+            for &ch in &channels {
+                match deltbae[ch] {
+                    0 => {}
+                    1 => {
+                        let segments = br.read(3)? + 1;
+                        ab.delta[ch] = (0..segments)
+                            .map(|_| Ok((br.read(5)? as usize, br.read(4)? as usize, br.read(3)? as i32)))
+                            .collect::<anyhow::Result<_>>()?;
+                    }
+                    2 => ab.delta[ch].clear(),
+                    _ => bail!("reserved AC-3 delta bit allocation mode"),
+                }
+            }
+        }
+
+        // skip field
+        if br.flag()? {
+            let skipl = br.read(9)? as usize;
+            br.skip(skipl * 8)?;
+        }
+
+        for ch in (0..nfch).chain(ab.cplinu.then_some(CPL)).chain(header.lfe.then_some(LFE)) {
+            let start = if ch == CPL { ab.start[CPL] } else { 0 };
+            ab.bap[ch] = self.bit_allocation(ab, ch, start, ab.end[ch], header.fscod);
+        }
+
+        // mantissas, the coupling channel comes right after the first coupled channel
+        let mut groups = MantissaGroups::default();
+        let mut got_cpl = false;
+        for ch in (0..nfch).chain(header.lfe.then_some(LFE)) {
+            for bin in 0..ab.end[ch] {
+                ab.mant[ch][bin] = groups.read(br, ab.bap[ch][bin])?;
+            }
+            if ch < 5 && ab.chincpl[ch] && !got_cpl {
+                for bin in ab.start[CPL]..ab.end[CPL] {
+                    ab.mant[CPL][bin] = groups.read(br, ab.bap[CPL][bin])?;
+                }
+                got_cpl = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Turn the mantissas and exponents of a block into transform coefficients:
+    /// dither, decoupling, rematrixing and dynamic range control.
+    fn coefficients(&mut self, header: &Ac3Header, ab: &AudioBlock, coeffs: &mut [[f32; 256]; 7]) {
+        let nfch = header.full_channels();
+        for ch in (0..nfch).chain(header.lfe.then_some(LFE)) {
+            let dither = ch < 5 && ab.dithflag[ch];
+            for (bin, c) in coeffs[ch][..ab.end[ch]].iter_mut().enumerate() {
+                let mant = match ab.bap[ch][bin] {
+                    0 if dither => self.next_dither(),
+                    _ => ab.mant[ch][bin],
+                };
+                *c = mant * 2f32.powi(-(ab.exps[ch][bin] as i32));
+            }
+        }
+
+        if ab.cplinu {
+            for ch in (0..nfch).filter(|&ch| ab.chincpl[ch]) {
+                for (bnd, &(lo, hi)) in ab.cpl_bands.iter().enumerate() {
+                    let mut co = ab.cplco[ch][bnd];
+                    if header.acmod == 2 && ch == 1 && ab.phsflginu && ab.phsflg[bnd] {
+                        co = -co;
+                    }
+                    for (c, bin) in coeffs[ch][lo..hi].iter_mut().zip(lo..) {
+                        let mant = match ab.bap[CPL][bin] {
+                            0 if ab.dithflag[ch] => self.next_dither(),
+                            _ => ab.mant[CPL][bin],
+                        };
+                        *c = mant * 2f32.powi(-(ab.exps[CPL][bin] as i32)) * co;
+                    }
+                }
+            }
+        }
+
+        if header.acmod == 2 {
+            let end = if ab.cplinu { ab.start[CPL] } else { ab.end[0].min(ab.end[1]) };
+            let (left, right) = coeffs.split_at_mut(1);
+            for band in (0..ab.nrematbd).filter(|&b| ab.rematflg[b]) {
+                let bins = REMAT_BANDS[band].min(end)..REMAT_BANDS[band + 1].min(end);
+                for (l, r) in left[0][bins.clone()].iter_mut().zip(&mut right[0][bins]) {
+                    (*l, *r) = (*l + *r, *l - *r);
+                }
+            }
+        }
+
+        for ch in (0..nfch).chain(header.lfe.then_some(LFE)) {
+            let gain = if header.acmod == 0 && ch == 1 { ab.dynrng[1] } else { ab.dynrng[0] };
+            coeffs[ch].iter_mut().for_each(|c| *c *= gain);
+        }
+    }
+
+    /// Parametric bit allocation (A/52 section 7.2.2), returns the bap of bins `start..end`
+    fn bit_allocation(&self, ab: &AudioBlock, ch: usize, start: usize, end: usize, fscod: usize) -> [u8; 256] {
+        let mut bap = [0u8; 256];
+        let snroffset = (((ab.csnroffst - 15) << 4) + ab.fsnroffst[ch]) << 2;
+        if snroffset == -960 || start >= end {
+            return bap; // csnroffst and fsnroffst both zero: no mantissas at all
+        }
+        let masktab = &self.masktab;
+        let sdecay = SLOW_DECAY[ab.sdcycod];
+        let fdecay = FAST_DECAY[ab.fdcycod];
+        let sgain = SLOW_GAIN[ab.sgaincod];
+        let dbknee = DB_PER_BIT[ab.dbpbcod];
+        let floor = FLOOR[ab.floorcod];
+        let fgain = FAST_GAIN[ab.fgaincod[ch]];
+
+        // exponents to power spectral density, integrated per band
+        let mut psd = [0i32; 256];
+        for (p, &exp) in psd[start..end].iter_mut().zip(&ab.exps[ch][start..end]) {
+            *p = 3072 - ((exp as i32) << 7);
+        }
+        let mut bndpsd = [0i32; 51];
+        let mut bin = start;
+        let mut band = masktab[start];
+        loop {
+            let last = BAND_START[band + 1].min(end);
+            bndpsd[band] = psd[bin + 1..last].iter().fold(psd[bin], |acc, &p| log_add(acc, p));
+            bin = last;
+            band += 1;
+            if end <= last { break; }
+        }
+
+        // excitation function
+        let bndstrt = masktab[start];
+        let bndend = masktab[end - 1] + 1;
+        let mut excite = [0i32; 51];
+        let (mut fastleak, mut slowleak);
+        let mut begin;
+        if bndstrt == 0 {
+            let mut lowcomp = low_comp(0, bndpsd[0], bndpsd[1], 0);
+            excite[0] = bndpsd[0] - fgain - lowcomp;
+            lowcomp = low_comp(lowcomp, bndpsd[1], bndpsd[2], 1);
+            excite[1] = bndpsd[1] - fgain - lowcomp;
+            begin = 7;
+            fastleak = 0;
+            slowleak = 0;
+            for b in 2..7 {
+                let lfe_edge = bndend == 7 && b == 6;
+                if !lfe_edge {
+                    lowcomp = low_comp(lowcomp, bndpsd[b], bndpsd[b + 1], b);
+                }
+                fastleak = bndpsd[b] - fgain;
+                slowleak = bndpsd[b] - sgain;
+                excite[b] = fastleak - lowcomp;
+                if !lfe_edge && bndpsd[b] <= bndpsd[b + 1] {
+                    begin = b + 1;
+                    break;
+                }
+            }
+            for b in begin..bndend.min(22) {
+                if !(bndend == 7 && b == 6) {
+                    lowcomp = low_comp(lowcomp, bndpsd[b], bndpsd[b + 1], b);
+                }
+                fastleak = (fastleak - fdecay).max(bndpsd[b] - fgain);
+                slowleak = (slowleak - sdecay).max(bndpsd[b] - sgain);
+                excite[b] = (fastleak - lowcomp).max(slowleak);
+            }
+            begin = 22;
+        } else {
+            begin = bndstrt;
+            fastleak = (ab.cplfleak << 8) + 768;
+            slowleak = (ab.cplsleak << 8) + 768;
+        }
+        for b in begin..bndend {
+            fastleak = (fastleak - fdecay).max(bndpsd[b] - fgain);
+            slowleak = (slowleak - sdecay).max(bndpsd[b] - sgain);
+            excite[b] = fastleak.max(slowleak);
+        }
+
+        // masking curve, with the delta bit allocation applied
+        let mut mask = [0i32; 51];
+        for b in bndstrt..bndend {
+            if bndpsd[b] < dbknee {
+                excite[b] += (dbknee - bndpsd[b]) >> 2;
+            }
+            mask[b] = excite[b].max(HEARING_THRESHOLD[b][fscod]);
+        }
+        let mut band = 0;
+        for &(offset, len, code) in &ab.delta[ch] {
+            band += offset;
+            let delta = if code >= 4 { (code - 3) << 7 } else { (code - 4) << 7 };
+            for _ in 0..len {
+                if let Some(m) = mask.get_mut(band) { *m += delta; }
+                band += 1;
+            }
+        }
+
+        let mut bin = start;
+        let mut band = masktab[start];
+        loop {
+            let last = BAND_START[band + 1].min(end);
+            let mut m = (mask[band] - snroffset - floor).max(0);
+            m = (m & 0x1fe0) + floor;
+            for b in bin..last {
+                bap[b] = BAP[((psd[b] - m) >> 5).clamp(0, 63) as usize]
+            }
+            bin = last;
+            band += 1;
+            if end <= last { break; }
+        }
+        bap
+    }
+}
+
+/// Differentially coded exponents: `groups` codes of 7 bits, each holding 3 deltas that
+/// apply to 1, 2 or 4 bins depending on the strategy (D15, D25, D45).
+fn read_exponents(br: &mut BitReader, absexp: u32, groups: usize, expstr: u32, out: &mut [u8]) -> anyhow::Result<()> {
+    let repeat = 1 << (expstr - 1);
+    let mut exp = absexp as i32;
+    let mut i = 0;
+    for _ in 0..groups {
+        let code = br.read(7)?;
+        ensure!(code < 125, "invalid AC-3 exponent group");
+        for delta in [code / 25, code % 25 / 5, code % 5] {
+            exp += delta as i32 - 2;
+            ensure!((0..=24).contains(&exp), "AC-3 exponent out of range");
+            let bins = out.get_mut(i..i + repeat).context("AC-3 exponents past the last bin")?;
+            bins.fill(exp as u8);
+            i += repeat;
+        }
+    }
+    Ok(())
+}
+
+/// A/52 CRC (x^16 + x^15 + x^2 + 1, MSB first), zero over a span ending with its own CRC word
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ ((byte as u16) << 8), |crc, _| {
+            if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else { crc << 1 }
+        })
+    })
+}
+
+fn log_add(a: i32, b: i32) -> i32 {
+    let c = a - b;
+    let address = ((c.abs() >> 1) as usize).min(255);
+    if c >= 0 { a + LATAB[address] as i32 } else { b + LATAB[address] as i32 }
+}
+
+fn low_comp(a: i32, b0: i32, b1: i32, band: usize) -> i32 {
+    if band < 7 {
+        if b0 + 256 == b1 { 384 } else if b0 > b1 { (a - 64).max(0) } else { a }
+    } else if band < 20 {
+        if b0 + 256 == b1 { 320 } else if b0 > b1 { (a - 64).max(0) } else { a }
+    } else {
+        (a - 128).max(0)
+    }
+}
+
+/// Where each full bandwidth channel of an `acmod` goes in WAVE order (FL FR FC LFE SL SR).
+/// A single surround channel is spread over both surrounds.
+fn wave_channels(acmod: u8) -> &'static [&'static [usize]] {
+    match acmod {
+        1 => &[&[2]],
+        0 | 2 => &[&[0], &[1]],
+        3 => &[&[0], &[2], &[1]],
+        4 => &[&[0], &[1], &[4, 5]],
+        5 => &[&[0], &[2], &[1], &[4, 5]],
+        6 => &[&[0], &[1], &[4], &[5]],
+        _ => &[&[0], &[2], &[1], &[4], &[5]],
+    }
+}
+
+/// The A/52 inverse transform: one 512-point or two 256-point IMDCTs through
+/// complex FFTs, then KBD windowing and overlap-add with the previous block.
+struct Imdct {
+    pre512: [(f32, f32); 128],
+    pre256: [(f32, f32); 64],
+    twiddle: [(f32, f32); 64],
+    window: [f32; 256],
+}
+
+impl Imdct {
+    fn new() -> Self {
+        let mut pre512 = [(0.0, 0.0); 128];
+        for (k, c) in pre512.iter_mut().enumerate() {
+            let a = 2.0 * PI * (8 * k + 1) as f64 / 4096.0;
+            *c = (-a.cos() as f32, -a.sin() as f32);
+        }
+        let mut pre256 = [(0.0, 0.0); 64];
+        for (k, c) in pre256.iter_mut().enumerate() {
+            let a = 2.0 * PI * (8 * k + 1) as f64 / 2048.0;
+            *c = (-a.cos() as f32, -a.sin() as f32);
+        }
+        let mut twiddle = [(0.0, 0.0); 64];
+        for (k, c) in twiddle.iter_mut().enumerate() {
+            let a = 2.0 * PI * k as f64 / 128.0;
+            *c = (a.cos() as f32, a.sin() as f32);
+        }
+        Self { pre512, pre256, twiddle, window: kbd_window() }
+    }
+
+    /// Transform one block of coefficients into 256 output samples
+    fn run(&self, coeffs: &[f32; 256], short: bool, delay: &mut [f32; BLOCK_SAMPLES], out: &mut [f32]) {
+        let w = &self.window;
+        let mut x = [0f32; 512];
+        if short {
+            let mut y1 = [(0.0, 0.0); 64];
+            let mut y2 = [(0.0, 0.0); 64];
+            for k in 0..64 {
+                y1[k] = cmul((coeffs[2 * (127 - 2 * k)], coeffs[4 * k]), self.pre256[k]);
+                y2[k] = cmul((coeffs[2 * (127 - 2 * k) + 1], coeffs[4 * k + 1]), self.pre256[k]);
+            }
+            self.ifft(&mut y1);
+            self.ifft(&mut y2);
+            for ((a, b), &c) in y1.iter_mut().zip(&mut y2).zip(&self.pre256) {
+                (*a, *b) = (cmul(*a, c), cmul(*b, c));
+            }
+            for n in 0..64 {
+                x[2 * n] = -y1[n].1 * w[2 * n];
+                x[2 * n + 1] = y1[63 - n].0 * w[2 * n + 1];
+                x[128 + 2 * n] = -y1[n].0 * w[128 + 2 * n];
+                x[128 + 2 * n + 1] = y1[63 - n].1 * w[128 + 2 * n + 1];
+                x[256 + 2 * n] = -y2[n].0 * w[255 - 2 * n];
+                x[256 + 2 * n + 1] = y2[63 - n].1 * w[254 - 2 * n];
+                x[384 + 2 * n] = y2[n].1 * w[127 - 2 * n];
+                x[384 + 2 * n + 1] = -y2[63 - n].0 * w[126 - 2 * n];
+            }
+        } else {
+            let mut y = [(0.0, 0.0); 128];
+            for k in 0..128 {
+                y[k] = cmul((coeffs[255 - 2 * k], coeffs[2 * k]), self.pre512[k]);
+            }
+            self.ifft(&mut y);
+            for (v, &c) in y.iter_mut().zip(&self.pre512) {
+                *v = cmul(*v, c);
+            }
+            for n in 0..64 {
+                x[2 * n] = -y[64 + n].1 * w[2 * n];
+                x[2 * n + 1] = y[63 - n].0 * w[2 * n + 1];
+                x[128 + 2 * n] = -y[n].0 * w[128 + 2 * n];
+                x[128 + 2 * n + 1] = y[127 - n].1 * w[128 + 2 * n + 1];
+                x[256 + 2 * n] = -y[64 + n].0 * w[255 - 2 * n];
+                x[256 + 2 * n + 1] = y[63 - n].1 * w[254 - 2 * n];
+                x[384 + 2 * n] = y[n].1 * w[127 - 2 * n];
+                x[384 + 2 * n + 1] = -y[127 - n].0 * w[126 - 2 * n];
+            }
+        }
+
+        for n in 0..BLOCK_SAMPLES {
+            out[n] = 2.0 * (x[n] + delay[n]);
+            delay[n] = x[256 + n];
+        }
+    }
+
+    /// In-place inverse complex FFT (no scaling) of 64 or 128 points
+    fn ifft(&self, buf: &mut [(f32, f32)]) {
+        let n = buf.len();
+        let bits = n.trailing_zeros();
+        for i in 0..n {
+            let j = i.reverse_bits() >> (usize::BITS - bits);
+            if j > i { buf.swap(i, j); }
+        }
+        let mut len = 2;
+        while len <= n {
+            let step = 128 / len;
+            for start in (0..n).step_by(len) {
+                for k in 0..len / 2 {
+                    let a = buf[start + k];
+                    let b = cmul(buf[start + k + len / 2], self.twiddle[k * step]);
+                    buf[start + k] = (a.0 + b.0, a.1 + b.1);
+                    buf[start + k + len / 2] = (a.0 - b.0, a.1 - b.1);
+                }
+            }
+            len <<= 1;
+        }
+    }
+}
+
+fn cmul(a: (f32, f32), b: (f32, f32)) -> (f32, f32) {
+    (a.0 * b.0 - a.1 * b.1, a.0 * b.1 + a.1 * b.0)
+}
+
+/// First half of the 512-point Kaiser-Bessel derived window, alpha = 5
+fn kbd_window() -> [f32; 256] {
+    const N: usize = 256;
+    let alpha2 = (5.0 * PI / N as f64).powi(2);
+    let mut cumulative = [0f64; N];
+    let mut sum = 0.0;
+    for (i, c) in cumulative.iter_mut().enumerate() {
+        let tmp = (i * (N - i)) as f64 * alpha2;
+        let mut bessel = 1.0; // I0 by its power series
+        for j in (1..=50).rev() {
+            bessel = bessel * tmp / (j * j) as f64 + 1.0;
+        }
+        sum += bessel;
+        *c = sum;
+    }
+    sum += 1.0;
+    let mut window = [0f32; N];
+    for (w, c) in window.iter_mut().zip(cumulative) {
+        *w = (c / sum).sqrt() as f32;
+    }
+    window
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A/52 forward transform of one 512-sample block (two 256-point halves when `short`)
+    fn mdct(input: &[f64], window: &[f32; 256], short: bool) -> [f32; 256] {
+        let w = |n: usize| if n < 256 { window[n] } else { window[511 - n] } as f64;
+        let x: Vec<f64> = (0..512).map(|n| input[n] * w(n)).collect();
+        let mut out = [0f32; 256];
+        let transform = |x: &[f64], n_len: usize, alpha: f64, k: usize| -> f64 {
+            let n_f = n_len as f64;
+            (0..n_len).map(|n| x[n] * (2.0 * PI / (4.0 * n_f) * (2 * n + 1) as f64 * (2 * k + 1) as f64
+                + PI / 4.0 * (2 * k + 1) as f64 * (1.0 + alpha)).cos()).sum::<f64>() * -2.0 / n_f
+        };
+        if short {
+            for k in 0..128 {
+                out[2 * k] = transform(&x[..256], 256, -1.0, k) as f32;
+                out[2 * k + 1] = transform(&x[256..], 256, 1.0, k) as f32;
+            }
+        } else {
+            for (k, o) in out.iter_mut().enumerate() {
+                *o = transform(&x, 512, 0.0, k) as f32;
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn parses_header() {
+        // 640 kbps, bsid 8, 3/2 + LFE
+        let mut frame = vec![0x0B, 0x77, 0x00, 0x00, 0x24, 0x40, 0xE1];
+        frame.resize(16, 0);
+        let header = Ac3Header::read(&mut BitReader::new(&frame)).unwrap();
+        assert_eq!((header.sample_rate, header.frame_bytes), (48_000, 2560));
+        assert_eq!((header.acmod, header.lfe, header.full_channels()), (7, true, 5));
+
+        // 44.1 kHz frames alternate between two sizes
+        frame[4] = 0x40 | 37;
+        assert_eq!(Ac3Header::read(&mut BitReader::new(&frame)).unwrap().frame_bytes, 2788);
+        assert!(Ac3Header::read(&mut BitReader::new(&frame[2..])).is_err());
+    }
+
+    /// The first block has nothing to overlap with, every later sample must come back.
+    #[test]
+    fn imdct_reconstructs_long_and_short_blocks() {
+        let imdct = Imdct::new();
+        let signal: Vec<f64> = (0..256 * 8u64).map(|i| (i * i * 7919 % 1009) as f64 / 1009.0 - 0.5).collect();
+        let shorts = [false, false, true, true, false, true, false];
+
+        let mut delay = [0f32; BLOCK_SAMPLES];
+        let mut out = vec![0f32; signal.len()];
+        for (blk, &short) in shorts.iter().enumerate() {
+            let coeffs = mdct(&signal[blk * 256..blk * 256 + 512], &imdct.window, short);
+            imdct.run(&coeffs, short, &mut delay, &mut out[blk * 256..(blk + 1) * 256]);
+        }
+        for i in 256..256 * 7 {
+            assert!((out[i] as f64 - signal[i]).abs() < 1e-4, "sample {i}: {} != {}", out[i], signal[i]);
+        }
+    }
+
+    /// MSB-first writer to build frames with
+    #[derive(Default)]
+    struct BitWriter {
+        bytes: Vec<u8>,
+        bits: usize,
+    }
+
+    impl BitWriter {
+        fn put(&mut self, bits: usize, value: u32) {
+            for i in (0..bits).rev() {
+                if self.bits.is_multiple_of(8) { self.bytes.push(0); }
+                *self.bytes.last_mut().unwrap() |= ((value >> i & 1) as u8) << (7 - self.bits % 8);
+                self.bits += 1;
+            }
+        }
+
+        /// Sync info and BSI of a 48 kHz, 128 kbps 2/0 frame
+        fn stereo_header(&mut self) {
+            self.put(16, 0x0B77);
+            self.put(16, 0); // crc1, see `seal`
+            self.put(2, 0); // fscod
+            self.put(6, 16); // frmsizecod
+            self.put(5, 8); // bsid
+            self.put(3, 0); // bsmod
+            self.put(3, 2); // acmod
+            self.put(2, 0); // dsurmod
+            self.put(1, 0); // lfeon
+            self.put(5, 31); // dialnorm
+            self.put(3, 0); // no compr, langcod, audprodinfo
+            self.put(5, 0); // copyrightb, origbs, no timecodes, no addbsi
+        }
+
+        /// The frame padded to its 512 bytes, with both CRC words filled in
+        fn seal(self) -> Vec<u8> {
+            const SIZE: usize = 512;
+            const SPLIT: usize = 2 * ((SIZE >> 2) + (SIZE >> 4));
+            assert!(self.bytes.len() <= SIZE - 2, "{} bytes do not fit in the frame", self.bytes.len());
+            let mut frame = self.bytes;
+            frame.resize(SIZE, 0);
+            let crc2 = crc16(&frame[SPLIT..SIZE - 2]);
+            frame[SIZE - 2..].copy_from_slice(&crc2.to_be_bytes());
+
+            // crc1 comes first in its span: solve for it, the CRC being linear in each bit
+            let mut span_crc = |crc1: u16| {
+                frame[2..4].copy_from_slice(&crc1.to_be_bytes());
+                crc16(&frame[2..SPLIT])
+            };
+            let zero = span_crc(0);
+            let bits: Vec<u16> = (0..16).map(|i| span_crc(1 << i) ^ zero).collect();
+            let crc1 = (0..=u16::MAX)
+                .find(|&c| (0..16).filter(|i| c >> i & 1 == 1).fold(zero, |acc, i| acc ^ bits[i]) == 0)
+                .unwrap();
+            frame[2..4].copy_from_slice(&crc1.to_be_bytes());
+            frame
+        }
+    }
+
+    const TONE_BIN: usize = 8;
+
+    /// A 2/0 frame with the same coefficient, 0.5 at `TONE_BIN`, in both channels of every
+    /// block. The bit allocation is the decoder's own, as an encoder would compute it.
+    pub(crate) fn tone_frame() -> Vec<u8> {
+        // D15 exponent deltas of bins 1..73: 15 at bin 0, down to 0 at the tone, up to 24
+        let deltas: Vec<i32> = [(7, -2), (1, -1), (1, 1), (11, 2), (1, 1), (51, 0)]
+            .into_iter()
+            .flat_map(|(n, d)| std::iter::repeat_n(d, n))
+            .collect();
+        let mut ab = AudioBlock::new();
+        (ab.sdcycod, ab.fdcycod, ab.sgaincod, ab.dbpbcod, ab.floorcod) = (2, 1, 1, 2, 4);
+        (ab.csnroffst, ab.fsnroffst[0], ab.fgaincod[0]) = (15, 0, 4);
+        ab.exps[0][0] = 15;
+        for (bin, d) in deltas.iter().enumerate() {
+            ab.exps[0][bin + 1] = (ab.exps[0][bin] as i32 + d) as u8;
+        }
+        let bap = Ac3Decoder::new().bit_allocation(&ab, 0, 0, 73, 0);
+        assert!(bap[TONE_BIN] >= 6, "tone quantized with bap {}", bap[TONE_BIN]);
+
+        let mut w = BitWriter::default();
+        w.stereo_header();
+        for blk in 0..BLOCKS {
+            w.put(5, 0); // blksw, dithflag, no dynrng
+            if blk == 0 {
+                w.put(2, 0b10); // cplstre, no coupling
+                w.put(5, 0b10000); // rematstr, no band rematrixed
+                w.put(4, 0b0101); // chexpstr D15
+                w.put(12, 0); // chbwcod: 73 bins
+                for _ in 0..2 {
+                    w.put(4, ab.exps[0][0] as u32);
+                    for d in deltas.chunks(3) {
+                        w.put(7, (25 * (d[0] + 2) + 5 * (d[1] + 2) + d[2] + 2) as u32);
+                    }
+                    w.put(2, 0); // gainrng
+                }
+                w.put(1, 1); // baie
+                for (bits, code) in [(2, ab.sdcycod), (2, ab.fdcycod), (2, ab.sgaincod), (2, ab.dbpbcod), (3, ab.floorcod)] {
+                    w.put(bits, code as u32);
+                }
+                w.put(1, 1); // snroffste
+                w.put(6, ab.csnroffst as u32);
+                for _ in 0..2 {
+                    w.put(4, ab.fsnroffst[0] as u32);
+                    w.put(3, ab.fgaincod[0] as u32);
+                }
+            } else {
+                w.put(2, 0); // cplstre, rematstr
+                w.put(4, 0); // exponents reused
+                w.put(2, 0); // baie, snroffste
+            }
+            w.put(2, 0); // deltbaie, skiple
+
+            let mut left = [0; 3]; // values still to come in the bap 1, 2 and 4 groups
+            for _ in 0..2 {
+                for (bin, &b) in bap[..73].iter().enumerate() {
+                    let bits = match b { 14 => 14, 15 => 16, _ => (b as usize).max(1) - 1 };
+                    match b {
+                        0 => {}
+                        _ if bin == TONE_BIN => w.put(bits, 1 << (bits - 2)),
+                        1 | 2 | 4 => {
+                            // codes of a group of zeros
+                            let (slot, bits, zeros, size) = match b { 1 => (0, 5, 13, 3), 2 => (1, 7, 62, 3), _ => (2, 7, 60, 2) };
+                            if left[slot] == 0 {
+                                w.put(bits, zeros);
+                                left[slot] = size;
+                            }
+                            left[slot] -= 1;
+                        }
+                        3 => w.put(3, 3),
+                        5 => w.put(4, 7),
+                        _ => w.put(bits, 0),
+                    }
+                }
+            }
+        }
+        w.seal()
+    }
+
+    #[test]
+    fn decodes_a_whole_frame() {
+        let mut decoder = Ac3Decoder::new();
+        let header = decoder.decode(&tone_frame()).unwrap();
+        assert_eq!((header.sample_rate, header.frame_bytes, header.acmod), (48_000, 512, 2));
+
+        let mut coeffs = [0f32; 256];
+        coeffs[TONE_BIN] = 0.5;
+        let mut delay = [0f32; BLOCK_SAMPLES];
+        let mut expected = [0f32; FRAME_SAMPLES];
+        for block in expected.chunks_mut(BLOCK_SAMPLES) {
+            decoder.imdct.run(&coeffs, false, &mut delay, block);
+        }
+        assert!(expected.iter().any(|s| s.abs() > 0.1));
+        let pcm = decoder.pcm();
+        assert_eq!(pcm[0], expected);
+        assert_eq!(pcm[1], expected);
+        assert!(pcm[2..].iter().flatten().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn drops_frames_failing_either_crc() {
+        let frame = tone_frame();
+        for (byte, crc) in [(100, "crc1"), (400, "crc2")] {
+            let mut corrupt = frame.clone();
+            corrupt[byte] ^= 0x10;
+            let err = Ac3Decoder::new().decode(&corrupt).unwrap_err();
+            assert!(err.to_string().contains(crc), "byte {byte}: {err}");
+        }
+    }
+
+    /// Block 0 rematrixes all four bands, block 1 starts coupling at bin 37 and keeps
+    /// the rematrixing flags: only the two bands below the coupling range are left.
+    #[test]
+    fn rematrixing_stops_at_the_coupling_range() {
+        let mut w = BitWriter::default();
+        w.stereo_header();
+        for blk in 0..BLOCKS {
+            w.put(5, 0); // blksw, dithflag, no dynrng
+            match blk {
+                0 => {
+                    w.put(2, 0b10); // cplstre, no coupling
+                    w.put(5, 0b11111); // rematstr, every band rematrixed
+                    w.put(4, 0b0101); // chexpstr D15
+                    w.put(12, 0); // chbwcod: 73 bins
+                    for _ in 0..2 {
+                        w.put(4, 0);
+                        for _ in 0..24 { w.put(7, 62); } // flat exponents
+                        w.put(2, 0); // gainrng
+                    }
+                    w.put(12, 1 << 11); // baie, default parameters
+                    w.put(21, 1 << 20); // snroffste, all offsets zero: no mantissas
+                }
+                1 => {
+                    w.put(4, 0b1111); // cplstre, cplinu, both channels coupled
+                    w.put(1, 0); // phsflginu
+                    w.put(10, 0); // cplbegf, cplendf, cplbndstrc
+                    w.put(2, 0); // no coupling coordinates
+                    w.put(1, 0); // rematstr
+                    w.put(6, 0); // exponents reused
+                    w.put(3, 0); // baie, snroffste, cplleake
+                }
+                _ => {
+                    w.put(1, 0); // cplstre
+                    w.put(2, 0); // no coupling coordinates
+                    w.put(1, 0); // rematstr
+                    w.put(6, 0); // exponents reused
+                    w.put(3, 0); // baie, snroffste, cplleake
+                }
+            }
+            w.put(2, 0); // deltbaie, skiple
+        }
+
+        let mut decoder = Ac3Decoder::new();
+        decoder.decode(&w.seal()).unwrap();
+        assert!(decoder.pcm().iter().flatten().all(|&s| s == 0.0));
+    }
+}
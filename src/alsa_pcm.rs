@@ -0,0 +1,80 @@
+/* ALSA PCM setup shared by AlsaSource and AlsaSink: hw params from a PulseAudio Spec, xrun recovery */
+use alsa::pcm::{Access, Format as AlsaFormat, HwParams, PCM};
+use alsa::{Direction, ValueOr};
+use anyhow::Context;
+use libpulse_binding::sample::{Format, Spec};
+
+/// Periods in the ALSA ring buffer, each one `chunk_frames` long
+const PERIODS: usize = 4;
+
+fn alsa_format(format: Format) -> Option<AlsaFormat> {
+    Some(match format {
+        Format::U8 => AlsaFormat::U8,
+        Format::S16le => AlsaFormat::S16LE,
+        Format::S16be => AlsaFormat::S16BE,
+        Format::S24le => AlsaFormat::S243LE,
+        Format::S24be => AlsaFormat::S243BE,
+        Format::S24_32le => AlsaFormat::S24LE,
+        Format::S24_32be => AlsaFormat::S24BE,
+        Format::S32le => AlsaFormat::S32LE,
+        Format::S32be => AlsaFormat::S32BE,
+        Format::F32le => AlsaFormat::FloatLE,
+        Format::F32be => AlsaFormat::FloatBE,
+        _ => return None,
+    })
+}
+
+/// Open `device` with exactly `spec`, one period per chunk. Capture never resamples:
+/// IEC-61937 must arrive bit-exact.
+pub(crate) fn open(device: &str, direction: Direction, spec: Spec, chunk_frames: usize) -> anyhow::Result<PCM> {
+    let format = alsa_format(spec.format).with_context(|| format!("{:?} has no ALSA equivalent", spec.format))?;
+    let pcm = PCM::new(device, direction, false).with_context(|| format!("opening ALSA device {device}"))?;
+    {
+        let hwp = HwParams::any(&pcm).context("snd_pcm_hw_params_any")?;
+        hwp.set_access(Access::RWInterleaved).context("interleaved access")?;
+        hwp.set_format(format).with_context(|| format!("{device} does not take {format}"))?;
+        hwp.set_channels(spec.channels as u32).with_context(|| format!("{device} does not take {} channels", spec.channels))?;
+        if direction == Direction::Capture {
+            hwp.set_rate_resample(false).context("disabling resampling")?;
+        }
+        hwp.set_rate(spec.rate, ValueOr::Nearest).with_context(|| format!("{device} does not take {} Hz", spec.rate))?;
+        anyhow::ensure!(hwp.get_rate()? == spec.rate, "{device} runs at {} Hz, not {}", hwp.get_rate()?, spec.rate);
+        hwp.set_period_size_near(chunk_frames as alsa::pcm::Frames, ValueOr::Nearest).context("period size")?;
+        hwp.set_buffer_size_near((chunk_frames * PERIODS) as alsa::pcm::Frames).context("buffer size")?;
+        pcm.hw_params(&hwp).context("snd_pcm_hw_params")?;
+    }
+    if direction == Direction::Playback {
+        // start once all but one period is queued, like the PulseAudio prebuf
+        let hwp = pcm.hw_params_current()?;
+        let (period, buffer) = (hwp.get_period_size()?, hwp.get_buffer_size()?);
+        let swp = pcm.sw_params_current()?;
+        swp.set_start_threshold(buffer - period).context("start threshold")?;
+        pcm.sw_params(&swp).context("snd_pcm_sw_params")?;
+    }
+    Ok(pcm)
+}
+
+/// Recover from an xrun or a suspend, anything else is fatal
+pub(crate) fn recover(pcm: &PCM, err: alsa::Error, what: &str) -> anyhow::Result<()> {
+    eprintln!("ALSA {what}: {err}, recovering");
+    pcm.try_recover(err, true).with_context(|| format!("ALSA {what}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sinks::{AlsaSink, AudioSink};
+    use crate::sources::{AlsaSource, AudioSource};
+    use libpulse_binding::sample::{Format, Spec};
+
+    // ALSA's null plugin is in every alsa-lib install, no sound card needed
+    #[test]
+    fn null_device_round_trip() {
+        let spec = Spec { format: Format::S16le, rate: 48_000, channels: 2 };
+        let mut source = AlsaSource::open("null", spec, 256).unwrap();
+        assert_eq!(source.read_chunk().unwrap().unwrap().len(), 256 * 4);
+
+        let mut sink = AlsaSink::open("null", Format::F32le, 48_000, 6, 256).unwrap();
+        sink.write(&vec![0u8; 4096 * 24]).unwrap();
+        assert_eq!(sink.specs().channels, 6);
+    }
+}
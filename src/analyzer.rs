@@ -0,0 +1,297 @@
+/* Offline analysis of a capture file: timeline of PCM and burst segments, and where the main loop would switch modes */
+use std::fmt::Write as _;
+use libpulse_binding::sample::{Format, Spec};
+use serde::Serialize;
+use crate::decoders::DecoderRegistry;
+use crate::iec61937_detector::{Iec61937Detector, PREAMBLE_BYTES};
+use crate::iec61937_framer::{Iec61937Burst, MAX_BURST_BYTES};
+use crate::pipeline::{Pipeline, Switch};
+use crate::sinks::NullSink;
+use crate::sources::AudioSource;
+
+/// How the capture is read and the main loop configured
+pub struct AnalyzeConfig<'a> {
+    pub rate: u32,
+    pub frame_bytes: usize, // one frame of 16-bit words, after extract_words
+    pub chunk_frames: usize,
+    pub det_window: usize,
+    pub min_confidence: f32,
+    pub registry: &'a DecoderRegistry,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Analysis {
+    pub rate: u32,
+    pub frames: usize,
+    pub segments: Vec<TimelineSegment>,
+    pub switches: Vec<ModeSwitch>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum TimelineSegment {
+    /// No preamble in there
+    Pcm { start_frame: usize, frames: usize },
+    /// Consecutive bursts of one data type and stream number
+    Bursts {
+        start_frame: usize,
+        frames: usize,
+        data_type: String,
+        data_type_code: u8,
+        stream_number: u8,
+        bursts: usize,
+        error_bursts: usize,  // Pc error flag set
+        pd_min: u16,          // raw Pd, bits or bytes depending on the type
+        pd_max: u16,
+        min_confidence: f32,
+        period_violations: Vec<usize>, // start frame of bursts not spaced by the repetition period
+    },
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ModeSwitch {
+    pub frame: usize, // end of the chunk that triggered it
+    pub mode: &'static str,
+    pub data_type: Option<String>,
+}
+
+/// Run the timeline and the mode replay over `words` (as returned by `Iec61937Detector::extract_words`)
+pub fn analyze(words: &[u8], config: &AnalyzeConfig) -> anyhow::Result<Analysis> {
+    Ok(Analysis {
+        rate: config.rate,
+        frames: words.len() / config.frame_bytes,
+        segments: timeline(words, config.frame_bytes),
+        switches: replay_modes(words, config)?,
+    })
+}
+
+/// Walk the whole capture burst by burst, like the framer but knowing where each one starts
+fn timeline(words: &[u8], frame_bytes: usize) -> Vec<TimelineSegment> {
+    let mut segments = Vec::new();
+    let mut pos = 0;
+    while pos < words.len() {
+        let Some((offset, preamble)) = Iec61937Detector::find_preamble_at(&words[pos..]) else {
+            push_pcm(&mut segments, pos / frame_bytes, (words.len() - pos) / frame_bytes);
+            break;
+        };
+        let start = pos + offset;
+        if offset > 0 {
+            push_pcm(&mut segments, pos / frame_bytes, offset / frame_bytes);
+        }
+
+        // the burst runs up to the next preamble, or over its zero padding if there is none close enough
+        let payload_end = (start + PREAMBLE_BYTES + preamble.payload_bytes().unwrap_or(0).next_multiple_of(2)).min(words.len());
+        let next = Iec61937Detector::find_preamble_at(&words[payload_end..])
+            .map(|(offset, _)| payload_end + offset)
+            .filter(|next| next - start <= MAX_BURST_BYTES);
+        let end = next.unwrap_or_else(|| payload_end + (words[payload_end..].iter().take_while(|b| **b == 0).count() & !1));
+
+        let burst = Iec61937Burst { preamble, bytes: words[start..end].to_vec() };
+        let violation = next.is_some() && burst.preamble.repetition_period().is_some_and(|p| p * 4 != end - start);
+        push_burst(&mut segments, &burst, start / frame_bytes, (end - start) / frame_bytes, violation);
+        pos = end;
+    }
+    segments
+}
+
+fn push_pcm(segments: &mut Vec<TimelineSegment>, start: usize, len: usize) {
+    match segments.last_mut() {
+        Some(TimelineSegment::Pcm { start_frame, frames }) if *start_frame + *frames == start => *frames += len,
+        _ => segments.push(TimelineSegment::Pcm { start_frame: start, frames: len }),
+    }
+}
+
+fn push_burst(segments: &mut Vec<TimelineSegment>, burst: &Iec61937Burst, start: usize, len: usize, violation: bool) {
+    let preamble = &burst.preamble;
+    let confidence = burst.confidence();
+    if let Some(TimelineSegment::Bursts {
+        start_frame, frames, data_type_code, stream_number, bursts, error_bursts, pd_min, pd_max, min_confidence, period_violations, ..
+    }) = segments.last_mut()
+        && *data_type_code == preamble.stream_type.data_type() && *stream_number == preamble.stream_number && *start_frame + *frames == start
+    {
+        *frames += len;
+        *bursts += 1;
+        *error_bursts += preamble.error as usize;
+        *pd_min = (*pd_min).min(preamble.length_code);
+        *pd_max = (*pd_max).max(preamble.length_code);
+        *min_confidence = min_confidence.min(confidence);
+        if violation {
+            period_violations.push(start);
+        }
+        return;
+    }
+    segments.push(TimelineSegment::Bursts {
+        start_frame: start,
+        frames: len,
+        data_type: preamble.stream_type.to_string(),
+        data_type_code: preamble.stream_type.data_type(),
+        stream_number: preamble.stream_number,
+        bursts: 1,
+        error_bursts: preamble.error as usize,
+        pd_min: preamble.length_code,
+        pd_max: preamble.length_code,
+        min_confidence: confidence,
+        period_violations: if violation { vec![start] } else { Vec::new() },
+    });
+}
+
+/// The capture served chunk by chunk, as words of a 16-bit source
+struct WordsSource {
+    words: Vec<u8>,
+    pos: usize,
+    chunk_bytes: usize,
+    spec: Spec,
+}
+
+impl AudioSource for WordsSource {
+    fn read_chunk(&mut self) -> anyhow::Result<Option<&[u8]>> {
+        let start = self.pos;
+        self.pos = (start + self.chunk_bytes).min(self.words.len());
+        Ok(Some(&self.words[start..self.pos]).filter(|chunk| !chunk.is_empty()))
+    }
+
+    fn specs(&self) -> Spec {
+        self.spec
+    }
+}
+
+/// Feed the capture chunk by chunk through the main loop's pipeline, with null sinks and
+/// decoders that only swallow their bursts
+fn replay_modes(words: &[u8], config: &AnalyzeConfig) -> anyhow::Result<Vec<ModeSwitch>> {
+    let spec = Spec { format: Format::S16le, rate: config.rate, channels: (config.frame_bytes / 2) as u8 };
+    let source = WordsSource { words: words.to_vec(), pos: 0, chunk_bytes: config.chunk_frames * config.frame_bytes, spec };
+    let mut pipeline = Pipeline::builder(Box::new(source))
+        .pcm_sink(Box::new(NullSink::new(spec.format, spec.rate, spec.channels)))
+        .decoded_sink(Box::new(NullSink::new(spec.format, spec.rate, spec.channels)))
+        .registry(config.registry.without_decoding())
+        .det_window(config.det_window)
+        .min_confidence(config.min_confidence)
+        .build()?;
+
+    let frames = words.len() / config.frame_bytes;
+    let mut switches = Vec::new();
+    let mut chunks = 0;
+    while pipeline.step()? {
+        chunks += 1;
+        let frame = (chunks * config.chunk_frames).min(frames);
+        let (mode, stream_type) = match pipeline.last_switch() {
+            None => continue,
+            Some(Switch::Decode(stream_type)) => ("IEC-61937", Some(stream_type)),
+            Some(Switch::Pcm) => ("PCM", None),
+            Some(Switch::Muted(stream_type)) => ("muted", Some(stream_type)),
+        };
+        switches.push(ModeSwitch { frame, mode, data_type: stream_type.map(|t| t.to_string()) });
+    }
+    Ok(switches)
+}
+
+impl Analysis {
+    pub fn to_text(&self, config: &AnalyzeConfig) -> String {
+        let seconds = |frame: usize| frame as f64 / self.rate as f64;
+        let mut out = String::new();
+        let _ = writeln!(out, "{} frames ({:.3}s) at {} Hz", self.frames, seconds(self.frames), self.rate);
+        for segment in &self.segments {
+            match segment {
+                TimelineSegment::Pcm { start_frame, frames } => {
+                    let _ = writeln!(out, "{:>10.3}s  PCM  {frames} frames ({:.3}s)", seconds(*start_frame), seconds(*frames));
+                }
+                TimelineSegment::Bursts {
+                    start_frame, frames, data_type, data_type_code, stream_number, bursts, error_bursts, pd_min, pd_max, min_confidence, period_violations,
+                } => {
+                    let pd = if pd_min == pd_max { pd_min.to_string() } else { format!("{pd_min}..{pd_max}") };
+                    let _ = writeln!(out,
+                        "{:>10.3}s  {data_type} (0x{data_type_code:02X}) stream {stream_number}: {bursts} bursts over {frames} frames ({:.3}s), Pd {pd}, {error_bursts} with error flag, confidence >= {min_confidence:.2}",
+                        seconds(*start_frame), seconds(*frames));
+                    if !period_violations.is_empty() {
+                        let at: Vec<String> = period_violations.iter().map(|f| format!("{:.3}s", seconds(*f))).collect();
+                        let _ = writeln!(out, "{:>12}{} repetition period violations at {}", "", period_violations.len(), at.join(", "));
+                    }
+                }
+            }
+        }
+        let _ = writeln!(out, "Mode switches (--det-window {} chunks of {} frames, --min-confidence {}):", config.det_window, config.chunk_frames, config.min_confidence);
+        for switch in &self.switches {
+            match &switch.data_type {
+                Some(data_type) => { let _ = writeln!(out, "{:>10.3}s  -> {} ({data_type})", seconds(switch.frame), switch.mode); }
+                None => { let _ = writeln!(out, "{:>10.3}s  -> {}", seconds(switch.frame), switch.mode); }
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iec61937_detector::StreamType;
+    use crate::iec61937_packer::{burst, SignalBuilder};
+
+    #[test]
+    fn timeline_and_switches_of_a_generated_capture() {
+        let mut ac3 = vec![0u8; 2560];
+        ac3[..7].copy_from_slice(&[0x0B, 0x77, 0x00, 0x00, 0x24, 0x40, 0xE1]);
+        let mut signal = SignalBuilder::new(48_000);
+        signal.pcm(48_000, 440.0).bursts(crate::iec61937_packer::Codec::Ac3, &ac3.repeat(20)).unwrap();
+        let mut words = signal.build();
+        words.truncate(words.len() - 800 * 4); // padding of the last burst cut short
+        words.extend(burst(StreamType::Ac3, 0, &ac3).unwrap());
+        let mut tail = SignalBuilder::new(48_000);
+        tail.pcm(48_000, 440.0);
+        words.extend(tail.build());
+
+        let registry = DecoderRegistry::new();
+        let config = AnalyzeConfig { rate: 48_000, frame_bytes: 4, chunk_frames: 512, det_window: 8, min_confidence: 0.75, registry: &registry };
+        let analysis = analyze(&words, &config).unwrap();
+
+        assert_eq!(analysis.segments.len(), 3);
+        assert_eq!(analysis.segments[0], TimelineSegment::Pcm { start_frame: 0, frames: 48_000 });
+        let TimelineSegment::Bursts { start_frame, bursts, pd_min, period_violations, .. } = &analysis.segments[1] else {
+            panic!("expected bursts, got {:?}", analysis.segments[1]);
+        };
+        assert_eq!((*start_frame, *bursts, *pd_min), (48_000, 21, 2560 * 8));
+        assert_eq!(period_violations, &[48_000 + 19 * 1536]);
+
+        let modes: Vec<&str> = analysis.switches.iter().map(|s| s.mode).collect();
+        assert_eq!(modes, ["PCM", "IEC-61937", "PCM"]);
+        assert_eq!(analysis.switches[1].data_type.as_deref(), Some("AC-3"));
+        assert!(serde_json::to_string(&analysis).unwrap().contains(r#""kind":"bursts""#));
+    }
+
+    #[test]
+    fn codec_change_reopens_the_decoder() {
+        let mut ac3 = vec![0u8; 2560];
+        ac3[..7].copy_from_slice(&[0x0B, 0x77, 0x00, 0x00, 0x24, 0x40, 0xE1]);
+        let mut dts = vec![0u8; 1000];
+        dts[..4].copy_from_slice(&[0x7F, 0xFE, 0x80, 0x01]);
+        let mut words = burst(StreamType::Ac3, 0, &ac3).unwrap().repeat(10);
+        words.extend(burst(StreamType::DtsType1, 0, &dts).unwrap().repeat(30));
+
+        let registry = DecoderRegistry::new();
+        let config = AnalyzeConfig { rate: 48_000, frame_bytes: 4, chunk_frames: 512, det_window: 8, min_confidence: 0.75, registry: &registry };
+        let switches = analyze(&words, &config).unwrap().switches;
+
+        let types: Vec<_> = switches.iter().map(|s| (s.mode, s.data_type.as_deref())).collect();
+        assert_eq!(types, [("IEC-61937", Some("AC-3")), ("IEC-61937", Some("DTS type I"))]);
+    }
+
+    #[test]
+    fn rejected_stream_stays_muted_between_its_bursts() {
+        let mut aac = vec![0x5A; 2000]; // LOAS sync, then filler
+        aac[..2].copy_from_slice(&[0x56, 0xE0]);
+        let mut words = burst(StreamType::Mpeg4Aac, 0, &aac).unwrap().repeat(20);
+        let aac_frames = words.len() / 4;
+        let mut tail = SignalBuilder::new(48_000);
+        tail.pcm(48_000, 440.0);
+        words.extend(tail.build());
+
+        // bursts span 16 chunks: the chunks between two preambles must not add up to the window
+        let registry = DecoderRegistry::new();
+        let config = AnalyzeConfig { rate: 48_000, frame_bytes: 4, chunk_frames: 64, det_window: 20, min_confidence: 0.75, registry: &registry };
+        let switches = analyze(&words, &config).unwrap().switches;
+
+        let modes: Vec<&str> = switches.iter().map(|s| s.mode).collect();
+        assert_eq!(modes, ["muted", "PCM"]);
+        assert_eq!(switches[0].data_type.as_deref(), Some("MPEG-4 AAC"));
+        assert!(switches[1].frame > aac_frames);
+    }
+}
@@ -1,43 +1,583 @@
-/* AC-3 decoder using ffmpeg child: write IEC61937 in, read 6ch float out */
-use std::io::{Read, Write};
-use std::process::{Child, Command, Stdio};
+/* Decoders for IEC61937 bursts: an ffmpeg child (write IEC61937 in, read 6ch float out), native AC-3,
+   or compressed passthrough to an IEC61937-capable PulseAudio sink */
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 use anyhow::{anyhow, Context};
+use libpulse_binding::format::Encoding;
 use libpulse_binding::sample::{Format, Spec};
-use crate::sinks::AudioSink;
+use crate::ac3::{Ac3Decoder, FRAME_SAMPLES, OUT_CHANNELS, SAMPLE_RATES};
+use crate::iec61937_detector::{swap_words, Iec61937Detector, StreamType, MAT_START_CODE, PREAMBLE_BYTES};
+use crate::sinks::{AudioSink, ConvertSink, PulsePassthroughSink, RemapSink};
 
 pub trait AudioDecoder : AudioSink {
     fn wrap(sink: Box<dyn AudioSink + Send>) -> anyhow::Result<Self>
     where Self: Sized;
 
-    fn finish(self) -> anyhow::Result<Box<dyn AudioSink + Send>>;
+    /// Write `frames` frames of silence to the wrapped sink (e.g. for a PAUSE burst).
+    fn write_silence(&mut self, frames: usize) -> anyhow::Result<()>;
+
+    /// One whole burst of `stream_type` in little-endian words, lasting `frames` frames of
+    /// `specs()`: PAUSE becomes silence, NULL is dropped, the others are decoded.
+    fn write_burst(&mut self, stream_type: StreamType, burst: &[u8], frames: usize) -> anyhow::Result<()> {
+        match stream_type {
+            StreamType::Null => Ok(()),
+            StreamType::Pause => self.write_silence(frames),
+            _ => self.write(burst),
+        }
+    }
+
+    fn finish(self: Box<Self>) -> anyhow::Result<Box<dyn AudioSink + Send>>;
+}
+
+/// Implementations that can decode bursts
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    /// ffmpeg child process, see `FfmpegDecoderSink`
+    Ffmpeg,
+    /// Built-in decoder, see `NativeAc3DecoderSink`
+    Native,
+    /// Bursts forwarded undecoded to a receiver, see `PassthroughDecoderSink`
+    Passthrough,
+}
+
+impl Backend {
+    pub fn supports(&self, stream_type: StreamType) -> bool {
+        match self {
+            Backend::Ffmpeg => FfmpegDecoderSink::supports(stream_type),
+            Backend::Native => NativeAc3DecoderSink::supports(stream_type),
+            Backend::Passthrough => PassthroughDecoderSink::supports(stream_type),
+        }
+    }
+
+    fn factory(&self) -> DecoderFactory {
+        match self {
+            Backend::Ffmpeg => |sink, stream_type, _| Ok(Box::new(FfmpegDecoderSink::wrap_stream(sink, stream_type)?)),
+            Backend::Native => |sink, _, _| Ok(Box::new(NativeAc3DecoderSink::wrap(sink)?)),
+            Backend::Passthrough => PassthroughDecoderSink::open_or_decode,
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Backend::Ffmpeg => "ffmpeg",
+            Backend::Native => "native",
+            Backend::Passthrough => "passthrough",
+        })
+    }
+}
+
+/// Codec names accepted on the command line, and the stream types they cover
+pub const CODECS: &[(&str, &[StreamType])] = {
+    use StreamType::*;
+    &[
+        ("ac3", &[Ac3]),
+        ("eac3", &[EAc3]),
+        ("dts", &[DtsType1, DtsType2, DtsType3]),
+        ("dtshd", &[DtsType4]),
+        ("truehd", &[TrueHd]),
+        ("mpeg", &[Mpeg1Layer1, Mpeg1Layer23, Mpeg2Ext, Mpeg2Layer1Lsf, Mpeg2Layer23Lsf]),
+        ("aac", &[Mpeg2Aac, Mpeg2AacLsf, Mpeg4Aac]),
+    ]
+};
+
+/// Stream types of a codec of `CODECS`, by name
+pub fn codec_types(codec: &str) -> anyhow::Result<&'static [StreamType]> {
+    CODECS.iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(codec))
+        .map(|(_, types)| *types)
+        .ok_or_else(|| anyhow!("unknown codec {codec:?}, expected one of {}",
+            CODECS.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(", ")))
+}
+
+/// Channel positions of ffmpeg's default layout for that many channels, what the decoders output
+pub(crate) fn channel_positions(channels: u8) -> &'static [&'static str] {
+    match channels {
+        1 => &["MONO"],
+        2 => &["FL", "FR"],
+        3 => &["FL", "FR", "FC"],
+        4 => &["FL", "FR", "RL", "RR"],
+        5 => &["FL", "FR", "FC", "SL", "SR"],
+        6 => &["FL", "FR", "FC", "LFE", "SL", "SR"],
+        7 => &["FL", "FR", "FC", "LFE", "RC", "SL", "SR"],
+        8 => &["FL", "FR", "FC", "LFE", "RL", "RR", "SL", "SR"],
+        _ => &[],
+    }
+}
+
+type DecoderFactory = fn(Box<dyn AudioSink + Send>, StreamType, &PassthroughTarget) -> anyhow::Result<Box<dyn AudioDecoder + Send>>;
+
+/// Where the passthrough backend sends the bursts
+#[derive(Clone, Debug)]
+pub struct PassthroughTarget {
+    /// PulseAudio sink name (PipeWire node with `pipewire`), None for the default sink
+    pub sink: Option<String>,
+    /// IEC61937 container rate of the input
+    pub rate: u32,
+    /// Open an IEC958 PipeWire node instead of a PulseAudio stream (`pipewire` feature)
+    pub pipewire: bool,
+}
+
+/// Which decoder to open for each detected stream type. Types without an
+/// entry have no decoder and are muted by the caller.
+pub struct DecoderRegistry {
+    decoders: HashMap<StreamType, (Backend, DecoderFactory)>,
+    passthrough: PassthroughTarget,
+}
+
+impl Default for DecoderRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DecoderRegistry {
+    /// ffmpeg for everything it can decode
+    pub fn new() -> Self {
+        let mut decoders = HashMap::new();
+        for stream_type in CODECS.iter().flat_map(|(_, types)| types.iter().copied()) {
+            if Backend::Ffmpeg.supports(stream_type) {
+                decoders.insert(stream_type, (Backend::Ffmpeg, Backend::Ffmpeg.factory()));
+            }
+        }
+        Self { decoders, passthrough: PassthroughTarget { sink: None, rate: 48000, pipewire: false } }
+    }
+
+    pub fn set_passthrough_target(&mut self, target: PassthroughTarget) {
+        self.passthrough = target;
+    }
+
+    /// Decode every stream type of `codec` (one of `CODECS`) with `backend`
+    pub fn select(&mut self, codec: &str, backend: Backend) -> anyhow::Result<()> {
+        let types = codec_types(codec)?;
+        let supported: Vec<StreamType> = types.iter().copied().filter(|t| backend.supports(*t)).collect();
+        anyhow::ensure!(!supported.is_empty(), "{backend} cannot decode {codec}");
+        for stream_type in supported {
+            self.decoders.insert(stream_type, (backend, backend.factory()));
+        }
+        Ok(())
+    }
+
+    pub fn backend(&self, stream_type: StreamType) -> Option<Backend> {
+        self.decoders.get(&stream_type).map(|(backend, _)| *backend)
+    }
+
+    /// Same backends, but every decoder only swallows its bursts: replays the mode decisions
+    /// of a pipeline without decoding anything (`analyze`)
+    pub fn without_decoding(&self) -> Self {
+        let discard: DecoderFactory = |sink, _, _| Ok(Box::new(DiscardDecoderSink::wrap(sink)?));
+        let decoders = self.decoders.iter().map(|(t, (backend, _))| (*t, (*backend, discard))).collect();
+        Self { decoders, passthrough: self.passthrough.clone() }
+    }
+
+    /// Wrap `sink` in the decoder registered for `stream_type`
+    pub fn open(&self, sink: Box<dyn AudioSink + Send>, stream_type: StreamType) -> anyhow::Result<Box<dyn AudioDecoder + Send>> {
+        let (_, factory) = self.decoders.get(&stream_type)
+            .ok_or_else(|| anyhow!("no decoder for {stream_type}"))?;
+        factory(sink, stream_type, &self.passthrough)
+    }
+}
+
+/// How the IEC61937 bursts are handed to ffmpeg
+#[derive(Clone, Copy, Debug)]
+enum Demux {
+    /// Raw IEC61937 stream, parsed by ffmpeg's `spdif` demuxer
+    Spdif,
+    /// Codec frames extracted from each burst, for the HBR types `spdif` can't read
+    Elementary(StreamType),
+}
+
+// Positions of the MAT codes inside a TrueHD MAT frame (start, middle, end)
+const MAT_MIDDLE_CODE_OFFSET: usize = 30708;
+const MAT_MIDDLE_CODE_LEN: usize = 12;
+const MAT_END_CODE_LEN: usize = 16;
+
+// Restart delays after ffmpeg dies: doubled on every quick failure, reset once
+// a child stayed up for HEALTHY_RUN.
+const MIN_BACKOFF: Duration = Duration::from_millis(20);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+const HEALTHY_RUN: Duration = Duration::from_secs(10);
+
+// Silence waiting behind ffmpeg's output past which the child is drained to play it
+const MAX_QUEUED_SILENCE: Duration = Duration::from_millis(500);
+
+/// ffmpeg's name for raw samples in `format`
+pub(crate) fn ffmpeg_format(format: Format) -> Option<&'static str> {
+    use Format::*;
+    Some(match format {
+        U8 => "u8",
+        S16le => "s16le",
+        S16be => "s16be",
+        S24le => "s24le",
+        S24be => "s24be",
+        S32le => "s32le",
+        S32be => "s32be",
+        F32le => "f32le",
+        F32be => "f32be",
+        ALaw => "alaw",
+        ULaw => "mulaw",
+        _ => return None,
+    })
+}
+
+/// What a decoder writes by itself, checked against the decoded sink when it is wrapped
+struct DecoderOutput {
+    name: &'static str,
+    formats: fn(Format) -> bool,
+    channels: &'static [u8], // empty: any
+    rates: &'static [u32],   // empty: any
+}
+
+impl DecoderOutput {
+    const FFMPEG: Self = Self { name: "ffmpeg", formats: |f| ffmpeg_format(f).is_some(), channels: &[], rates: &[] };
+    const NATIVE_AC3: Self = Self {
+        name: "native AC-3 decoder",
+        formats: |f| matches!(f, Format::F32le | Format::S16le | Format::S32le),
+        channels: &[2, 6],
+        rates: &SAMPLE_RATES,
+    };
+
+    /// The sink as-is if the decoder can write its spec, behind a `RemapSink` if its channels
+    /// are mapped in another order and a `ConvertSink` if the sample format is off, an error
+    /// naming the mismatch otherwise
+    fn negotiate(&self, mut sink: Box<dyn AudioSink + Send>) -> anyhow::Result<Box<dyn AudioSink + Send>> {
+        let spec = sink.specs();
+        let name = self.name;
+        anyhow::ensure!(self.channels.is_empty() || self.channels.contains(&spec.channels),
+            "{name} writes {:?} channels, the decoded sink takes {}", self.channels, spec.channels);
+        anyhow::ensure!(self.rates.is_empty() || self.rates.contains(&spec.rate),
+            "{name} cannot resample, it writes {:?} Hz and the decoded sink runs at {} Hz", self.rates, spec.rate);
+        let layout = channel_positions(spec.channels);
+        let positions = sink.channel_positions();
+        if !layout.is_empty() && positions != layout {
+            eprintln!("{name} writes {} but the decoded sink is mapped {}, reordering",
+                layout.join(","), positions.join(","));
+            sink = Box::new(RemapSink::wrap(sink, &positions)?);
+        }
+        if (self.formats)(spec.format) {
+            return Ok(sink);
+        }
+        anyhow::ensure!((self.formats)(Format::F32le) && ConvertSink::supports(spec.format),
+            "{name} cannot write {:?} samples for the decoded sink", spec.format);
+        eprintln!("{name} writes F32LE, converting to {:?} for the decoded sink", spec.format);
+        Ok(Box::new(ConvertSink::wrap(sink)?))
+    }
+}
+
+/// Where silence goes in the output of the current child, shared with its pump:
+/// a gap is written once ffmpeg has output every frame fed before it
+#[derive(Default)]
+struct Timeline {
+    fed: u64,                     // frames of output the child was fed, by the bursts' duration
+    decoded: u64,                 // frames the pump wrote into the sink
+    gaps: VecDeque<(u64, usize)>, // (fed frames before it, frames of silence)
+}
+
+/// One ffmpeg process and the threads draining its stdout and stderr
+struct FfmpegChild {
+    stdin: ChildStdin,
+    child: Child,
+    pump: thread::JoinHandle<anyhow::Result<()>>,
+    stderr: thread::JoinHandle<Option<String>>, // last line ffmpeg printed
+    started: Instant,
+}
+
+/// ffmpeg under supervision, writing its output into a sink: when the child exits
+/// (e.g. on a corrupt frame) its input is dropped and a new child is started on the
+/// same sink, with backoff. Shared by the decoders and the encoder.
+pub(crate) struct FfmpegProcess {
+    child: Option<FfmpegChild>, // None until the next restart
+    sink: Arc<Mutex<Box<dyn AudioSink + Send>>>, // shared with the pump thread
+    sink_error: Arc<Mutex<Option<anyhow::Error>>>, // set by the pump when the sink fails
+    timeline: Arc<Mutex<Timeline>>,                 // PAUSE silence placed by the pump
+    out_spec: Spec,
+    args: Vec<String>, // between -hide_banner/-loglevel and the output pipe
+    fed: bool,         // the child was given data since it started
+    drained: bool,     // the child was stopped by `drain`, not by a failure
+    spawned: u32,      // children started, the first one included
+    restarts: u32,
+    backoff: Duration,
+    restart_at: Instant,
+}
+
+impl FfmpegProcess {
+    pub(crate) fn spawn(sink: Box<dyn AudioSink + Send>, args: Vec<String>) -> anyhow::Result<Self> {
+        let out_spec = sink.specs();
+        let sink = Arc::new(Mutex::new(sink));
+        let sink_error = Arc::default();
+        let timeline = Arc::default();
+        let child = FfmpegChild::spawn(&sink, &sink_error, &timeline, out_spec, &args)?;
+        Ok(Self {
+            child: Some(child), sink, sink_error, timeline, out_spec, args, fed: false, drained: false,
+            spawned: 1, restarts: 0, backoff: MIN_BACKOFF, restart_at: Instant::now(),
+        })
+    }
+
+    /// Running child, restarting it first if it died and its backoff elapsed
+    fn running_child(&mut self) -> Option<&mut FfmpegChild> {
+        if let Some(child) = &mut self.child {
+            if matches!(child.child.try_wait(), Ok(None)) {
+                return self.child.as_mut();
+            }
+            self.child_died("ffmpeg exited");
+        }
+        if std::mem::take(&mut self.drained) {
+            match FfmpegChild::spawn(&self.sink, &self.sink_error, &self.timeline, self.out_spec, &self.args) {
+                Ok(child) => {
+                    self.spawned += 1;
+                    self.child = Some(child);
+                }
+                Err(e) => {
+                    eprintln!("ffmpeg start failed: {e:#}");
+                    self.schedule_restart();
+                }
+            }
+            return self.child.as_mut();
+        }
+        if Instant::now() < self.restart_at {
+            return None;
+        }
+
+        self.restarts += 1;
+        match FfmpegChild::spawn(&self.sink, &self.sink_error, &self.timeline, self.out_spec, &self.args) {
+            Ok(child) => {
+                eprintln!("ffmpeg restarted ({} restarts so far)", self.restarts);
+                self.spawned += 1;
+                self.child = Some(child);
+            }
+            Err(e) => {
+                eprintln!("ffmpeg restart failed: {e:#}");
+                self.schedule_restart();
+            }
+        }
+        self.child.as_mut()
+    }
+
+    /// Reap the current child, report why it died and schedule its replacement
+    fn child_died(&mut self, reason: &str) {
+        let Some(child) = self.child.take() else { return };
+        if child.started.elapsed() >= HEALTHY_RUN {
+            self.backoff = MIN_BACKOFF;
+        }
+        match child.stop() {
+            Ok(()) => eprintln!("{reason}; restarting in {:?}", self.backoff),
+            Err(e) => eprintln!("{reason}: {e:#}; restarting in {:?}", self.backoff),
+        }
+        self.schedule_restart();
+    }
+
+    fn schedule_restart(&mut self) {
+        self.restart_at = Instant::now() + self.backoff;
+        self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+    }
+
+    /// Feed ffmpeg, the data is lost while a restart is pending.
+    /// Fails once the sink ffmpeg writes into has failed.
+    pub(crate) fn write(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        self.feed(bytes, 0)
+    }
+
+    /// `write` of input that decodes to `frames` frames of output, for `write_silence`
+    pub(crate) fn feed(&mut self, bytes: &[u8], frames: usize) -> anyhow::Result<()> {
+        self.check_sink()?;
+        let Some(child) = self.running_child() else {
+            return Ok(()); // waiting for the restart
+        };
+        match child.stdin.write_all(bytes) {
+            Ok(()) => {
+                self.fed = true;
+                self.timeline.lock().map_err(|_| anyhow!("timeline lock poisoned"))?.fed += frames as u64;
+            }
+            Err(e) => self.child_died(&format!("writing to ffmpeg failed ({e})")),
+        }
+        Ok(())
+    }
+
+    /// Silence after everything fed so far: straight into the sink once ffmpeg's output
+    /// caught up, else queued for the pump. Too much of it queued drains ffmpeg instead.
+    pub(crate) fn write_silence(&mut self, frames: usize) -> anyhow::Result<()> {
+        self.check_sink()?;
+        let mut timeline = self.timeline.lock().map_err(|_| anyhow!("timeline lock poisoned"))?;
+        if self.child.is_none() || (timeline.gaps.is_empty() && timeline.decoded >= timeline.fed) {
+            return self.write_sink(&vec![0u8; frames * self.out_spec.frame_size()]);
+        }
+        let at = timeline.fed;
+        timeline.gaps.push_back((at, frames));
+        let queued: usize = timeline.gaps.iter().map(|(_, frames)| frames).sum();
+        drop(timeline);
+        if queued as u64 > self.out_spec.rate as u64 * MAX_QUEUED_SILENCE.as_millis() as u64 / 1000 {
+            self.drain()?;
+        }
+        Ok(())
+    }
+
+    /// Close ffmpeg's input and wait until everything it was fed is in the sink.
+    /// The next `write` starts a new child.
+    pub(crate) fn drain(&mut self) -> anyhow::Result<()> {
+        if std::mem::take(&mut self.fed) && let Some(child) = self.child.take() {
+            if let Err(e) = child.stop() {
+                eprintln!("ffmpeg did not exit cleanly: {e:#}");
+            }
+            self.drained = true;
+        }
+        self.check_sink()
+    }
+
+    /// The error the sink failed with, if the pump hit one
+    fn check_sink(&self) -> anyhow::Result<()> {
+        match self.sink_error.lock().map_err(|_| anyhow!("sink error lock poisoned"))?.take() {
+            Some(e) => Err(e.context("ffmpeg output sink failed")),
+            None => Ok(()),
+        }
+    }
+
+    /// Write straight into the output sink, bypassing ffmpeg
+    pub(crate) fn write_sink(&self, bytes: &[u8]) -> anyhow::Result<()> {
+        let mut sink = self.sink.lock().map_err(|_| anyhow!("sink lock poisoned"))?;
+        sink.write(bytes)
+    }
+
+    /// `AudioSink::deactivate` on the output sink
+    pub(crate) fn deactivate_sink(&self) -> anyhow::Result<()> {
+        let mut sink = self.sink.lock().map_err(|_| anyhow!("sink lock poisoned"))?;
+        sink.deactivate()
+    }
+
+    /// Children started so far, restarts and restarts after `drain` included
+    #[cfg(test)]
+    fn spawned(&self) -> u32 {
+        self.spawned
+    }
+
+    /// Close ffmpeg input, wait for it to exit, join the pump thread
+    /// and return the original sink so it can be reused.
+    pub(crate) fn finish(mut self, role: &str) -> anyhow::Result<Box<dyn AudioSink + Send>> {
+        if let Some(Err(e)) = self.child.take().map(FfmpegChild::stop) {
+            eprintln!("ffmpeg did not exit cleanly: {e:#}");
+        }
+        if self.restarts > 0 {
+            eprintln!("ffmpeg {role} was restarted {} times", self.restarts);
+        }
+
+        let sink = Arc::try_unwrap(self.sink)
+            .map_err(|_| anyhow!("sink still shared after pump exit"))?
+            .into_inner()
+            .map_err(|_| anyhow!("sink lock poisoned"))?;
+
+        Ok(sink)
+    }
 }
 
+/// ffmpeg decoder: IEC61937 (or the frames extracted from it) in, PCM in the sink's spec out
 pub struct FfmpegDecoderSink {
-    child_stdin: std::process::ChildStdin,
-    child: Option<Child>,
-    _pump: Option<thread::JoinHandle<anyhow::Result<Box<dyn AudioSink + Send>>>>,
-    specs: Spec
+    process: FfmpegProcess,
+    specs: Spec,
+    demux: Demux,
 }
 
 impl FfmpegDecoderSink {
-    fn bytes_per_sample(format: Format) -> usize {
-        use Format::*;
-        match format {
+    /// Data types understood by ffmpeg's `spdif` demuxer, or extracted for it (HBR types).
+    pub fn supports(stream_type: StreamType) -> bool {
+        use StreamType::*;
+        match stream_type {
+            Ac3 | Mpeg1Layer1 | Mpeg1Layer23 | Mpeg2Ext | Mpeg2Aac | Mpeg2Layer1Lsf | Mpeg2Layer23Lsf
+            | DtsType1 | DtsType2 | DtsType3 | Mpeg2AacLsf | EAc3 => true,
+            DtsType4 | TrueHd => true,
+            Null | Pause | Mpeg4Aac | Unknown(_) => false,
+        }
+    }
+
+    /// Same as `wrap`, but picks how to feed ffmpeg from the detected stream type.
+    pub fn wrap_stream(sink: Box<dyn AudioSink + Send>, stream_type: StreamType) -> anyhow::Result<Self> {
+        if stream_type.is_hbr() {
+            Self::spawn(sink, Demux::Elementary(stream_type))
+        } else {
+            Self::spawn(sink, Demux::Spdif)
+        }
+    }
+
+    /// Extract the codec frame carried by one whole burst, in codec byte order.
+    /// TrueHD gives the MLP access units of its MAT frame, type IV the DTS-HD frame.
+    fn elementary_frame(stream_type: StreamType, burst: &[u8]) -> Option<Vec<u8>> {
+        let (0, preamble) = Iec61937Detector::find_preamble_at(burst)? else {
+            return None;
+        };
+        let payload = burst.get(PREAMBLE_BYTES..PREAMBLE_BYTES + preamble.payload_bytes()?)?;
+        if Iec61937Detector::payload_sync(stream_type, payload) != Some(true) {
+            return None;
+        }
 
-            S16le | S16be => 2,
-            S24le | S24be => 3,
-            S32le | S32be => 4,
-            F32le | F32be => 4,
-            // extend if you want other formats
-            _ => panic!("Unsupported format for decoder"),
+        let frame = swap_words(payload);
+        match stream_type {
+            StreamType::TrueHd => {
+                let end_code = frame.len().checked_sub(MAT_END_CODE_LEN)?;
+                let middle = MAT_MIDDLE_CODE_OFFSET..MAT_MIDDLE_CODE_OFFSET + MAT_MIDDLE_CODE_LEN;
+                let data: Vec<u8> = frame.iter().enumerate()
+                    .filter(|(i, _)| *i >= MAT_START_CODE.len() && !middle.contains(i) && *i < end_code)
+                    .map(|(_, b)| *b)
+                    .collect();
+                Some(Self::mlp_access_units(&data))
+            }
+            StreamType::DtsType4 => {
+                let size = u16::from_be_bytes([*frame.get(10)?, *frame.get(11)?]) as usize;
+                frame.get(12..12 + size).map(|f| f.to_vec())
+            }
+            _ => Some(frame),
         }
     }
+
+    /// The access units in the data of a MAT frame (its codes removed), without the zero
+    /// padding the units are spaced with. Each unit starts with its length in 16-bit words.
+    fn mlp_access_units(data: &[u8]) -> Vec<u8> {
+        let mut units = Vec::with_capacity(data.len());
+        let mut pos = 0;
+        while pos + 4 <= data.len() {
+            let header = u16::from_be_bytes([data[pos], data[pos + 1]]);
+            if header == 0 {
+                pos += 2; // padding
+                continue;
+            }
+            let len = ((header & 0x0fff) as usize * 2).max(2);
+            units.extend_from_slice(&data[pos..(pos + len).min(data.len())]);
+            pos += len;
+        }
+        units
+    }
+
+    fn spawn(sink: Box<dyn AudioSink + Send>, demux: Demux) -> anyhow::Result<Self> {
+        let sink = DecoderOutput::FFMPEG.negotiate(sink)?;
+        let specs = sink.specs();
+        let input_format = match demux {
+            Demux::Spdif => "spdif",
+            Demux::Elementary(StreamType::TrueHd) => "truehd",
+            Demux::Elementary(_) => "dts",
+        };
+        let args = [
+            "-f", input_format, "-i", "pipe:0",
+            "-f", ffmpeg_format(specs.format).context("sample format unknown to ffmpeg")?, "-ac", &specs.channels.to_string(), "-ar", &specs.rate.to_string(),
+        ].map(String::from).to_vec();
+        Ok(Self { process: FfmpegProcess::spawn(sink, args)?, specs, demux })
+    }
 }
 
 impl AudioSink for FfmpegDecoderSink {
     fn write(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
-        self.child_stdin.write_all(bytes).context("write IEC61937 to ffmpeg")
+        match self.demux {
+            Demux::Spdif => self.process.write(bytes),
+            Demux::Elementary(stream_type) => match Self::elementary_frame(stream_type, bytes) {
+                Some(frame) => self.process.write(&frame),
+                None => {
+                    eprintln!("dropping burst without {stream_type} sync");
+                    Ok(())
+                }
+            },
+        }
     }
 
     fn specs(& self) -> Spec {
@@ -49,31 +589,85 @@ impl AudioDecoder for FfmpegDecoderSink {
 
     fn wrap(sink: Box<dyn AudioSink + Send>) -> anyhow::Result<Self>
     {
-        let spec = sink.specs();
-        let chans = spec.channels as usize;
-        let sample_bytes = FfmpegDecoderSink::bytes_per_sample(spec.format);
-        let frame_bytes = chans * sample_bytes;
+        Self::spawn(sink, Demux::Spdif)
+    }
+
+    /// After what ffmpeg still holds, so the gap plays where it was in the stream
+    fn write_silence(&mut self, frames: usize) -> anyhow::Result<()> {
+        self.process.write_silence(frames)
+    }
+
+    /// Audio bursts are counted by their duration, to place the silence of later PAUSE bursts
+    fn write_burst(&mut self, stream_type: StreamType, burst: &[u8], frames: usize) -> anyhow::Result<()> {
+        match (stream_type, self.demux) {
+            (StreamType::Null, _) => Ok(()),
+            (StreamType::Pause, _) => self.write_silence(frames),
+            (_, Demux::Spdif) => self.process.feed(burst, frames),
+            (_, Demux::Elementary(stream_type)) => match Self::elementary_frame(stream_type, burst) {
+                Some(frame) => self.process.feed(&frame, frames),
+                None => {
+                    eprintln!("dropping burst without {stream_type} sync");
+                    Ok(())
+                }
+            },
+        }
+    }
+
+    fn finish(self: Box<Self>) -> anyhow::Result<Box<dyn AudioSink + Send>> {
+        self.process.finish("decoder")
+    }
+
+}
+
+impl FfmpegChild {
+    /// `ffmpeg -hide_banner -loglevel warning <args> pipe:1`, its output pumped into `sink`
+    fn spawn(sink: &Arc<Mutex<Box<dyn AudioSink + Send>>>, sink_error: &Arc<Mutex<Option<anyhow::Error>>>,
+             timeline: &Arc<Mutex<Timeline>>, spec: Spec, args: &[String]) -> anyhow::Result<Self> {
+        let frame_bytes = spec.frame_size();
         let mut child = Command::new("ffmpeg")
-            .args([
-                "-hide_banner", "-loglevel", "warning",
-                "-f", "spdif", "-i", "pipe:0",
-                "-f", &spec.format.to_string().unwrap(), "-ac", &spec.channels.to_string(), "-ar", &spec.rate.to_string(), "pipe:1",
-            ])
+            .args(["-hide_banner", "-loglevel", "warning"])
+            .args(args)
+            .arg("pipe:1")
             .stdin(Stdio::piped())
-            .stderr(Stdio::inherit())
+            .stderr(Stdio::piped())
             .stdout(Stdio::piped())
             .spawn()
             .context("spawning ffmpeg")?;
 
-        let writer = sink;
+        // forward ffmpeg's messages, remembering the last one for the exit report
+        let stderr = child.stderr.take().context("ffmpeg stderr")?;
+        let stderr = thread::spawn(move || {
+            let mut last = None;
+            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                eprintln!("[ffmpeg] {line}");
+                last = Some(line);
+            }
+            last
+        });
+
+        let writer = Arc::clone(sink);
+        let writer_error = Arc::clone(sink_error);
+        let timeline = Arc::clone(timeline);
         let stdout = child.stdout.take().context("ffmpeg stdout")?;
-        let pump = thread::spawn(move || -> anyhow::Result<Box<dyn AudioSink + Send>> {
-            let mut reader = std::io::BufReader::new(stdout);
+        let pump = thread::spawn(move || -> anyhow::Result<()> {
+            let mut reader = BufReader::new(stdout);
             let mut inbuf = vec![0u8; 8 * 1024];
-            const FRAME_BYTES: usize = 6 /*ch*/ * 4 /*f32*/;
 
             let mut stash: Vec<u8> = Vec::with_capacity(128 * frame_bytes);
-            let mut out: Option<Box<dyn AudioSink + Send>> = Some(writer);
+            let mut dropping = false;
+            let mut write = |bytes: &[u8]| -> anyhow::Result<()> {
+                if dropping {
+                    return Ok(());
+                }
+                let mut w = writer.lock().map_err(|_| anyhow!("sink lock poisoned"))?;
+                if let Err(e) = w.write(bytes) {
+                    // keep ffmpeg going, the next write to it reports the error
+                    eprintln!("sink write failed: {e}; dropping samples to keep decoder alive");
+                    *writer_error.lock().map_err(|_| anyhow!("sink error lock poisoned"))? = Some(e);
+                    dropping = true;
+                }
+                Ok(())
+            };
 
             loop {
                 let n = reader.read(&mut inbuf)?;
@@ -84,48 +678,507 @@ impl AudioDecoder for FfmpegDecoderSink {
 
                 // number of bytes we can safely write (multiple of frame size)
                 let aligned = stash.len() - (stash.len() % frame_bytes);
-                if aligned > 0 {
-                    if let Some(w) = out.as_mut() {
-                        if let Err(e) = w.write(&stash[..aligned]) {
-                            eprintln!("sink write failed: {e}; dropping samples to keep decoder alive");
-                            out = None;
-                        }
+                let mut timeline = timeline.lock().map_err(|_| anyhow!("timeline lock poisoned"))?;
+                let mut out = &stash[..aligned];
+                loop {
+                    // silence due before the next decoded frame
+                    while let Some(&(at, frames)) = timeline.gaps.front() && at <= timeline.decoded {
+                        timeline.gaps.pop_front();
+                        write(&vec![0u8; frames * frame_bytes])?;
                     }
-                    stash.drain(..aligned);
+                    if out.is_empty() { break; }
+                    let frames = out.len() / frame_bytes;
+                    let until_gap = timeline.gaps.front().map_or(frames, |&(at, _)| ((at - timeline.decoded) as usize).min(frames));
+                    write(&out[..until_gap * frame_bytes])?;
+                    timeline.decoded += until_gap as u64;
+                    out = &out[until_gap * frame_bytes..];
                 }
+                stash.drain(..aligned);
             }
 
-            // optional: flush any tail by padding to a frame boundary
+            // flush any tail by padding it to a frame of the sink's spec
             if !stash.is_empty() {
-                let pad = FRAME_BYTES - (stash.len() % FRAME_BYTES);
-                if pad < FRAME_BYTES { stash.extend(std::iter::repeat(0u8).take(pad)); }
-                if let Some(w) = out.as_mut() {
-                    let _ = w.write(&stash); // ignore final error
+                let pad = frame_bytes - stash.len() % frame_bytes;
+                stash.extend(std::iter::repeat_n(0, pad));
+                let _ = write(&stash); // ignore final error
+            }
+
+            // the silence of gaps ffmpeg did not output enough frames to reach
+            let mut timeline = timeline.lock().map_err(|_| anyhow!("timeline lock poisoned"))?;
+            for (_, frames) in std::mem::take(&mut *timeline).gaps {
+                write(&vec![0u8; frames * frame_bytes])?;
+            }
+            Ok(())
+        });
+
+        Ok(Self { stdin: child.stdin.take().context("ffmpeg stdin")?, child, pump, stderr, started: Instant::now() })
+    }
+
+    /// Close ffmpeg's stdin so it can flush and exit, then wait for it and its threads.
+    /// Fails with the exit status and ffmpeg's last message when it did not exit cleanly.
+    fn stop(self) -> anyhow::Result<()> {
+        let Self { stdin, mut child, pump, stderr, .. } = self;
+        drop(stdin);
+        let status = child.wait().context("waiting for ffmpeg")?;
+        let pumped = pump.join().map_err(|_| anyhow!("pump thread panicked"))?;
+        let last_message = stderr.join().ok().flatten();
+
+        if !status.success() {
+            match last_message {
+                Some(message) => anyhow::bail!("{status}: {message}"),
+                None => anyhow::bail!("{status}"),
+            }
+        }
+        pumped
+    }
+}
+
+/// In-process AC-3 decoder: decodes the frame carried by each burst and writes the
+/// PCM straight into the wrapped sink, 5.1 as-is or downmixed to stereo, resampled
+/// when the stream and the sink run at different AC-3 rates.
+pub struct NativeAc3DecoderSink {
+    sink: Box<dyn AudioSink + Send>,
+    decoder: Box<Ac3Decoder>,
+    resampler: Option<Resampler>, // while the stream rate is not the sink's
+}
+
+impl NativeAc3DecoderSink {
+    pub fn supports(stream_type: StreamType) -> bool {
+        stream_type == StreamType::Ac3
+    }
+
+    /// The AC-3 frame carried by one whole burst, in codec byte order
+    fn frame(burst: &[u8]) -> Option<Vec<u8>> {
+        let (0, preamble) = Iec61937Detector::find_preamble_at(burst)? else {
+            return None;
+        };
+        if preamble.stream_type != StreamType::Ac3 {
+            return None;
+        }
+        let payload = burst.get(PREAMBLE_BYTES..PREAMBLE_BYTES + preamble.payload_bytes()?)?;
+        Some(swap_words(payload))
+    }
+
+    /// Interleave decoded frames in the sink's channel count and sample format
+    fn write_pcm(sink: &mut Box<dyn AudioSink + Send>, frames: &[[f32; OUT_CHANNELS]]) -> anyhow::Result<()> {
+        let spec = sink.specs();
+        let mut out = Vec::with_capacity(frames.len() * spec.frame_size());
+        for frame in frames {
+            let downmix;
+            let samples: &[f32] = if spec.channels == 2 {
+                // Lo/Ro: centre and surrounds at -3 dB, LFE dropped
+                let (c, sl, sr) = (frame[2], frame[4], frame[5]);
+                downmix = [
+                    frame[0] + std::f32::consts::FRAC_1_SQRT_2 * (c + sl),
+                    frame[1] + std::f32::consts::FRAC_1_SQRT_2 * (c + sr),
+                ];
+                &downmix
+            } else {
+                frame
+            };
+            for &s in samples {
+                match spec.format {
+                    Format::F32le => out.extend_from_slice(&s.to_le_bytes()),
+                    Format::S16le => out.extend_from_slice(&((s * 32768.0).clamp(-32768.0, 32767.0) as i16).to_le_bytes()),
+                    _ => out.extend_from_slice(&((s as f64 * 2147483648.0).clamp(-2147483648.0, 2147483647.0) as i32).to_le_bytes()),
                 }
             }
+        }
+        sink.write(&out)
+    }
+}
 
-            Ok(out.unwrap())
+impl AudioSink for NativeAc3DecoderSink {
+    fn write(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        let Some(frame) = Self::frame(bytes) else {
+            eprintln!("dropping burst without an AC-3 frame");
+            return Ok(());
+        };
+        match self.decoder.decode(&frame) {
+            Ok(header) => {
+                let pcm = self.decoder.pcm();
+                let frames: Vec<[f32; OUT_CHANNELS]> = (0..FRAME_SAMPLES).map(|i| std::array::from_fn(|ch| pcm[ch][i])).collect();
+                let rate = self.sink.specs().rate;
+                if header.sample_rate == rate {
+                    self.resampler = None;
+                    return Self::write_pcm(&mut self.sink, &frames);
+                }
+                if self.resampler.as_ref().is_none_or(|r| r.from != header.sample_rate) {
+                    eprintln!("resampling AC-3 from {} Hz to the sink's {rate} Hz", header.sample_rate);
+                    self.resampler = Some(Resampler::new(header.sample_rate, rate));
+                }
+                let frames = self.resampler.as_mut().unwrap().run(&frames);
+                Self::write_pcm(&mut self.sink, &frames)
+            }
+            Err(e) => {
+                eprintln!("AC-3 decode error: {e}; muting one frame");
+                let frames = self.resampler.as_ref().map_or(FRAME_SAMPLES, |r| FRAME_SAMPLES * r.to as usize / r.from as usize);
+                self.write_silence(frames)
+            }
+        }
+    }
+
+    fn specs(&self) -> Spec {
+        self.sink.specs()
+    }
+}
+
+impl AudioDecoder for NativeAc3DecoderSink {
+    fn wrap(sink: Box<dyn AudioSink + Send>) -> anyhow::Result<Self> {
+        let sink = DecoderOutput::NATIVE_AC3.negotiate(sink)?;
+        Ok(Self { sink, decoder: Box::new(Ac3Decoder::new()), resampler: None })
+    }
+
+    fn write_silence(&mut self, frames: usize) -> anyhow::Result<()> {
+        let spec = self.sink.specs();
+        self.sink.write(&vec![0u8; frames * spec.frame_size()])
+    }
+
+    fn finish(self: Box<Self>) -> anyhow::Result<Box<dyn AudioSink + Send>> {
+        Ok(self.sink)
+    }
+}
+
+/// Linear interpolation from one rate to another, carried across calls
+struct Resampler {
+    from: u32,
+    to: u32,
+    phase: u64,                // next output position after `last`, in 1/`to` input frames
+    last: [f32; OUT_CHANNELS], // last input frame of the previous call
+}
+
+impl Resampler {
+    fn new(from: u32, to: u32) -> Self {
+        Self { from, to, phase: 0, last: [0.0; OUT_CHANNELS] }
+    }
+
+    fn run(&mut self, input: &[[f32; OUT_CHANNELS]]) -> Vec<[f32; OUT_CHANNELS]> {
+        let (from, to) = (self.from as u64, self.to as u64);
+        let end = input.len() as u64 * to;
+        let mut out = Vec::with_capacity((end / from) as usize + 1);
+        while self.phase < end {
+            let i = (self.phase / to) as usize;
+            let t = (self.phase % to) as f32 / to as f32;
+            let prev = if i == 0 { &self.last } else { &input[i - 1] };
+            out.push(std::array::from_fn(|ch| prev[ch] + (input[i][ch] - prev[ch]) * t));
+            self.phase += from;
+        }
+        self.phase -= end;
+        if let Some(last) = input.last() {
+            self.last = *last;
+        }
+        out
+    }
+}
+
+/// Forwards every burst, PAUSE and NULL included, untouched to a PulseAudio sink (or PipeWire
+/// node) opened in the matching encoded format, so an AV receiver does the decoding. The decoded
+/// sink stays idle and is handed back on `finish`, unless the output fails and ffmpeg takes over.
+pub struct PassthroughDecoderSink {
+    stream_type: StreamType,
+    route: Route,
+    decoded: Option<Box<dyn AudioSink + Send>>, // None once wrapped by the ffmpeg fallback
+}
+
+enum Route {
+    Passthrough(Box<dyn AudioSink + Send>),
+    Ffmpeg(Box<FfmpegDecoderSink>),
+}
+
+impl PassthroughDecoderSink {
+    fn encoding(stream_type: StreamType) -> Option<Encoding> {
+        use StreamType::*;
+        match stream_type {
+            Ac3 => Some(Encoding::AC3_IEC61937),
+            EAc3 => Some(Encoding::EAC3_IEC61937),
+            DtsType1 | DtsType2 | DtsType3 => Some(Encoding::DTS_IEC61937),
+            Mpeg1Layer1 | Mpeg1Layer23 | Mpeg2Ext | Mpeg2Layer1Lsf | Mpeg2Layer23Lsf => Some(Encoding::MPEG_IEC61937),
+            Mpeg2Aac | Mpeg2AacLsf => Some(Encoding::MPEG2_AAC_IEC61937),
+            // TrueHD and DTS-HD encodings need PulseAudio 13 (pa_v13)
+            _ => None,
+        }
+    }
+
+    pub fn supports(stream_type: StreamType) -> bool {
+        Self::encoding(stream_type).is_some()
+    }
+
+    fn open_output(stream_type: StreamType, target: &PassthroughTarget) -> anyhow::Result<Box<dyn AudioSink + Send>> {
+        if target.pipewire {
+            #[cfg(feature = "pipewire")]
+            return Ok(Box::new(crate::sinks::PipeWireSink::open_iec958(target.sink.as_deref(), stream_type, target.rate)?));
+            #[cfg(not(feature = "pipewire"))]
+            anyhow::bail!("built without the pipewire feature");
+        }
+        let encoding = Self::encoding(stream_type).ok_or_else(|| anyhow!("no passthrough format for {stream_type}"))?;
+        Ok(Box::new(PulsePassthroughSink::open(target.sink.as_deref(), encoding, target.rate)?))
+    }
+
+    /// Passthrough if the sink accepts the format, otherwise decode with ffmpeg
+    fn open_or_decode(decoded: Box<dyn AudioSink + Send>, stream_type: StreamType, target: &PassthroughTarget) -> anyhow::Result<Box<dyn AudioDecoder + Send>> {
+        match Self::open_output(stream_type, target) {
+            Ok(out) => Ok(Box::new(Self { stream_type, route: Route::Passthrough(out), decoded: Some(decoded) })),
+            Err(e) => {
+                eprintln!("{stream_type} passthrough unavailable ({e:#}); decoding with ffmpeg instead");
+                Ok(Box::new(FfmpegDecoderSink::wrap_stream(decoded, stream_type)?))
+            }
+        }
+    }
+
+    /// The output failed: decode into the decoded sink from now on
+    fn fall_back(&mut self, e: anyhow::Error) -> anyhow::Result<()> {
+        eprintln!("{} passthrough failed ({e:#}); decoding with ffmpeg instead", self.stream_type);
+        let decoded = self.decoded.take().context("decoded sink already handed to ffmpeg")?;
+        self.route = Route::Ffmpeg(Box::new(FfmpegDecoderSink::wrap_stream(decoded, self.stream_type)?));
+        Ok(())
+    }
+}
+
+impl AudioSink for PassthroughDecoderSink {
+    fn write(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        let e = match &mut self.route {
+            Route::Ffmpeg(dec) => return dec.write(bytes),
+            Route::Passthrough(out) => match out.write(bytes) {
+                Ok(()) => return Ok(()),
+                Err(e) => e,
+            },
+        };
+        self.fall_back(e)?;
+        self.write(bytes)
+    }
+
+    fn specs(&self) -> Spec {
+        match &self.route {
+            Route::Passthrough(out) => out.specs(),
+            Route::Ffmpeg(dec) => dec.specs(),
+        }
+    }
+}
+
+impl AudioDecoder for PassthroughDecoderSink {
+    fn wrap(_sink: Box<dyn AudioSink + Send>) -> anyhow::Result<Self> {
+        anyhow::bail!("the passthrough format depends on the stream type, use DecoderRegistry::open")
+    }
+
+    /// Zeros on the passthrough stream: the receiver sees a gap in the bursts
+    fn write_silence(&mut self, frames: usize) -> anyhow::Result<()> {
+        match &mut self.route {
+            Route::Passthrough(out) => {
+                let spec = out.specs();
+                out.write(&vec![0u8; frames * spec.frame_size()])
+            }
+            Route::Ffmpeg(dec) => dec.write_silence(frames),
+        }
+    }
+
+    /// As-is while passing through, the receiver locks to the burst cadence PAUSE and NULL keep
+    fn write_burst(&mut self, stream_type: StreamType, burst: &[u8], frames: usize) -> anyhow::Result<()> {
+        match &mut self.route {
+            Route::Passthrough(_) => self.write(burst),
+            Route::Ffmpeg(dec) => dec.write_burst(stream_type, burst, frames),
+        }
+    }
+
+    fn finish(self: Box<Self>) -> anyhow::Result<Box<dyn AudioSink + Send>> {
+        match self.route {
+            Route::Passthrough(_) => self.decoded.context("decoded sink lost"),
+            Route::Ffmpeg(dec) => dec.finish(),
+        }
+    }
+}
+
+/// Drops the bursts, see `DecoderRegistry::without_decoding`
+struct DiscardDecoderSink {
+    sink: Box<dyn AudioSink + Send>,
+}
+
+impl AudioSink for DiscardDecoderSink {
+    fn write(&mut self, _bytes: &[u8]) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn specs(&self) -> Spec {
+        self.sink.specs()
+    }
+}
+
+impl AudioDecoder for DiscardDecoderSink {
+    fn wrap(sink: Box<dyn AudioSink + Send>) -> anyhow::Result<Self> {
+        Ok(Self { sink })
+    }
+
+    fn write_silence(&mut self, _frames: usize) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> anyhow::Result<Box<dyn AudioSink + Send>> {
+        Ok(self.sink)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iec61937_packer::{burst, pause_burst};
+
+    #[test]
+    fn registry_picks_backend_per_codec() {
+        let mut registry = DecoderRegistry::new();
+        assert_eq!(registry.backend(StreamType::Ac3), Some(Backend::Ffmpeg));
+        assert_eq!(registry.backend(StreamType::TrueHd), Some(Backend::Ffmpeg));
+        assert_eq!(registry.backend(StreamType::Mpeg4Aac), None);
+        assert_eq!(registry.backend(StreamType::Pause), None);
+
+        registry.select("AC3", Backend::Native).unwrap();
+        assert_eq!(registry.backend(StreamType::Ac3), Some(Backend::Native));
+        assert_eq!(registry.backend(StreamType::EAc3), Some(Backend::Ffmpeg));
+
+        assert!(registry.select("dts", Backend::Native).is_err());
+        assert!(registry.select("flac", Backend::Ffmpeg).is_err());
+
+        assert!(registry.select("truehd", Backend::Passthrough).is_err());
+        registry.select("dts", Backend::Passthrough).unwrap();
+        assert_eq!(registry.backend(StreamType::DtsType2), Some(Backend::Passthrough));
+        assert_eq!(registry.backend(StreamType::DtsType4), Some(Backend::Ffmpeg));
+    }
+
+    /// Records what it is given
+    struct Capture(Spec, Arc<Mutex<Vec<u8>>>);
+    impl AudioSink for Capture {
+        fn write(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+            self.1.lock().unwrap().extend_from_slice(bytes);
+            Ok(())
+        }
+
+        fn specs(&self) -> Spec {
+            self.0
+        }
+    }
+
+    /// AC-3 with short PAUSE gaps, as encoders send between tracks: one ffmpeg child
+    /// for the whole stream, and each gap's silence between the frames around it
+    #[test]
+    #[ignore = "needs ffmpeg"]
+    fn pause_gaps_keep_ffmpeg_running() {
+        let written = Arc::default();
+        let spec = Spec { format: Format::F32le, rate: 48_000, channels: 2 };
+        let mut decoder = FfmpegDecoderSink::wrap(Box::new(Capture(spec, Arc::clone(&written)))).unwrap();
+        let ac3 = burst(StreamType::Ac3, 0, &crate::ac3::tests::tone_frame()).unwrap();
+        let pause = pause_burst(FRAME_SAMPLES);
+        for _ in 0..3 {
+            decoder.write_burst(StreamType::Ac3, &ac3, FRAME_SAMPLES).unwrap();
+            decoder.write_burst(StreamType::Ac3, &ac3, FRAME_SAMPLES).unwrap();
+            decoder.write_burst(StreamType::Pause, &pause, FRAME_SAMPLES).unwrap();
+        }
+        assert_eq!(decoder.process.spawned(), 1);
+        Box::new(decoder).finish().unwrap();
+
+        let samples: Vec<f32> = written.lock().unwrap().chunks(4).map(|s| f32::from_le_bytes(s.try_into().unwrap())).collect();
+        let frame = FRAME_SAMPLES * 2; // samples of one AC-3 frame
+        assert_eq!(samples.len(), 9 * frame);
+        for gap in 0..3 {
+            let at = (3 * gap + 2) * frame;
+            assert!(samples[at - frame..at].iter().any(|&s| s != 0.0), "no audio before gap {gap}");
+            assert!(samples[at..at + frame].iter().all(|&s| s == 0.0), "audio in gap {gap}");
+        }
+    }
+
+    #[test]
+    fn truehd_frame_is_its_access_units() {
+        // MAT frame: start code, a unit, padding, a unit cut by the middle code, padding, end code
+        let mut mat = vec![0u8; 61424];
+        mat[..20].copy_from_slice(&MAT_START_CODE);
+        let unit_a: Vec<u8> = [0xF0, 0x0A].into_iter().chain([0xAA; 18]).collect();
+        let unit_b: Vec<u8> = [0xF0, 0x14].into_iter().chain([0xBB; 38]).collect();
+        mat[20..40].copy_from_slice(&unit_a);
+        mat[30700..30708].copy_from_slice(&unit_b[..8]);
+        mat[30708..30720].fill(0xEE);
+        mat[30720..30752].copy_from_slice(&unit_b[8..]);
+        mat[61408..].fill(0xEE);
+
+        let burst = crate::iec61937_packer::burst(StreamType::TrueHd, 0, &mat).unwrap();
+        let frame = FfmpegDecoderSink::elementary_frame(StreamType::TrueHd, &burst).unwrap();
+        assert_eq!(frame, [unit_a, unit_b].concat());
+    }
+
+    #[test]
+    fn passthrough_forwards_every_burst() {
+        let spec = Spec { format: Format::S16le, rate: 48_000, channels: 2 };
+        let (out, decoded) = (Arc::default(), Arc::default());
+        let mut passthrough: Box<dyn AudioDecoder + Send> = Box::new(PassthroughDecoderSink {
+            stream_type: StreamType::Ac3,
+            route: Route::Passthrough(Box::new(Capture(spec, Arc::clone(&out)))),
+            decoded: Some(Box::new(Capture(spec, Arc::clone(&decoded)))),
         });
+        let mut expected = Vec::new();
+        for (stream_type, fill) in [(StreamType::Ac3, 1), (StreamType::Pause, 2), (StreamType::Null, 3), (StreamType::Ac3, 4)] {
+            let burst = vec![fill; 64];
+            passthrough.write_burst(stream_type, &burst, burst.len() / 4).unwrap();
+            expected.extend(burst);
+        }
+        assert_eq!(*out.lock().unwrap(), expected);
+        assert!(decoded.lock().unwrap().is_empty());
+        assert_eq!(passthrough.finish().unwrap().specs(), spec);
+    }
 
-        Ok(Self { child_stdin: child.stdin.take().context("ffmpeg stdin")?, child: Some(child), _pump: Some(pump), specs: spec })
+    #[test]
+    fn resampler_keeps_the_duration() {
+        let mut resampler = Resampler::new(44_100, 48_000);
+        let frames: usize = (0..100).map(|_| resampler.run(&[[0.5; OUT_CHANNELS]; FRAME_SAMPLES]).len()).sum();
+        assert_eq!(frames, (100 * FRAME_SAMPLES * 48_000).div_ceil(44_100));
+        // past the first input frame, which fades in from silence, a constant stays constant
+        let out = resampler.run(&[[0.25; OUT_CHANNELS]; FRAME_SAMPLES]);
+        assert!(out[2..].iter().flatten().all(|&s| s == 0.25));
     }
 
-    /// Close ffmpeg input, wait for it to exit, join the pump thread
-    /// and return the original sink so it can be reused.
-    fn finish(mut self) -> anyhow::Result<Box<dyn AudioSink + Send>> {
-        // Close ffmpeg's stdin so it can flush and exit.
-        drop(self.child_stdin);
+    #[test]
+    fn decoded_sink_is_negotiated() {
+        let sink = |format, rate, channels| -> Box<dyn AudioSink + Send> {
+            Box::new(Capture(Spec { format, rate, channels }, Arc::default()))
+        };
+        let native = DecoderOutput::NATIVE_AC3;
+        assert_eq!(native.negotiate(sink(Format::F32le, 48_000, 6)).unwrap().specs().format, Format::F32le);
+        assert!(native.negotiate(sink(Format::F32le, 48_000, 8)).is_err());
+        assert!(native.negotiate(sink(Format::F32le, 96_000, 2)).is_err());
+        assert!(DecoderOutput::FFMPEG.negotiate(sink(Format::F32le, 96_000, 8)).is_ok());
+        assert!(DecoderOutput::FFMPEG.negotiate(sink(Format::Invalid, 48_000, 2)).is_err());
 
-        if let Some(mut child) = self.child.take() {
-            let _ = child.wait(); // ignore exit code here or handle it if you want
+        let written = Arc::default();
+        let spec = Spec { format: Format::S24_32le, rate: 48_000, channels: 2 };
+        let mut converted = DecoderOutput::FFMPEG.negotiate(Box::new(Capture(spec, Arc::clone(&written)))).unwrap();
+        assert_eq!(converted.specs().format, Format::F32le);
+        let samples: Vec<u8> = [0.5f32, -1.0, 2.0].iter().flat_map(|s| s.to_le_bytes()).collect();
+        converted.write(&samples).unwrap();
+        let words: Vec<i32> = written.lock().unwrap().chunks(4).map(|w| i32::from_le_bytes(w.try_into().unwrap())).collect();
+        assert_eq!(words, [0x40_0000, -0x80_0000, 0x7f_ffff]);
+    }
+
+    /// Capture with its channels wired in another order than the decoders'
+    struct Mapped(Capture, Vec<&'static str>);
+    impl AudioSink for Mapped {
+        fn write(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+            self.0.write(bytes)
         }
 
-        let handle = self._pump.take().ok_or_else(|| anyhow!("pump missing"))?;
-        let sink = handle
-            .join()
-            .map_err(|_| anyhow!("pump thread panicked"))??;
+        fn specs(&self) -> Spec {
+            self.0.specs()
+        }
 
-        Ok(sink)
+        fn channel_positions(&self) -> Vec<&'static str> {
+            self.1.clone()
+        }
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn decoded_sink_is_remapped() {
+        let written = Arc::default();
+        let spec = Spec { format: Format::F32le, rate: 48_000, channels: 2 };
+        let sink = Mapped(Capture(spec, Arc::clone(&written)), vec!["FR", "FL"]);
+        let mut remapped = DecoderOutput::NATIVE_AC3.negotiate(Box::new(sink)).unwrap();
+        assert_eq!(remapped.channel_positions(), ["FL", "FR"]);
+        let samples: Vec<u8> = [0.5f32, -0.5].iter().flat_map(|s| s.to_le_bytes()).collect();
+        remapped.write(&samples).unwrap();
+        let out: Vec<f32> = written.lock().unwrap().chunks(4).map(|s| f32::from_le_bytes(s.try_into().unwrap())).collect();
+        assert_eq!(out, [-0.5, 0.5]);
+    }
+}
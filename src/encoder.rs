@@ -0,0 +1,103 @@
+/* AC-3 encoder for PCM input: multichannel PCM in, IEC61937 bursts (2ch S16LE) out for S/PDIF-only receivers */
+use anyhow::Context;
+use libpulse_binding::sample::{Format, Spec};
+use crate::ac3::BITRATES_KBPS;
+use crate::decoders::{ffmpeg_format, FfmpegProcess};
+use crate::sinks::AudioSink;
+
+/// Sample rates AC-3 can be encoded at
+const AC3_RATES: [u32; 3] = [32000, 44100, 48000];
+
+/// ffmpeg encoding the PCM written to it to AC-3 and wrapping each frame in an
+/// IEC61937 burst ("Dolby Digital Live"). The bursts go to the wrapped sink.
+pub struct FfmpegAc3EncoderSink {
+    process: FfmpegProcess,
+    input: Spec,
+}
+
+impl FfmpegAc3EncoderSink {
+    /// `input` is the layout of the PCM that will be written, the wrapped sink must be 2ch S16LE
+    pub fn wrap(sink: Box<dyn AudioSink + Send>, input: Spec, bitrate_kbps: u32) -> anyhow::Result<Self> {
+        let out = sink.specs();
+        anyhow::ensure!(out.format == Format::S16le && out.channels == 2,
+            "AC-3 encoding needs a 2ch S16LE PCM output, got {}ch {:?}", out.channels, out.format);
+        anyhow::ensure!(AC3_RATES.contains(&out.rate), "AC-3 cannot be sent at {} Hz, use 32000, 44100 or 48000", out.rate);
+        anyhow::ensure!((1..=6).contains(&input.channels), "AC-3 carries at most 6 channels, got {}", input.channels);
+        anyhow::ensure!(BITRATES_KBPS.contains(&(bitrate_kbps as usize)), "{bitrate_kbps} kbps is not an AC-3 bitrate");
+        let in_format = ffmpeg_format(input.format).with_context(|| format!("cannot encode {:?} samples", input.format))?;
+
+        let args = [
+            "-f", in_format, "-ac", &input.channels.to_string(), "-ar", &input.rate.to_string(), "-i", "pipe:0",
+            "-c:a", "ac3", "-b:a", &format!("{bitrate_kbps}k"), "-ar", &out.rate.to_string(), "-f", "spdif",
+        ].map(String::from).to_vec();
+        Ok(Self { process: FfmpegProcess::spawn(sink, args)?, input })
+    }
+}
+
+impl AudioSink for FfmpegAc3EncoderSink {
+    fn write(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        self.process.write(bytes)
+    }
+
+    fn specs(&self) -> Spec {
+        self.input
+    }
+
+    /// Flush the last frames out of ffmpeg, the next write starts a new encoder
+    fn deactivate(&mut self) -> anyhow::Result<()> {
+        self.process.drain()?;
+        self.process.deactivate_sink()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use crate::iec61937_detector::StreamType;
+    use crate::iec61937_framer::Iec61937Framer;
+
+    /// Records what it is given
+    struct Capture(Arc<Mutex<Vec<u8>>>);
+    impl AudioSink for Capture {
+        fn write(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+            self.0.lock().unwrap().extend_from_slice(bytes);
+            Ok(())
+        }
+
+        fn specs(&self) -> Spec {
+            Spec { format: Format::S16le, rate: 48_000, channels: 2 }
+        }
+    }
+
+    /// AC-3 bursts in what the sink got
+    fn ac3_bursts(bytes: &[u8]) -> usize {
+        let mut framer = Iec61937Framer::new();
+        let mut bursts = framer.push(bytes);
+        bursts.extend(framer.flush());
+        bursts.iter().filter(|b| b.preamble.stream_type == StreamType::Ac3).count()
+    }
+
+    #[test]
+    #[ignore = "needs ffmpeg"]
+    fn deactivate_flushes_the_encoded_tail() {
+        let written = Arc::default();
+        let input = Spec { format: Format::S16le, rate: 48_000, channels: 6 };
+        let mut encoder = FfmpegAc3EncoderSink::wrap(Box::new(Capture(Arc::clone(&written))), input, 448).unwrap();
+        // 100 ms of a 5.1 tone: 3 AC-3 frames and a bit
+        let pcm: Vec<u8> = (0..4800)
+            .flat_map(|i| [((i as f32 * 0.06).sin() * 8000.0) as i16; 6])
+            .flat_map(i16::to_le_bytes)
+            .collect();
+
+        encoder.write(&pcm).unwrap();
+        encoder.deactivate().unwrap();
+        let first = ac3_bursts(&written.lock().unwrap());
+        assert!(first >= 3, "{first} AC-3 bursts after the first take");
+
+        // a new encoder starts with the next take
+        encoder.write(&pcm).unwrap();
+        encoder.deactivate().unwrap();
+        assert!(ac3_bursts(&written.lock().unwrap()) >= 2 * first);
+    }
+}
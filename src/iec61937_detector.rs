@@ -1,9 +1,11 @@
-/// IEC-61937 preamble words (big-endian)
-const PA_SYNC: u16 = 0xF872;
-const PB_SYNC: u16 = 0x4E1F;
+use std::borrow::Cow;
 
-const DEFAULT_CHUNK_FRAMES: usize = 2048;
-const DEFAULT_DET_WINDOW_CHUNKS: usize = 64;
+/// Pa + Pb + Pc + Pd
+pub const PREAMBLE_BYTES: usize = 8;
+
+/// IEC-61937 preamble words (Pa, Pb as 16-bit values)
+pub const PA_SYNC: u16 = 0xF872;
+pub const PB_SYNC: u16 = 0x4E1F;
 
 // Pc (16-bit) bit layout:
 //  [6:0]   data_type
@@ -21,10 +23,25 @@ pub const PC_INFO_SHIFT: u8 = 8;
 pub const PC_STRM_SHIFT: u8 = 13;
 
 #[repr(u8)]
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum StreamType {
+    Null = 0x00,  // stuffing, no payload
     Ac3 = 0x01,
+    Pause = 0x03, // gap in the compressed stream
+    Mpeg1Layer1 = 0x04,
+    Mpeg1Layer23 = 0x05,    // also MPEG-2 without extension
+    Mpeg2Ext = 0x06,        // MPEG-2 with extension
+    Mpeg2Aac = 0x07,        // ADTS
+    Mpeg2Layer1Lsf = 0x08,  // low sampling frequency
+    Mpeg2Layer23Lsf = 0x09, // low sampling frequency
+    DtsType1 = 0x0B, // 512 samples per frame
+    DtsType2 = 0x0C, // 1024 samples per frame
+    DtsType3 = 0x0D, // 2048 samples per frame
+    DtsType4 = 0x11, // DTS-HD, repetition period in Pc info
+    Mpeg2AacLsf = 0x13,
+    Mpeg4Aac = 0x14,        // LATM/LOAS
     EAc3 = 0x15,
+    TrueHd = 0x16,   // MAT frames
     // … add more as needed
     Unknown(u8),
 }
@@ -32,15 +49,128 @@ pub enum StreamType {
 impl From<u8> for StreamType {
     fn from(value: u8) -> Self {
         match value {
+            0x00 => StreamType::Null,
             0x01 => StreamType::Ac3,
+            0x03 => StreamType::Pause,
+            0x04 => StreamType::Mpeg1Layer1,
+            0x05 => StreamType::Mpeg1Layer23,
+            0x06 => StreamType::Mpeg2Ext,
+            0x07 => StreamType::Mpeg2Aac,
+            0x08 => StreamType::Mpeg2Layer1Lsf,
+            0x09 => StreamType::Mpeg2Layer23Lsf,
+            0x0B => StreamType::DtsType1,
+            0x0C => StreamType::DtsType2,
+            0x0D => StreamType::DtsType3,
+            0x11 => StreamType::DtsType4,
+            0x13 => StreamType::Mpeg2AacLsf,
+            0x14 => StreamType::Mpeg4Aac,
             0x15 => StreamType::EAc3,
+            0x16 => StreamType::TrueHd,
             other => StreamType::Unknown(other),
         }
     }
 }
 
-#[derive(Debug)]
+impl StreamType {
+    /// Pc[6:0] value of this type
+    pub fn data_type(&self) -> u8 {
+        match self {
+            StreamType::Null => 0x00,
+            StreamType::Ac3 => 0x01,
+            StreamType::Pause => 0x03,
+            StreamType::Mpeg1Layer1 => 0x04,
+            StreamType::Mpeg1Layer23 => 0x05,
+            StreamType::Mpeg2Ext => 0x06,
+            StreamType::Mpeg2Aac => 0x07,
+            StreamType::Mpeg2Layer1Lsf => 0x08,
+            StreamType::Mpeg2Layer23Lsf => 0x09,
+            StreamType::DtsType1 => 0x0B,
+            StreamType::DtsType2 => 0x0C,
+            StreamType::DtsType3 => 0x0D,
+            StreamType::DtsType4 => 0x11,
+            StreamType::Mpeg2AacLsf => 0x13,
+            StreamType::Mpeg4Aac => 0x14,
+            StreamType::EAc3 => 0x15,
+            StreamType::TrueHd => 0x16,
+            StreamType::Unknown(t) => *t,
+        }
+    }
+
+    /// False for the NULL and PAUSE bursts, which only fill gaps in the stream.
+    pub fn carries_audio(&self) -> bool {
+        !matches!(self, StreamType::Null | StreamType::Pause)
+    }
+
+    /// Types only carried by a high-bit-rate (8ch 192 kHz) container.
+    pub fn is_hbr(&self) -> bool {
+        matches!(self, StreamType::DtsType4 | StreamType::TrueHd)
+    }
+
+    /// Frames (2ch sample pairs) between two consecutive bursts of this type.
+    pub fn repetition_period(&self) -> Option<usize> {
+        match self {
+            StreamType::Ac3 => Some(1536),
+            StreamType::Mpeg1Layer1 => Some(384),
+            StreamType::Mpeg1Layer23 | StreamType::Mpeg2Ext => Some(1152),
+            StreamType::Mpeg2Aac => Some(1024),
+            StreamType::Mpeg2Layer1Lsf => Some(768),
+            StreamType::Mpeg2Layer23Lsf => Some(2304),
+            StreamType::DtsType1 => Some(512),
+            StreamType::DtsType2 => Some(1024),
+            StreamType::DtsType3 => Some(2048),
+            StreamType::Mpeg2AacLsf => Some(2048),
+            StreamType::Mpeg4Aac => Some(1024), // one AAC frame
+            StreamType::EAc3 => Some(6144),
+            StreamType::TrueHd => Some(15360),
+            StreamType::Null | StreamType::Pause | StreamType::DtsType4 | StreamType::Unknown(_) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for StreamType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StreamType::Null => write!(f, "NULL"),
+            StreamType::Ac3 => write!(f, "AC-3"),
+            StreamType::Pause => write!(f, "PAUSE"),
+            StreamType::Mpeg1Layer1 => write!(f, "MPEG-1 Layer 1"),
+            StreamType::Mpeg1Layer23 => write!(f, "MPEG-1 Layer 2/3"),
+            StreamType::Mpeg2Ext => write!(f, "MPEG-2 extension"),
+            StreamType::Mpeg2Aac => write!(f, "MPEG-2 AAC"),
+            StreamType::Mpeg2Layer1Lsf => write!(f, "MPEG-2 Layer 1 LSF"),
+            StreamType::Mpeg2Layer23Lsf => write!(f, "MPEG-2 Layer 2/3 LSF"),
+            StreamType::DtsType1 => write!(f, "DTS type I"),
+            StreamType::DtsType2 => write!(f, "DTS type II"),
+            StreamType::DtsType3 => write!(f, "DTS type III"),
+            StreamType::DtsType4 => write!(f, "DTS-HD"),
+            StreamType::Mpeg2AacLsf => write!(f, "MPEG-2 AAC LSF"),
+            StreamType::Mpeg4Aac => write!(f, "MPEG-4 AAC"),
+            StreamType::EAc3 => write!(f, "E-AC-3"),
+            StreamType::TrueHd => write!(f, "TrueHD"),
+            StreamType::Unknown(t) => write!(f, "unknown type 0x{t:02X}"),
+        }
+    }
+}
+
+/// Byte order of the 16-bit IEC-61937 words in the captured stream
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Endianness {
+    Little, // 72 F8 1F 4E, what S16LE capture gives
+    Big,    // F8 72 4E 1F, byte-swapped hardware or S16BE capture
+}
+
+/// Where the 16-bit IEC-61937 word sits inside one captured sample.
+/// Wider containers (S24, S32) carry it in their top bits.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WordLayout {
+    pub sample_bytes: usize,     // container size
+    pub word_offset: usize,      // first byte of the 16-bit word
+    pub endianness: Endianness,  // byte order of the container
+}
+
+#[derive(Clone, Debug)]
 pub struct Iec61937Preamble {
+    pub endianness: Endianness,  // byte order the preamble was found in
     pub stream_type: StreamType, // Pc[6:0]
     pub error: bool,             // Pc[7]
     pub info: u8,                // Pc[12:8] (type-dependent width)
@@ -51,47 +181,183 @@ pub struct Iec61937Preamble {
 impl Iec61937Preamble {
     pub fn payload_bytes(&self) -> Option<usize> {
         match self.stream_type {
-            StreamType::Ac3 => Some((self.length_code as usize) / 8), // Pd in bits → bytes
-            StreamType::EAc3 => Some(self.length_code as usize),      // Pd already in bytes
+            StreamType::Null
+            | StreamType::Ac3
+            | StreamType::Pause
+            | StreamType::Mpeg1Layer1
+            | StreamType::Mpeg1Layer23
+            | StreamType::Mpeg2Ext
+            | StreamType::Mpeg2Aac
+            | StreamType::Mpeg2Layer1Lsf
+            | StreamType::Mpeg2Layer23Lsf
+            | StreamType::DtsType1
+            | StreamType::DtsType2
+            | StreamType::DtsType3
+            | StreamType::Mpeg2AacLsf
+            | StreamType::Mpeg4Aac => Some((self.length_code as usize) / 8), // Pd in bits → bytes
+            StreamType::EAc3
+            | StreamType::DtsType4
+            | StreamType::TrueHd => Some(self.length_code as usize), // Pd already in bytes
             StreamType::Unknown(_) => None,
         }
     }
+
+    /// Frames between this burst and the next one. DTS type IV carries it in Pc info.
+    pub fn repetition_period(&self) -> Option<usize> {
+        match self.stream_type {
+            StreamType::DtsType4 if self.info <= 5 => Some(512 << self.info),
+            other => other.repetition_period(),
+        }
+    }
+}
+
+// Start of a TrueHD MAT frame and of a DTS-HD type IV payload (codec byte order)
+pub const MAT_START_CODE: [u8; 20] = [
+    0x07, 0x9E, 0x00, 0x03, 0x84, 0x01, 0x01, 0x01, 0x80, 0x00,
+    0x56, 0xA5, 0x3B, 0xF4, 0x81, 0x83, 0x49, 0x80, 0x77, 0xE0,
+];
+pub const DTSHD_START_CODE: [u8; 10] = [0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFE, 0xFE];
+
+/// IEC-61937 words are little-endian on the wire, codecs are big-endian:
+/// swap every 16-bit word to get the codec byte order back.
+pub fn swap_words(bytes: &[u8]) -> Vec<u8> {
+    bytes.chunks(2).flat_map(|w| w.iter().rev().copied()).collect()
 }
 
 pub struct Iec61937Detector {}
+impl Default for Iec61937Detector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 impl Iec61937Detector {
     pub fn new() -> Self {
         Self {}
     }
 
+    /// Check that a burst payload (as captured, little-endian words) starts with
+    /// the codec's own sync. `None` when we don't know what to look for.
+    pub fn payload_sync(stream_type: StreamType, payload: &[u8]) -> Option<bool> {
+        use StreamType::*;
+        let starts_with = |code: &[u8]| payload.len() >= code.len() && swap_words(&payload[..code.len()]) == code;
+
+        // (sync, mask) over the first 32 bits of the payload, in codec byte order
+        let (sync, mask): (u32, u32) = match stream_type {
+            TrueHd => return Some(starts_with(&MAT_START_CODE)),
+            DtsType4 => return Some(starts_with(&DTSHD_START_CODE)),
+            Ac3 | EAc3 => (0x0B77_0000, 0xFFFF_0000),
+            DtsType1 | DtsType2 | DtsType3 => (0x7FFE_8001, 0xFFFF_FFFF), // 16-bit big-endian core
+            Mpeg1Layer1 | Mpeg1Layer23 | Mpeg2Ext | Mpeg2Layer1Lsf | Mpeg2Layer23Lsf => (0xFFE0_0000, 0xFFE0_0000),
+            Mpeg2Aac | Mpeg2AacLsf => (0xFFF0_0000, 0xFFF6_0000), // ADTS, layer 0
+            Mpeg4Aac => (0x56E0_0000, 0xFFE0_0000),               // LOAS
+            Null | Pause | Unknown(_) => return None,
+        };
+        if payload.len() < 4 {
+            return Some(false);
+        }
+        let head = swap_words(&payload[..4]);
+        Some(u32::from_be_bytes([head[0], head[1], head[2], head[3]]) & mask == sync)
+    }
+
+    /// Score (0..=1) how much a burst looks like real IEC-61937 rather than a sync
+    /// pattern occurring by chance in PCM. `burst` runs from Pa up to the next Pa,
+    /// in little-endian words. Only the checks that apply to the data type count:
+    /// plausible Pd, spacing matching the repetition period, zero padding and the
+    /// codec's own sync at the start of the payload.
+    pub fn confidence(preamble: &Iec61937Preamble, burst: &[u8]) -> f32 {
+        let period_bytes = preamble.repetition_period().map(|frames| frames * 4);
+        let payload_end = preamble
+            .payload_bytes()
+            .map(|len| PREAMBLE_BYTES + len)
+            .filter(|end| *end <= burst.len() && period_bytes.is_none_or(|p| *end <= p));
+        let mut checks = Vec::with_capacity(4);
+
+        checks.push(payload_end.is_some_and(|end| end > PREAMBLE_BYTES || !preamble.stream_type.carries_audio()));
+        if let Some(p) = period_bytes {
+            checks.push(burst.len() == p);
+        }
+        match payload_end {
+            Some(end) => {
+                let padding = &burst[end..];
+                checks.push(padding.iter().all(|b| *b == 0) && (!padding.is_empty() || Some(burst.len()) == period_bytes));
+                if let Some(sync) = Self::payload_sync(preamble.stream_type, &burst[PREAMBLE_BYTES..end]) {
+                    checks.push(sync);
+                }
+            }
+            // payload and padding can't be located: both count as failed
+            None => checks.extend([false, false]),
+        }
+
+        checks.iter().filter(|ok| **ok).count() as f32 / checks.len() as f32
+    }
+
+    /// Pull the 16-bit words out of wider sample containers, as little-endian words.
+    /// 16-bit input is returned untouched so its byte order can still be detected.
+    pub fn extract_words(bytes: &[u8], layout: WordLayout) -> Cow<'_, [u8]> {
+        if layout.sample_bytes == 2 {
+            return Cow::Borrowed(bytes);
+        }
+        let words = bytes
+            .chunks_exact(layout.sample_bytes)
+            .flat_map(|sample| {
+                let w = &sample[layout.word_offset..layout.word_offset + 2];
+                match layout.endianness {
+                    Endianness::Little => [w[0], w[1]],
+                    Endianness::Big => [w[1], w[0]],
+                }
+            })
+            .collect();
+        Cow::Owned(words)
+    }
+
     pub fn find_preamble(bytes: &[u8]) -> Option<Iec61937Preamble> {
-        if bytes.len() < 8 {
+        Self::find_preamble_at(bytes).map(|(_, preamble)| preamble)
+    }
+
+    /// Same as `find_preamble` but also returns the byte offset of Pa in `bytes`.
+    /// The preamble words are aligned to the 16-bit words of the stream, so `bytes` must
+    /// start on a word and only even offsets are tried.
+    pub fn find_preamble_at(bytes: &[u8]) -> Option<(usize, Iec61937Preamble)> {
+        if bytes.len() < PREAMBLE_BYTES {
             return None;
         }
 
-        // IEC61937 sync words, little endian
-        const PA_SYNC_LE: [u8; 2] = [0x72, 0xF8]; // 0xF872
-        const PB_SYNC_LE: [u8; 2] = [0x1F, 0x4E]; // 0x4E1F
+        // IEC61937 sync words, in both byte orders
+        const PA_SYNC_LE: [u8; 2] = PA_SYNC.to_le_bytes();
+        const PB_SYNC_LE: [u8; 2] = PB_SYNC.to_le_bytes();
+        const PA_SYNC_BE: [u8; 2] = PA_SYNC.to_be_bytes();
+        const PB_SYNC_BE: [u8; 2] = PB_SYNC.to_be_bytes();
 
         // scan up to len - 7 to have room for the whole header
-        for i in 0..=bytes.len().saturating_sub(8) {
-            if bytes[i..i + 2] == PA_SYNC_LE && bytes[i + 2..i + 4] == PB_SYNC_LE {
-                let pc = u16::from_le_bytes([bytes[i + 4], bytes[i + 5]]);
-                let pd = u16::from_le_bytes([bytes[i + 6], bytes[i + 7]]);
-
-                let data_type = ((pc & PC_TYPE_MASK) >> PC_TYPE_SHIFT) as u8;
-                let error = ((pc & PC_ERR_MASK) >> PC_ERR_SHIFT) != 0;
-                let info = ((pc & PC_INFO_MASK) >> PC_INFO_SHIFT) as u8;
-                let stream_num = ((pc & PC_STRM_MASK) >> PC_STRM_SHIFT) as u8;
-
-                return Some(Iec61937Preamble {
-                    stream_type: data_type.into(),
-                    error,
-                    info,
-                    stream_number: stream_num,
-                    length_code: pd,
-                });
-            }
+        for i in (0..=bytes.len().saturating_sub(8)).step_by(2) {
+            let endianness = if bytes[i..i + 2] == PA_SYNC_LE && bytes[i + 2..i + 4] == PB_SYNC_LE {
+                Endianness::Little
+            } else if bytes[i..i + 2] == PA_SYNC_BE && bytes[i + 2..i + 4] == PB_SYNC_BE {
+                Endianness::Big
+            } else {
+                continue;
+            };
+
+            let word = |at: usize| match endianness {
+                Endianness::Little => u16::from_le_bytes([bytes[at], bytes[at + 1]]),
+                Endianness::Big => u16::from_be_bytes([bytes[at], bytes[at + 1]]),
+            };
+            let pc = word(i + 4);
+            let pd = word(i + 6);
+
+            let data_type = ((pc & PC_TYPE_MASK) >> PC_TYPE_SHIFT) as u8;
+            let error = ((pc & PC_ERR_MASK) >> PC_ERR_SHIFT) != 0;
+            let info = ((pc & PC_INFO_MASK) >> PC_INFO_SHIFT) as u8;
+            let stream_num = ((pc & PC_STRM_MASK) >> PC_STRM_SHIFT) as u8;
+
+            return Some((i, Iec61937Preamble {
+                endianness,
+                stream_type: data_type.into(),
+                error,
+                info,
+                stream_number: stream_num,
+                length_code: pd,
+            }));
         }
         None
     }
@@ -118,4 +384,134 @@ mod tests {
         assert_eq!(preamble.unwrap().stream_type, Ac3);
         Ok(())
     }
+
+    #[test]
+    fn ignores_sync_words_across_samples() {
+        // Pa/Pb starting in the middle of a 16-bit PCM sample
+        let bytes = [0x00, 0x72, 0xF8, 0x1F, 0x4E, 0x01, 0x00, 0x00, 0x10, 0x00];
+        assert!(Iec61937Detector::find_preamble(&bytes).is_none());
+        assert_eq!(Iec61937Detector::find_preamble_at(&bytes[1..]).map(|(at, _)| at), Some(0));
+    }
+
+    #[test]
+    fn can_detect_dts() {
+        // DTS type I burst header: Pc = 0x000B, Pd = 2012 bytes expressed in bits
+        let mut bytes = vec![0u8; 16];
+        bytes[4..12].copy_from_slice(&[0x72, 0xF8, 0x1F, 0x4E, 0x0B, 0x00, 0xE0, 0x3E]);
+
+        let preamble = Iec61937Detector::find_preamble(&bytes).expect("DTS preamble");
+        assert_eq!(preamble.stream_type, StreamType::DtsType1);
+        assert_eq!(preamble.payload_bytes(), Some(2012));
+        assert_eq!(preamble.stream_type.repetition_period(), Some(512));
+        assert_eq!(preamble.stream_type.to_string(), "DTS type I");
+    }
+
+    #[test]
+    fn can_tell_mpeg_types_apart() {
+        let types = [
+            (0x04, StreamType::Mpeg1Layer1, 384),
+            (0x05, StreamType::Mpeg1Layer23, 1152),
+            (0x06, StreamType::Mpeg2Ext, 1152),
+            (0x07, StreamType::Mpeg2Aac, 1024),
+            (0x08, StreamType::Mpeg2Layer1Lsf, 768),
+            (0x09, StreamType::Mpeg2Layer23Lsf, 2304),
+            (0x13, StreamType::Mpeg2AacLsf, 2048),
+            (0x14, StreamType::Mpeg4Aac, 1024),
+        ];
+        for (pc, expected, period) in types {
+            assert!(!expected.is_hbr());
+            let bytes = [0x72, 0xF8, 0x1F, 0x4E, pc, 0x00, 0x00, 0x10];
+            let preamble = Iec61937Detector::find_preamble(&bytes).expect("MPEG preamble");
+            assert_eq!(preamble.stream_type, expected);
+            assert_eq!(preamble.payload_bytes(), Some(0x1000 / 8));
+            assert_eq!(preamble.stream_type.repetition_period(), Some(period));
+        }
+    }
+
+    /// AC-3 burst of one 1536-frame period, payload starting with the 0x0B77 sync
+    fn ac3_burst(payload: usize) -> Vec<u8> {
+        let mut b = vec![0x72, 0xF8, 0x1F, 0x4E, 0x01, 0x00];
+        b.extend_from_slice(&((payload * 8) as u16).to_le_bytes());
+        b.extend([0x77, 0x0B]);
+        b.extend((2..payload).map(|i| (i % 251) as u8 | 1));
+        b.resize(1536 * 4, 0);
+        b
+    }
+
+    #[test]
+    fn scores_real_bursts_above_chance_matches() {
+        let burst = ac3_burst(1000);
+        let preamble = Iec61937Detector::find_preamble(&burst).unwrap();
+        assert_eq!(Iec61937Detector::confidence(&preamble, &burst), 1.0);
+
+        // wrong spacing, no codec sync: a sync pattern found by chance in PCM
+        let mut noise = burst.clone();
+        noise[8..10].copy_from_slice(&[0x12, 0x34]);
+        noise.truncate(3000);
+        noise[2000] = 0x42;
+        assert!(Iec61937Detector::confidence(&preamble, &noise) < 0.5);
+
+        // Pd longer than the repetition period
+        let mut bytes = burst.clone();
+        bytes[6..8].copy_from_slice(&(7000u16 * 8).to_le_bytes());
+        let preamble = Iec61937Detector::find_preamble(&bytes).unwrap();
+        assert!(Iec61937Detector::confidence(&preamble, &bytes) < 0.5);
+    }
+
+    #[test]
+    fn can_detect_in_wide_containers() {
+        let words = [0x72, 0xF8, 0x1F, 0x4E, 0x01, 0x00, 0x00, 0x10];
+
+        // S24LE: word in the two upper bytes, low byte is noise
+        let s24le: Vec<u8> = words.chunks(2).flat_map(|w| [0xAA, w[0], w[1]]).collect();
+        let layout = WordLayout { sample_bytes: 3, word_offset: 1, endianness: Endianness::Little };
+        let extracted = Iec61937Detector::extract_words(&s24le, layout);
+        assert_eq!(&extracted[..], &words);
+
+        // S32BE: word in the two first bytes, most significant first
+        let s32be: Vec<u8> = words.chunks(2).flat_map(|w| [w[1], w[0], 0x55, 0xAA]).collect();
+        let layout = WordLayout { sample_bytes: 4, word_offset: 0, endianness: Endianness::Big };
+        let preamble = Iec61937Detector::find_preamble(&Iec61937Detector::extract_words(&s32be, layout))
+            .expect("preamble in S32BE");
+        assert_eq!(preamble.stream_type, StreamType::Ac3);
+        assert_eq!(preamble.endianness, Endianness::Little);
+    }
+
+    #[test]
+    fn can_detect_pause_and_null() {
+        // PAUSE burst: Pd = 32 bits, payload = gap length
+        let bytes = [0x72, 0xF8, 0x1F, 0x4E, 0x03, 0x00, 0x20, 0x00, 0x00, 0x01, 0x00, 0x00];
+        let preamble = Iec61937Detector::find_preamble(&bytes).expect("PAUSE preamble");
+        assert_eq!(preamble.stream_type, StreamType::Pause);
+        assert_eq!(preamble.payload_bytes(), Some(4));
+        assert!(!preamble.stream_type.carries_audio());
+
+        let bytes = [0x72, 0xF8, 0x1F, 0x4E, 0x00, 0x00, 0x00, 0x00];
+        let preamble = Iec61937Detector::find_preamble(&bytes).expect("NULL preamble");
+        assert_eq!(preamble.stream_type, StreamType::Null);
+        assert!(!preamble.stream_type.carries_audio());
+        assert!(StreamType::Ac3.carries_audio());
+    }
+
+    #[test]
+    fn can_recognise_hbr_payloads() {
+        // TrueHD: Pd = 61424 bytes, payload starts with the MAT start code
+        let mut bytes = vec![0x72, 0xF8, 0x1F, 0x4E, 0x16, 0x00, 0xF0, 0xEF];
+        bytes.extend(swap_words(&MAT_START_CODE));
+        let preamble = Iec61937Detector::find_preamble(&bytes).expect("TrueHD preamble");
+        assert_eq!(preamble.stream_type, StreamType::TrueHd);
+        assert_eq!(preamble.payload_bytes(), Some(61424));
+        assert_eq!(preamble.repetition_period(), Some(15360));
+        assert_eq!(Iec61937Detector::payload_sync(preamble.stream_type, &bytes[8..]), Some(true));
+        assert_eq!(Iec61937Detector::payload_sync(preamble.stream_type, &bytes[9..]), Some(false));
+
+        // DTS-HD: subtype 4 in Pc info -> 8192 frames
+        let mut bytes = vec![0x72, 0xF8, 0x1F, 0x4E, 0x11, 0x04, 0x00, 0x20];
+        bytes.extend(swap_words(&DTSHD_START_CODE));
+        let preamble = Iec61937Detector::find_preamble(&bytes).expect("DTS-HD preamble");
+        assert_eq!(preamble.stream_type, StreamType::DtsType4);
+        assert!(preamble.stream_type.is_hbr());
+        assert_eq!(preamble.repetition_period(), Some(8192));
+        assert_eq!(Iec61937Detector::payload_sync(preamble.stream_type, &bytes[8..]), Some(true));
+    }
 }
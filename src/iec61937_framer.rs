@@ -0,0 +1,192 @@
+/* Stateful IEC-61937 burst framer: reassembles bursts across read_chunk() boundaries */
+use std::borrow::Cow;
+use crate::iec61937_detector::{swap_words, Endianness, Iec61937Detector, Iec61937Preamble, PREAMBLE_BYTES};
+
+/// Upper bound for one burst (preamble + payload + padding) before we give up
+/// waiting for the next preamble. Twice the longest repetition period
+/// (DTS type IV at 16384 frames of 2ch S16LE).
+pub(crate) const MAX_BURST_BYTES: usize = 2 * 16384 * 4;
+
+/// One complete burst, as it was found in the input stream.
+#[derive(Clone, Debug)]
+pub struct Iec61937Burst {
+    pub preamble: Iec61937Preamble,
+    pub bytes: Vec<u8>, // preamble + payload + padding, up to the next Pa
+}
+
+impl Iec61937Burst {
+    /// Payload only (without preamble and padding), when Pd can be interpreted.
+    pub fn payload(&self) -> Option<&[u8]> {
+        let len = self.preamble.payload_bytes()?;
+        self.bytes.get(PREAMBLE_BYTES..PREAMBLE_BYTES + len)
+    }
+
+    /// How much this looks like a real burst, see `Iec61937Detector::confidence`.
+    pub fn confidence(&self) -> f32 {
+        Iec61937Detector::confidence(&self.preamble, &self.le_bytes())
+    }
+
+    /// The burst with its words in little-endian order, as ffmpeg's `spdif` demuxer expects.
+    pub fn le_bytes(&self) -> Cow<'_, [u8]> {
+        match self.preamble.endianness {
+            Endianness::Little => Cow::Borrowed(&self.bytes),
+            Endianness::Big => Cow::Owned(swap_words(&self.bytes)),
+        }
+    }
+}
+
+pub struct Iec61937Framer {
+    pending: Vec<u8>,                   // bytes not yet emitted
+    current: Option<Iec61937Preamble>,  // preamble sitting at pending[0]
+    scan_from: usize,                   // where to resume looking for the next preamble in pending
+}
+
+impl Default for Iec61937Framer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Iec61937Framer {
+    pub fn new() -> Self {
+        Self { pending: Vec::new(), current: None, scan_from: 0 }
+    }
+
+    /// True while a preamble has been seen and its burst is not complete yet.
+    pub fn is_locked(&self) -> bool {
+        self.current.is_some()
+    }
+
+    /// Feed the next chunk of input, returns every burst completed by it.
+    ///
+    /// A burst is complete once the next preamble is found after its payload, so
+    /// that the padding is part of it. The search for that preamble starts at the
+    /// end of the payload (given by `payload_bytes`) to avoid matching inside it.
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<Iec61937Burst> {
+        self.pending.extend_from_slice(bytes);
+        let mut bursts = Vec::new();
+
+        loop {
+            let Some(preamble) = &self.current else {
+                match Iec61937Detector::find_preamble_at(&self.pending) {
+                    Some((pos, preamble)) => {
+                        self.pending.drain(..pos);
+                        self.current = Some(preamble);
+                        continue;
+                    }
+                    None => {
+                        // keep what could be the start of a split preamble, on a word boundary
+                        let keep = self.pending.len().min(PREAMBLE_BYTES - 1);
+                        self.pending.drain(..(self.pending.len() - keep) & !1);
+                        break;
+                    }
+                }
+            };
+
+            // Pd is in bits for some types, round the payload up to a whole 16-bit word
+            let payload_end = PREAMBLE_BYTES + preamble.payload_bytes().unwrap_or(0).next_multiple_of(2);
+            if self.pending.len() < payload_end {
+                break;
+            }
+
+            let from = self.scan_from.max(payload_end);
+            match Iec61937Detector::find_preamble_at(&self.pending[from..]) {
+                Some((pos, _)) => {
+                    let burst_len = from + pos;
+                    bursts.push(self.take_burst(burst_len));
+                }
+                None if self.pending.len() > MAX_BURST_BYTES => {
+                    // no follow-up preamble: emit what belongs to the payload and resync
+                    eprintln!("IEC-61937 burst without follow-up preamble, resyncing");
+                    bursts.push(self.take_burst(payload_end));
+                }
+                None => {
+                    // next time, only where a preamble could still be complete
+                    self.scan_from = self.pending.len().saturating_sub(PREAMBLE_BYTES - 2) & !1;
+                    break;
+                }
+            }
+        }
+
+        bursts
+    }
+
+    /// Emit the burst currently being assembled, if any, trimmed to its payload
+    /// when the padding could not be confirmed by a following preamble.
+    pub fn flush(&mut self) -> Option<Iec61937Burst> {
+        let preamble = self.current.as_ref()?;
+        let payload_end = PREAMBLE_BYTES + preamble.payload_bytes().unwrap_or(0).next_multiple_of(2);
+        let burst = self.take_burst(payload_end.min(self.pending.len()));
+        self.pending.clear();
+        Some(burst)
+    }
+
+    fn take_burst(&mut self, len: usize) -> Iec61937Burst {
+        let preamble = self.current.take().expect("take_burst without preamble");
+        self.scan_from = 0;
+        let bytes = self.pending.drain(..len).collect();
+        Iec61937Burst { preamble, bytes }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iec61937_detector::StreamType;
+
+    /// AC-3 burst with `payload` bytes of payload, padded to one 1536-frame period
+    fn ac3_burst(payload: usize) -> Vec<u8> {
+        let mut b = vec![0x72, 0xF8, 0x1F, 0x4E, 0x01, 0x00];
+        b.extend_from_slice(&((payload * 8) as u16).to_le_bytes());
+        b.extend((0..payload).map(|i| (i % 251) as u8 | 1));
+        b.resize(1536 * 4, 0);
+        b
+    }
+
+    #[test]
+    fn reassembles_bursts_at_any_chunk_size() {
+        let stream: Vec<u8> = [ac3_burst(1000), ac3_burst(1000), ac3_burst(1000)].concat();
+
+        for chunk in [1, 3, 7, 256, 1024, 5000, stream.len()] {
+            let mut framer = Iec61937Framer::new();
+            let mut bursts = Vec::new();
+            for c in stream.chunks(chunk) {
+                bursts.extend(framer.push(c));
+            }
+            bursts.extend(framer.flush());
+
+            assert_eq!(bursts.len(), 3, "chunk={chunk}");
+            assert_eq!(bursts[0].bytes.len(), 1536 * 4, "chunk={chunk}");
+            assert_eq!(bursts[0].preamble.stream_type, StreamType::Ac3);
+            assert_eq!(bursts[0].payload().map(|p| p.len()), Some(1000));
+            assert_eq!(bursts[2].bytes.len(), PREAMBLE_BYTES + 1000);
+        }
+    }
+
+    #[test]
+    fn normalises_byte_swapped_bursts() {
+        let le: Vec<u8> = [ac3_burst(1000), ac3_burst(1000)].concat();
+        let be = swap_words(&le);
+
+        let mut framer = Iec61937Framer::new();
+        let bursts = framer.push(&be);
+        assert_eq!(bursts.len(), 1);
+        assert_eq!(bursts[0].preamble.endianness, Endianness::Big);
+        assert_eq!(bursts[0].preamble.stream_type, StreamType::Ac3);
+        assert_eq!(bursts[0].payload().map(|p| p.len()), Some(1000));
+        assert_eq!(bursts[0].le_bytes(), &le[..1536 * 4]);
+    }
+
+    #[test]
+    fn skips_preamble_lookalike_inside_payload() {
+        let mut first = ac3_burst(1000);
+        first[100..108].copy_from_slice(&[0x72, 0xF8, 0x1F, 0x4E, 0x01, 0x00, 0x10, 0x00]);
+        let stream: Vec<u8> = [first, ac3_burst(1000)].concat();
+
+        let mut framer = Iec61937Framer::new();
+        let bursts = framer.push(&stream);
+        assert_eq!(bursts.len(), 1);
+        assert_eq!(bursts[0].bytes.len(), 1536 * 4);
+        assert!(framer.is_locked());
+    }
+}
@@ -0,0 +1,271 @@
+/* IEC-61937 packer: wraps AC-3 / E-AC-3 / DTS elementary streams into padded bursts,
+   and builds test signals (2ch S16LE) mixing PCM, bursts, pauses and corrupted bytes */
+use std::f32::consts::PI;
+use std::path::PathBuf;
+use std::str::FromStr;
+use anyhow::{bail, ensure, Context};
+use crate::ac3::Ac3Header;
+use crate::iec61937_detector::{swap_words, StreamType, PA_SYNC, PB_SYNC, PC_INFO_SHIFT, PREAMBLE_BYTES};
+
+/// Bytes of one 2ch S16LE frame
+const FRAME_BYTES: usize = 4;
+/// Payload of a PAUSE burst, Pd = 32 bits
+const PAUSE_PAYLOAD_BYTES: usize = 4;
+
+/// Elementary streams the packer understands
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+    Ac3,
+    EAc3,
+    Dts,
+}
+
+/// One codec frame cut out of an elementary stream
+#[derive(Clone, Copy, Debug)]
+struct CodecFrame<'a> {
+    stream_type: StreamType,
+    bytes: &'a [u8],
+    info: u8,       // Pc[12:8], bsmod for AC-3
+    blocks: usize,  // E-AC-3 audio blocks, 0 for dependent substreams
+}
+
+/// Cut the frame starting at `es[0]`, from its own header
+fn next_frame(codec: Codec, es: &[u8]) -> anyhow::Result<CodecFrame<'_>> {
+    let (stream_type, len, info, blocks) = match codec {
+        Codec::Ac3 => {
+            let header = Ac3Header::parse(es)?;
+            (StreamType::Ac3, header.frame_bytes, es[5] & 0x07, 6)
+        }
+        Codec::EAc3 => {
+            ensure!(es.len() >= 6 && es[..2] == [0x0B, 0x77], "no E-AC-3 sync word");
+            ensure!(es[5] >> 3 > 10, "bsid {} is not E-AC-3", es[5] >> 3);
+            let strmtyp = es[2] >> 6;
+            let frmsiz = (((es[2] & 0x07) as usize) << 8) | es[3] as usize;
+            let blocks = match (es[4] >> 6, (es[4] >> 4) & 0x03) {
+                (3, _) => 6, // fscod2: always 6 blocks
+                (_, numblkscod) => [1, 2, 3, 6][numblkscod as usize],
+            };
+            (StreamType::EAc3, (frmsiz + 1) * 2, 0, if strmtyp == 1 { 0 } else { blocks })
+        }
+        Codec::Dts => {
+            ensure!(es.len() >= 8 && es[..4] == [0x7F, 0xFE, 0x80, 0x01], "no DTS sync word (16-bit big-endian core expected)");
+            let nblks = (((es[4] & 0x01) as usize) << 6) | (es[5] >> 2) as usize;
+            let fsize = (((es[5] & 0x03) as usize) << 12) | ((es[6] as usize) << 4) | (es[7] >> 4) as usize;
+            let stream_type = match (nblks + 1) * 32 {
+                512 => StreamType::DtsType1,
+                1024 => StreamType::DtsType2,
+                2048 => StreamType::DtsType3,
+                samples => bail!("DTS frames of {samples} samples cannot be carried"),
+            };
+            (stream_type, fsize + 1, 0, 0)
+        }
+    };
+    let bytes = es.get(..len).with_context(|| format!("truncated {stream_type} frame: {len} bytes expected, {} left", es.len()))?;
+    Ok(CodecFrame { stream_type, bytes, info, blocks })
+}
+
+/// One burst: preamble, payload (codec byte order in, little-endian words out) and
+/// zero padding up to the repetition period of `stream_type`.
+pub fn burst(stream_type: StreamType, info: u8, payload: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let period = stream_type.repetition_period()
+        .with_context(|| format!("{stream_type} has no fixed repetition period"))? * FRAME_BYTES;
+    ensure!(PREAMBLE_BYTES + payload.len() <= period,
+        "{} bytes of {stream_type} do not fit in a {period} bytes burst", payload.len());
+
+    // Pd counts bytes for the types detected with it in bytes, bits otherwise
+    let pd = match stream_type {
+        StreamType::EAc3 | StreamType::TrueHd | StreamType::DtsType4 => payload.len(),
+        _ => payload.len() * 8,
+    };
+    let pd = u16::try_from(pd).with_context(|| format!("{stream_type} payload too long for Pd"))?;
+    let mut out = preamble(stream_type, info, pd);
+    let mut words = payload.to_vec();
+    words.resize(payload.len().next_multiple_of(2), 0);
+    out.extend(swap_words(&words));
+    out.resize(period, 0);
+    Ok(out)
+}
+
+/// A PAUSE burst lasting `frames` frames (at least the preamble and its 32 bit payload)
+pub fn pause_burst(frames: usize) -> Vec<u8> {
+    let mut out = preamble(StreamType::Pause, 0, (PAUSE_PAYLOAD_BYTES * 8) as u16);
+    out.resize((frames * FRAME_BYTES).max(PREAMBLE_BYTES + PAUSE_PAYLOAD_BYTES), 0);
+    out
+}
+
+fn preamble(stream_type: StreamType, info: u8, pd: u16) -> Vec<u8> {
+    let pc = stream_type.data_type() as u16 | ((info as u16) << PC_INFO_SHIFT);
+    [PA_SYNC, PB_SYNC, pc, pd].iter().flat_map(|w| w.to_le_bytes()).collect()
+}
+
+/// Wrap a whole elementary stream into bursts. E-AC-3 frames are grouped by six
+/// audio blocks, dependent substreams riding along with their independent frame.
+pub fn pack(codec: Codec, es: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    let mut group: Vec<u8> = Vec::new();
+    let mut group_blocks = 0;
+    while pos < es.len() {
+        let frame = next_frame(codec, &es[pos..]).with_context(|| format!("at byte {pos} of the {codec:?} stream"))?;
+        pos += frame.bytes.len();
+        if codec != Codec::EAc3 {
+            out.extend(burst(frame.stream_type, frame.info, frame.bytes)?);
+            continue;
+        }
+
+        if frame.blocks > 0 && group_blocks >= 6 {
+            out.extend(burst(StreamType::EAc3, 0, &group)?);
+            group.clear();
+            group_blocks = 0;
+        }
+        group.extend_from_slice(frame.bytes);
+        group_blocks += frame.blocks;
+    }
+    if !group.is_empty() {
+        out.extend(burst(StreamType::EAc3, 0, &group)?);
+    }
+    Ok(out)
+}
+
+/// One part of a test signal, see `SignalBuilder`
+#[derive(Clone, Debug, PartialEq)]
+pub enum Segment {
+    /// Sine tone (silence at 0 Hz), -6 dBFS on both channels
+    Pcm { ms: u32, tone_hz: f32 },
+    /// An elementary stream file, packed with `pack`
+    Bursts { codec: Codec, path: PathBuf },
+    /// PAUSE burst
+    Pause { ms: u32 },
+    /// Flip every bit of `count` bytes at byte `offset` of the signal written so far
+    Corrupt { offset: usize, count: usize },
+}
+
+impl FromStr for Segment {
+    type Err = String;
+
+    /// pcm:MS[@HZ], ac3:FILE, eac3:FILE, dts:FILE, pause:MS or corrupt:OFFSET[+COUNT]
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, value) = s.split_once(':').ok_or("expected KIND:VALUE")?;
+        let number = |v: &str| v.parse::<u32>().map_err(|e| format!("{v:?}: {e}"));
+        let size = |v: &str| v.parse::<usize>().map_err(|e| format!("{v:?}: {e}"));
+        Ok(match kind {
+            "pcm" => match value.split_once('@') {
+                Some((ms, hz)) => Segment::Pcm { ms: number(ms)?, tone_hz: hz.parse().map_err(|e| format!("{hz:?}: {e}"))? },
+                None => Segment::Pcm { ms: number(value)?, tone_hz: 1000.0 },
+            },
+            "ac3" => Segment::Bursts { codec: Codec::Ac3, path: value.into() },
+            "eac3" => Segment::Bursts { codec: Codec::EAc3, path: value.into() },
+            "dts" => Segment::Bursts { codec: Codec::Dts, path: value.into() },
+            "pause" => Segment::Pause { ms: number(value)? },
+            "corrupt" => match value.split_once('+') {
+                Some((offset, count)) => Segment::Corrupt { offset: size(offset)?, count: size(count)? },
+                None => Segment::Corrupt { offset: size(value)?, count: 1 },
+            },
+            other => return Err(format!("unknown segment {other:?}, expected pcm, ac3, eac3, dts, pause or corrupt")),
+        })
+    }
+}
+
+/// Builds a 2ch S16LE test signal, segment after segment
+pub struct SignalBuilder {
+    rate: u32,
+    bytes: Vec<u8>,
+}
+
+impl SignalBuilder {
+    pub fn new(rate: u32) -> Self {
+        Self { rate, bytes: Vec::new() }
+    }
+
+    fn frames(&self, ms: u32) -> usize {
+        self.rate as usize * ms as usize / 1000
+    }
+
+    pub fn pcm(&mut self, frames: usize, tone_hz: f32) -> &mut Self {
+        let step = 2.0 * PI * tone_hz / self.rate as f32;
+        for n in 0..frames {
+            let sample = ((step * n as f32).sin() * 16384.0) as i16;
+            self.bytes.extend(sample.to_le_bytes().repeat(2));
+        }
+        self
+    }
+
+    pub fn bursts(&mut self, codec: Codec, es: &[u8]) -> anyhow::Result<&mut Self> {
+        self.bytes.extend(pack(codec, es)?);
+        Ok(self)
+    }
+
+    pub fn pause(&mut self, frames: usize) -> &mut Self {
+        self.bytes.extend(pause_burst(frames));
+        self
+    }
+
+    pub fn corrupt(&mut self, offset: usize, count: usize) -> anyhow::Result<&mut Self> {
+        let len = self.bytes.len();
+        let bytes = self.bytes.get_mut(offset..offset + count)
+            .with_context(|| format!("cannot corrupt {count} bytes at {offset}, the signal has {len} so far"))?;
+        bytes.iter_mut().for_each(|b| *b ^= 0xFF);
+        Ok(self)
+    }
+
+    pub fn segment(&mut self, segment: &Segment) -> anyhow::Result<&mut Self> {
+        match segment {
+            Segment::Pcm { ms, tone_hz } => Ok(self.pcm(self.frames(*ms), *tone_hz)),
+            Segment::Bursts { codec, path } => {
+                let es = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+                self.bursts(*codec, &es)
+            }
+            Segment::Pause { ms } => Ok(self.pause(self.frames(*ms))),
+            Segment::Corrupt { offset, count } => self.corrupt(*offset, *count),
+        }
+    }
+
+    pub fn build(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iec61937_framer::Iec61937Framer;
+
+    /// 640 kbps 48 kHz 5.1 syncframes (2560 bytes), silent
+    fn ac3_stream(frames: usize) -> Vec<u8> {
+        let mut frame = vec![0u8; 2560];
+        frame[..7].copy_from_slice(&[0x0B, 0x77, 0x00, 0x00, 0x24, 0x40, 0xE1]);
+        frame.repeat(frames)
+    }
+
+    #[test]
+    fn packed_signal_is_framed_back() {
+        let mut signal = SignalBuilder::new(48_000);
+        signal.pcm(4800, 440.0).bursts(Codec::Ac3, &ac3_stream(3)).unwrap().pause(1536);
+        let pcm_bytes = 4800 * FRAME_BYTES;
+        signal.bursts(Codec::Ac3, &ac3_stream(1)).unwrap();
+        let signal = signal.build();
+        assert_eq!(signal.len(), pcm_bytes + 5 * 1536 * FRAME_BYTES);
+
+        let mut framer = Iec61937Framer::new();
+        let mut bursts = framer.push(&signal);
+        bursts.extend(framer.flush());
+        let types: Vec<StreamType> = bursts.iter().map(|b| b.preamble.stream_type).collect();
+        use StreamType::*;
+        assert_eq!(types, [Ac3, Ac3, Ac3, Pause, Ac3]);
+        assert!(bursts[..3].iter().all(|b| b.confidence() == 1.0 && b.payload().unwrap().len() == 2560));
+        assert_eq!(swap_words(&bursts[0].payload().unwrap()[..4]), [0x0B, 0x77, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn corrupts_and_rejects() {
+        let mut signal = SignalBuilder::new(48_000);
+        signal.bursts(Codec::Ac3, &ac3_stream(1)).unwrap().corrupt(0, 2).unwrap();
+        assert_eq!(signal.build()[..4], [0x8D, 0x07, 0x1F, 0x4E]);
+
+        assert!(pack(Codec::Dts, &ac3_stream(1)).is_err());
+        assert!(pack(Codec::Ac3, &ac3_stream(2)[..3000]).is_err());
+        assert_eq!("pcm:500@440".parse(), Ok(Segment::Pcm { ms: 500, tone_hz: 440.0 }));
+        assert_eq!("corrupt:10+4".parse(), Ok(Segment::Corrupt { offset: 10, count: 4 }));
+        assert!("flac:x".parse::<Segment>().is_err());
+    }
+}
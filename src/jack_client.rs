@@ -0,0 +1,200 @@
+/* JACK clients for JackSource and JackSink: float ports on one side, interleaved samples in a lock-free ring on the other */
+// The process callback never blocks nor allocates: it only moves whole frames between
+// the ports and the ring. The pipeline side polls the ring at a quarter of a chunk.
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{anyhow, Context};
+use jack::{AsyncClient, AudioIn, AudioOut, Client, ClientOptions, ClientStatus, Control, NotificationHandler, Port, ProcessHandler, ProcessScope, RingBuffer, RingBufferReader, RingBufferWriter};
+use libpulse_binding::sample::{Format, Spec};
+
+/// Chunks the ring holds between the pipeline and the process callback
+const RING_CHUNKS: usize = 8;
+/// Largest JACK period we convert, bigger ones are muted
+const MAX_PERIOD_FRAMES: usize = 8192;
+
+/// Bytes of one sample in the formats the ports convert from/to
+fn sample_bytes(format: Format) -> Option<usize> {
+    match format {
+        Format::S16le => Some(2),
+        Format::S32le | Format::F32le => Some(4),
+        _ => None,
+    }
+}
+
+fn to_float(format: Format, bytes: &[u8]) -> f32 {
+    match format {
+        Format::S16le => i16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 32768.0,
+        Format::S32le => i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f32 / 2_147_483_648.0,
+        _ => f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+    }
+}
+
+/// Exact for 16-bit words: each one is a float with at most 16 significant bits
+fn to_word(sample: f32) -> i16 {
+    (sample * 32768.0).round().clamp(i16::MIN as f32, i16::MAX as f32) as i16
+}
+
+/// Raised when the server shuts the client down
+struct Shutdown(Arc<AtomicBool>);
+impl NotificationHandler for Shutdown {
+    fn shutdown(&mut self, _status: ClientStatus, reason: &str) {
+        eprintln!("JACK shut the client down: {reason}");
+        self.0.store(true, Ordering::Release);
+    }
+}
+
+/// Input ports -> interleaved S16LE words
+pub(crate) struct Capture {
+    ports: Vec<Port<AudioIn>>,
+    ring: RingBufferWriter,
+    scratch: Vec<u8>,
+    overruns: Arc<AtomicUsize>,
+}
+impl ProcessHandler for Capture {
+    fn process(&mut self, _: &Client, ps: &ProcessScope) -> Control {
+        let frames = (ps.n_frames() as usize).min(MAX_PERIOD_FRAMES);
+        let frame_bytes = self.ports.len() * 2;
+        let bytes = frames * frame_bytes;
+        if self.ring.space() < bytes {
+            self.overruns.fetch_add(1, Ordering::Relaxed);
+            return Control::Continue;
+        }
+        for (c, port) in self.ports.iter().enumerate() {
+            for (i, sample) in port.as_slice(ps)[..frames].iter().enumerate() {
+                let at = i * frame_bytes + c * 2;
+                self.scratch[at..at + 2].copy_from_slice(&to_word(*sample).to_le_bytes());
+            }
+        }
+        self.ring.write_buffer(&self.scratch[..bytes]);
+        Control::Continue
+    }
+}
+
+/// Interleaved samples -> output ports, silence on underrun
+pub(crate) struct Playback {
+    ports: Vec<Port<AudioOut>>,
+    ring: RingBufferReader,
+    format: Format,
+    scratch: Vec<u8>,
+    underruns: Arc<AtomicUsize>,
+}
+impl ProcessHandler for Playback {
+    fn process(&mut self, _: &Client, ps: &ProcessScope) -> Control {
+        let frames = (ps.n_frames() as usize).min(MAX_PERIOD_FRAMES);
+        let sample_bytes = sample_bytes(self.format).unwrap_or(4);
+        let frame_bytes = self.ports.len() * sample_bytes;
+        let available = self.ring.space() / frame_bytes;
+        if available < frames {
+            self.underruns.fetch_add(1, Ordering::Relaxed);
+        }
+        let got = available.min(frames);
+        self.ring.read_buffer(&mut self.scratch[..got * frame_bytes]);
+        for (c, port) in self.ports.iter_mut().enumerate() {
+            for (i, out) in port.as_mut_slice(ps).iter_mut().enumerate() {
+                let at = i * frame_bytes + c * sample_bytes;
+                *out = if i < got { to_float(self.format, &self.scratch[at..at + sample_bytes]) } else { 0.0 };
+            }
+        }
+        Control::Continue
+    }
+}
+
+/// A running client and the pipeline's end of its ring
+pub(crate) struct JackClient<P: ProcessHandler, R> {
+    _client: AsyncClient<Shutdown, P>,
+    pub(crate) ring: R,
+    shutdown: Arc<AtomicBool>,
+    xruns: Arc<AtomicUsize>, // overruns or underruns seen by the process callback
+    reported: usize,
+    pub(crate) poll: Duration,
+}
+
+impl<P: ProcessHandler, R> JackClient<P, R> {
+    /// Fails once the server is gone, logs the xruns since the last call
+    pub(crate) fn check(&mut self, what: &str) -> anyhow::Result<()> {
+        anyhow::ensure!(!self.shutdown.load(Ordering::Acquire), "JACK server went away");
+        let xruns = self.xruns.load(Ordering::Relaxed);
+        if xruns != self.reported {
+            eprintln!("JACK {what}: {} xruns", xruns - self.reported);
+            self.reported = xruns;
+        }
+        Ok(())
+    }
+}
+
+fn client(name: &str, rate: u32) -> anyhow::Result<Client> {
+    let (client, _) = Client::new(name, ClientOptions::NO_START_SERVER)
+        .map_err(|e| anyhow!("opening JACK client {name}: {e}"))?;
+    anyhow::ensure!(client.sample_rate() == rate as usize, "JACK runs at {} Hz, not {rate}; resampling is not supported", client.sample_rate());
+    Ok(client)
+}
+
+fn ring(spec: Spec, frame_bytes: usize, chunk_frames: usize) -> anyhow::Result<(RingBufferReader, RingBufferWriter, Duration)> {
+    let ring = RingBuffer::new(chunk_frames * frame_bytes * RING_CHUNKS).map_err(|e| anyhow!("jack_ringbuffer_create: {e}"))?;
+    let (reader, writer) = ring.into_reader_writer();
+    let poll = Duration::from_secs_f64(chunk_frames as f64 / spec.rate as f64 / 4.0);
+    Ok((reader, writer, poll))
+}
+
+/// Connect our ports to `targets` in order, the others stay unconnected
+fn connect(client: &Client, ports: &[String], targets: &[String], capture: bool) -> anyhow::Result<()> {
+    for (port, target) in ports.iter().zip(targets) {
+        let (from, to) = if capture { (target, port) } else { (port, target) };
+        client.connect_ports_by_name(from, to).map_err(|e| anyhow!("connecting {from} to {to}: {e}"))?;
+    }
+    Ok(())
+}
+
+/// Client `name` with one input port per channel of `spec` (2, or 8 for HBR), delivering S16LE words
+pub(crate) fn capture(name: &str, spec: Spec, chunk_frames: usize, targets: &[String]) -> anyhow::Result<JackClient<Capture, RingBufferReader>> {
+    anyhow::ensure!(spec.format == Format::S16le, "JACK capture delivers S16LE words, not {:?}", spec.format);
+    let client = client(name, spec.rate)?;
+    let ports = (1..=spec.channels)
+        .map(|c| client.register_port(&format!("in_{c}"), AudioIn).map_err(|e| anyhow!("registering in_{c}: {e}")))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let names = ports.iter().map(|p| p.name()).collect::<Result<Vec<_>, _>>().map_err(|e| anyhow!("{e}"))?;
+    let (reader, writer, poll) = ring(spec, spec.channels as usize * 2, chunk_frames)?;
+    let (shutdown, xruns) = (Arc::new(AtomicBool::new(false)), Arc::new(AtomicUsize::new(0)));
+    let handler = Capture { ports, ring: writer, scratch: vec![0u8; MAX_PERIOD_FRAMES * spec.channels as usize * 2], overruns: xruns.clone() };
+    let client = client.activate_async(Shutdown(shutdown.clone()), handler).map_err(|e| anyhow!("activating JACK client: {e}"))?;
+    connect(client.as_client(), &names, targets, true)?;
+    Ok(JackClient { _client: client, ring: reader, shutdown, xruns, reported: 0, poll })
+}
+
+/// Client `name` with one output port per channel, named after the decoders' layout
+pub(crate) fn playback(name: &str, spec: Spec, chunk_frames: usize, targets: &[String]) -> anyhow::Result<JackClient<Playback, RingBufferWriter>> {
+    let sample_bytes = sample_bytes(spec.format).with_context(|| format!("JACK ports take S16LE, S32LE or F32LE, not {:?}", spec.format))?;
+    let client = client(name, spec.rate)?;
+    let positions = crate::decoders::channel_positions(spec.channels);
+    let ports = (0..spec.channels as usize)
+        .map(|c| {
+            let port = positions.get(c).map(|p| p.to_string()).unwrap_or_else(|| format!("out_{}", c + 1));
+            client.register_port(&port, AudioOut).map_err(|e| anyhow!("registering {port}: {e}"))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let names = ports.iter().map(|p| p.name()).collect::<Result<Vec<_>, _>>().map_err(|e| anyhow!("{e}"))?;
+    let frame_bytes = spec.channels as usize * sample_bytes;
+    let (reader, writer, poll) = ring(spec, frame_bytes, chunk_frames)?;
+    let (shutdown, xruns) = (Arc::new(AtomicBool::new(false)), Arc::new(AtomicUsize::new(0)));
+    let handler = Playback { ports, ring: reader, format: spec.format, scratch: vec![0u8; MAX_PERIOD_FRAMES * frame_bytes], underruns: xruns.clone() };
+    let client = client.activate_async(Shutdown(shutdown.clone()), handler).map_err(|e| anyhow!("activating JACK client: {e}"))?;
+    connect(client.as_client(), &names, targets, false)?;
+    Ok(JackClient { _client: client, ring: writer, shutdown, xruns, reported: 0, poll })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn words_survive_the_float_ports() {
+        for word in [i16::MIN, -12345, -1, 0, 1, 0x4e1f, 0x72f8, i16::MAX] {
+            let sample = to_float(Format::S16le, &word.to_le_bytes());
+            assert_eq!(to_word(sample), word);
+        }
+        assert_eq!(to_float(Format::S32le, &i32::MIN.to_le_bytes()), -1.0);
+        assert_eq!(to_float(Format::F32le, &0.5f32.to_le_bytes()), 0.5);
+        assert_eq!(sample_bytes(Format::S24le), None);
+    }
+}
@@ -0,0 +1,26 @@
+/* pcm-auto-decoder as a library: detect IEC-61937 in a capture and route it to a PCM sink or a decoder */
+#[cfg(feature = "alsa")]
+mod alsa_pcm;
+pub mod ac3;
+pub mod analyzer;
+pub mod decoders;
+pub mod encoder;
+pub mod iec61937_detector;
+pub mod iec61937_framer;
+pub mod iec61937_packer;
+#[cfg(feature = "jack")]
+mod jack_client;
+pub mod outputs;
+pub mod pipeline;
+#[cfg(feature = "pipewire")]
+mod pw_stream;
+pub mod sinks;
+pub mod sources;
+pub mod tee;
+pub mod wav;
+
+pub use decoders::{AudioDecoder, DecoderRegistry};
+pub use iec61937_detector::Iec61937Detector;
+pub use pipeline::{Mode, Pipeline, PipelineBuilder, Switch};
+pub use sinks::AudioSink;
+pub use sources::AudioSource;
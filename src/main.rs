@@ -1,32 +1,24 @@
-use crate::sinks::AudioSink;
-mod iec61937_detector;
-mod sinks;
-mod decoders;
-
 use anyhow::{Context, Result};
 use clap::Parser;
-use libpulse_binding as pulse;
-use libpulse_simple_binding::Simple;
-use pulse::channelmap::Map;
-use pulse::def::BufferAttr;
-use pulse::sample::{Format, Spec};
-use pulse::stream::Direction;
-use std::fs::File;
-use std::io::{Read, Write};
+use libpulse_binding::sample::{Format, Spec};
 use std::path::PathBuf;
-use std::thread::sleep;
-use std::time::{Duration, Instant};
-use libpulse_binding::channelmap::MapDef::ALSA;
-use crate::sinks::{FileSink, PulseAudioSink};
-use iec61937_detector::Iec61937Detector;
-use crate::decoders::{AudioDecoder, FfmpegDecoderSink};
-
-/// IEC-61937 preamble words (big-endian)
-const PA_SYNC: u16 = 0xF872;
-const PB_SYNC: u16 = 0x4E1F;
+use pcm_auto_decoder::analyzer::{self, AnalyzeConfig};
+use pcm_auto_decoder::decoders::{codec_types, Backend, DecoderRegistry, PassthroughTarget};
+use pcm_auto_decoder::encoder::FfmpegAc3EncoderSink;
+use pcm_auto_decoder::iec61937_detector::Iec61937Detector;
+use pcm_auto_decoder::iec61937_packer::{Segment, SignalBuilder};
+use pcm_auto_decoder::pipeline::{word_layout, Pipeline, DEFAULT_DET_WINDOW_CHUNKS, DEFAULT_MIN_CONFIDENCE, HBR_CHANNELS, HBR_RATE};
+use pcm_auto_decoder::outputs::{open_all, OutputDefaults};
+use pcm_auto_decoder::sinks::{AudioSink, PulseAudioSink};
+use pcm_auto_decoder::sources::{AudioSource, EofPolicy, FileSource, PulseAudioSource};
+#[cfg(feature = "alsa")]
+use pcm_auto_decoder::{sinks::AlsaSink, sources::AlsaSource};
+#[cfg(feature = "pipewire")]
+use pcm_auto_decoder::{sinks::PipeWireSink, sources::PipeWireSource};
+#[cfg(feature = "jack")]
+use pcm_auto_decoder::{sinks::JackSink, sources::JackSource};
 
 const DEFAULT_CHUNK_FRAMES: usize = 512;
-const DEFAULT_DET_WINDOW_CHUNKS: usize = 64;
 
 #[derive(Parser, Debug)]
 #[command(
@@ -34,18 +26,45 @@ const DEFAULT_DET_WINDOW_CHUNKS: usize = 64;
     about = "PCM/AC3 autodetector/decoder: stdin FIFO or PulseAudio -> (PCM) -> PulseAudio or FIFO"
 )]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// PulseAudio source name (ignored if --stdin is set)
     #[arg(long)]
     source: Option<String>,
 
-    /// PulseAudio sink name (if neither --fifo-out-* set)
+    /// PulseAudio sink name (for outputs without --*-out URIs)
     #[arg(long)]
     sink: Option<String>,
 
-    /// Read input from this file/FIFO instead of PulseAudio (expects S16LE 2ch @ 48kHz, may be IEC61937)
+    /// Talk to PipeWire natively: --source, --sink and --passthrough-sink name PipeWire nodes (default ones if unset)
+    #[cfg(feature = "pipewire")]
+    #[arg(long)]
+    pipewire: bool,
+
+    /// Run as JACK clients: --source and --sink are comma-separated ports to connect ours to, in order
+    #[cfg(feature = "jack")]
+    #[arg(long)]
+    jack: bool,
+
+    /// Capture from this ALSA device instead of PulseAudio (ignored if --stdin is set)
+    #[cfg(feature = "alsa")]
+    #[arg(long, value_name = "DEVICE")]
+    alsa_source: Option<String>,
+
+    /// Play on this ALSA device instead of PulseAudio (for outputs without --*-out URIs). Both outputs are opened at once, use a dmix/plug device
+    #[cfg(feature = "alsa")]
+    #[arg(long, value_name = "DEVICE")]
+    alsa_sink: Option<String>,
+
+    /// Read input from this file/FIFO instead of PulseAudio (expects --in-format 2ch @ 48kHz, may be IEC61937)
     #[arg(long)]
     stdin: Option<PathBuf>,
 
+    /// What to do when --stdin reaches its end: exit after flushing the decoder, reopen (wait for a new FIFO writer) or loop the file
+    #[arg(long, value_enum, default_value_t = EofPolicy::Reopen)]
+    on_eof: EofPolicy,
+
     /// Input channels, should always be 2 as it's the IEC61937 standard
     #[arg(long, default_value_t = 2)]
     in_channels: u8,
@@ -54,14 +73,23 @@ struct Args {
     #[arg(long, default_value_t = 48_000)]
     in_rate: u32,
 
-    /// Input format, default S16LE
+    /// Input format, default S16LE. S24/S32 containers carry IEC61937 in their top 16 bits
     #[arg(long, default_value = "S16LE")]
     in_format: String,
 
-    /// Write stereo PCM (S16LE 2ch @ 48kHz) here in PCM mode
+    /// High-bit-rate input (TrueHD, DTS-HD MA over HDMI): 8ch @ 192kHz container, overrides --in-channels/--in-rate.
+    /// PCM is played as captured, the PCM output must be 8ch @ 192kHz too
+    #[arg(long)]
+    hbr: bool,
+
+    /// Write stereo PCM (S16LE 2ch @ 48kHz) here in PCM mode, same as --pcm-out file://PATH
     #[arg(long, value_name = "PATH")]
     fifo_out_pcm: Option<PathBuf>,
 
+    /// Record the PCM output to this WAV file instead of playing it, same as --pcm-out wav://PATH
+    #[arg(long, value_name = "PATH")]
+    wav_out_pcm: Option<PathBuf>,
+
     /// Desired channels on the PCM output (when no compressed data is detected), default 2
     #[arg(long, default_value_t = 2)]
     out_pcm_channels: u8,
@@ -74,10 +102,35 @@ struct Args {
     #[arg(long, default_value = "S16LE")]
     out_pcm_format: String,
 
-    /// Write decoded 5.1 PCM (F32LE 6ch @ 48kHz) here in AC-3 mode
+    /// Write decoded 5.1 PCM (F32LE 6ch @ 48kHz) here in AC-3 mode, same as --decoded-out file://PATH
     #[arg(long, value_name = "PATH")]
     fifo_out_decoded: Option<PathBuf>,
 
+    /// Record the decoded output to this WAV file (channel mask of the decoded layout) instead of playing it,
+    /// same as --decoded-out wav://PATH
+    #[arg(long, value_name = "PATH")]
+    wav_out_decoded: Option<PathBuf>,
+
+    /// Start a new WAV file (<name>-2.wav, -3…) each time the mode switches back to a wav:// output
+    #[arg(long)]
+    wav_split: bool,
+
+    /// PCM output as a URI, repeatable to feed several at once: pulse://[SINK], file:///PATH, wav:///PATH,
+    /// alsa://DEVICE, tcp://HOST:PORT or null://. Options after '?', joined by '&': format=, rate=, channels=
+    /// (default --out-pcm-*), map=FL,FR,… (channel order of the output), split (wav), drop=block|newest|oldest
+    /// (what to do when that output falls behind the others, default oldest; block paces every output to it)
+    #[arg(long, value_name = "URI")]
+    pcm_out: Vec<String>,
+
+    /// Decoded output as a URI, repeatable, same forms as --pcm-out (defaults --out-decoded-*)
+    #[arg(long, value_name = "URI")]
+    decoded_out: Vec<String>,
+
+    /// Decoded output of one codec instead of --decoded-out, e.g. truehd=wav:///atmos.wav (repeatable,
+    /// several URIs for a codec are teed). Codecs as for --decoder
+    #[arg(long, value_name = "CODEC=URI", value_parser = parse_codec_out)]
+    codec_out: Vec<(String, String)>,
+
     /// Desired channels on decoded output, default 6
     #[arg(long, default_value_t = 6)]
     out_decoded_channels: u8,
@@ -97,190 +150,265 @@ struct Args {
     /// Chunks without IEC-61937 before switching to PCM (and vice-versa)
     #[arg(long, default_value_t = DEFAULT_DET_WINDOW_CHUNKS)]
     det_window: usize,
+
+    /// Minimum burst confidence (0..1) to switch into IEC-61937 decoding
+    #[arg(long, default_value_t = DEFAULT_MIN_CONFIDENCE)]
+    min_confidence: f32,
+
+    /// Decoder backend for a codec, e.g. ac3=native (repeatable). Codecs: ac3, eac3, dts, dtshd,
+    /// truehd, mpeg, aac. Backends: ffmpeg (default), native (AC-3 only, no ffmpeg needed),
+    /// passthrough (bursts sent undecoded to --passthrough-sink, falls back to ffmpeg if rejected)
+    #[arg(long = "decoder", value_name = "CODEC=BACKEND", value_parser = parse_decoder)]
+    decoders: Vec<(String, Backend)>,
+
+    /// PulseAudio sink receiving the bursts of passthrough codecs (needs an IEC61937-capable output), default --sink
+    #[arg(long)]
+    passthrough_sink: Option<String>,
+
+    /// Encode PCM input to AC-3 at this bitrate (kbps, e.g. 640) and play it as IEC-61937 on the PCM output,
+    /// for S/PDIF-only receivers. The PCM output must be 2ch S16LE
+    #[arg(long, value_name = "KBPS")]
+    encode_ac3: Option<u32>,
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-enum Mode {
-    Unknown,
-    Pcm,
-    Iec61937,
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Write a test signal (2ch S16LE, for --stdin) mixing PCM, IEC-61937 bursts, pauses and corrupted bytes
+    Generate(GenerateArgs),
+    /// Print the timeline of a raw capture (read with --in-format/--in-channels/--hbr) and where
+    /// the mode would switch under --det-window, --chunk-frames, --min-confidence and --decoder
+    Analyze(AnalyzeArgs),
 }
 
+#[derive(clap::Args, Debug)]
+struct AnalyzeArgs {
+    /// Raw capture file
+    file: PathBuf,
+
+    /// Print JSON instead of text
+    #[arg(long)]
+    json: bool,
+}
 
-/* --------------------- Input --------------------- */
+#[derive(clap::Args, Debug)]
+struct GenerateArgs {
+    /// Output file
+    #[arg(short, long)]
+    out: PathBuf,
 
-enum Input {
-    Pa(Simple, Vec<u8>),
-    File(File, Vec<u8>),
+    /// Sample rate, for the duration of the pcm and pause segments
+    #[arg(long, default_value_t = 48000)]
+    rate: u32,
+
+    /// In order: pcm:MS[@HZ] (sine, 1 kHz by default), ac3:FILE, eac3:FILE, dts:FILE (raw elementary
+    /// streams, packed into bursts), pause:MS, corrupt:OFFSET[+COUNT] (flip bytes already written)
+    #[arg(required = true, value_name = "SEGMENT")]
+    segments: Vec<Segment>,
 }
-impl Input {
-    fn open(args: &Args) -> Result<Self> {
-        let frame_bytes = (args.in_channels as u32) * 2;   // S16_LE
-        let frag_bytes  = args.chunk_frames as u32 * frame_bytes;
-
-        let buf = vec![0u8; frag_bytes as usize];
-
-        if let Some(path) = &args.stdin {
-            let f = File::options().read(true).open(path).context("open --stdin")?;
-            Ok(Self::File(f, buf))
-        } else {
-            let source = args
-                .source
-                .as_ref()
-                .map(|s| s.as_str())
-                .context("--source is required when not using --stdin")?;
-            let ss = Spec { format: Format::parse(&args.in_format), rate: args.in_rate, channels: args.in_channels };
-            anyhow::ensure!(ss.is_valid(), "Invalid capture spec");
-            let mut cm = Map::default();
-            cm.init_auto(args.in_channels, ALSA);
-
-            let attr = BufferAttr {
-                maxlength: u32::MAX,       // ok to leave MAX here
-                tlength:   u32::MAX,       // ignored for record
-                prebuf:    u32::MAX,       // ignored for record
-                minreq:    u32::MAX,       // ignored for record
-                fragsize:  frag_bytes,     // THIS matters: when PA wakes your record stream
-            };
-
-            let pa_in = Simple::new(
-                None,
-                "pcm-auto-decoder",
-                Direction::Record,
-                Some(source),
-                "capture",
-                &ss,
-                Some(&cm),
-                Some(&attr),
-            )
-                .context("opening PulseAudio capture")?;
-            Ok(Self::Pa(pa_in, buf))
+
+fn generate(args: &GenerateArgs) -> Result<()> {
+    let mut signal = SignalBuilder::new(args.rate);
+    for segment in &args.segments {
+        signal.segment(segment)?;
+    }
+    let bytes = signal.build();
+    std::fs::write(&args.out, &bytes).with_context(|| format!("writing {}", args.out.display()))?;
+    eprintln!("Wrote {} frames to {}", bytes.len() / 4, args.out.display());
+    Ok(())
+}
+
+fn analyze(args: &Args, analyze_args: &AnalyzeArgs, registry: &DecoderRegistry) -> Result<()> {
+    let bytes = std::fs::read(&analyze_args.file).with_context(|| format!("reading {}", analyze_args.file.display()))?;
+    let (channels, rate) = args.in_layout();
+    let words = Iec61937Detector::extract_words(&bytes, word_layout(Format::parse(&args.in_format))?);
+    let config = AnalyzeConfig {
+        rate,
+        frame_bytes: channels as usize * 2,
+        chunk_frames: args.chunk_frames,
+        det_window: args.det_window,
+        min_confidence: args.min_confidence,
+        registry,
+    };
+    let analysis = analyzer::analyze(&words, &config)?;
+    if analyze_args.json {
+        println!("{}", serde_json::to_string_pretty(&analysis)?);
+    } else {
+        print!("{}", analysis.to_text(&config));
+    }
+    Ok(())
+}
+
+fn parse_decoder(s: &str) -> Result<(String, Backend), String> {
+    let (codec, backend) = s.split_once('=').ok_or("expected CODEC=BACKEND")?;
+    let backend = <Backend as clap::ValueEnum>::from_str(backend, true)?;
+    Ok((codec.to_string(), backend))
+}
+
+fn parse_codec_out(s: &str) -> Result<(String, String), String> {
+    let (codec, uri) = s.split_once('=').ok_or("expected CODEC=URI")?;
+    Ok((codec.to_string(), uri.to_string()))
+}
+
+impl Args {
+    /// Channels and rate of the capture, taking --hbr into account
+    fn in_layout(&self) -> (u8, u32) {
+        match self.hbr {
+            true => (HBR_CHANNELS, HBR_RATE),
+            false => (self.in_channels, self.in_rate),
         }
     }
 
-    fn read_chunk(&mut self) -> Result<&[u8]> {
-        match self {
-            Input::Pa(pa, buf) => {
-                pa.read(buf).context("pa_simple_read")?;
-                Ok(buf.as_slice())
-            }
-            Input::File(f, buf) => {
-                let mut got = 0usize;
-                while got < buf.len() {
-                    let n = f.read(&mut buf[got..])?;
-                    if n == 0 {
-                        // EOF
-                        eprintln!("Input stream lost !");
-                        sleep(Duration::from_millis(500));
-                    }
-                    got += n;
-                }
-                Ok(buf.as_slice())
-            }
+    #[cfg(feature = "pipewire")]
+    fn pipewire(&self) -> bool {
+        self.pipewire
+    }
+    #[cfg(not(feature = "pipewire"))]
+    fn pipewire(&self) -> bool {
+        false
+    }
+
+    /// Ports of a comma-separated --source/--sink, for the JACK clients
+    #[cfg(feature = "jack")]
+    fn jack_ports(list: Option<&str>) -> Vec<String> {
+        list.map(|l| l.split(',').map(|p| p.trim().to_string()).filter(|p| !p.is_empty()).collect()).unwrap_or_default()
+    }
+
+    /// ALSA device with --alsa-sink, JACK client `pcm-auto-decoder-<output>` with --jack,
+    /// PipeWire node with --pipewire, PulseAudio --sink otherwise
+    #[cfg_attr(not(feature = "jack"), allow(unused_variables))]
+    fn playback_sink(&self, output: &str, format: Format, rate: u32, channels: u8) -> Result<Box<dyn AudioSink + Send>> {
+        #[cfg(feature = "alsa")]
+        if let Some(device) = &self.alsa_sink {
+            return Ok(Box::new(AlsaSink::open(device, format, rate, channels, self.chunk_frames)?));
+        }
+        #[cfg(feature = "jack")]
+        if self.jack {
+            let name = format!("pcm-auto-decoder-{output}");
+            return Ok(Box::new(JackSink::open(&name, &Self::jack_ports(self.sink.as_deref()), format, rate, channels, self.chunk_frames)?));
+        }
+        #[cfg(feature = "pipewire")]
+        if self.pipewire {
+            return Ok(Box::new(PipeWireSink::open(self.sink.as_deref(), format, rate, channels, self.chunk_frames)?));
+        }
+        Ok(Box::new(PulseAudioSink::open(self.sink.as_deref(), format, rate, channels, self.chunk_frames)?))
+    }
+
+    fn output_defaults(&self, format: &str, rate: u32, channels: u8) -> OutputDefaults {
+        OutputDefaults {
+            spec: Spec { format: Format::parse(format), rate, channels },
+            chunk_frames: self.chunk_frames,
+            wav_split: self.wav_split,
         }
     }
-}
 
-/* --------------------- Main --------------------- */
+    /// The URIs of an output, with its --fifo-out-*/--wav-out-* shorthands appended
+    fn output_uris(uris: &[String], fifo: &Option<PathBuf>, wav: &Option<PathBuf>) -> Vec<String> {
+        let fifo = fifo.iter().map(|p| format!("file://{}", p.display()));
+        let wav = wav.iter().map(|p| format!("wav://{}", p.display()));
+        uris.iter().cloned().chain(fifo).chain(wav).collect()
+    }
+
+    /// Sink over the output URIs, or `playback_sink` if there are none
+    fn output_sink(&self, output: &str, uris: &[String], defaults: OutputDefaults) -> Result<Box<dyn AudioSink + Send>> {
+        if uris.is_empty() {
+            let Spec { format, rate, channels } = defaults.spec;
+            return self.playback_sink(output, format, rate, channels);
+        }
+        open_all(uris, defaults)
+    }
+
+    /// ALSA device with --alsa-source, JACK client with --jack, PipeWire node with --pipewire,
+    /// PulseAudio --source otherwise
+    fn capture_source(&self, spec: Spec) -> Result<Box<dyn AudioSource + Send>> {
+        #[cfg(feature = "alsa")]
+        if let Some(device) = &self.alsa_source {
+            return Ok(Box::new(AlsaSource::open(device, spec, self.chunk_frames)?));
+        }
+        #[cfg(feature = "jack")]
+        if self.jack {
+            return Ok(Box::new(JackSource::open("pcm-auto-decoder", &Self::jack_ports(self.source.as_deref()), spec, self.chunk_frames)?));
+        }
+        #[cfg(feature = "pipewire")]
+        if self.pipewire {
+            return Ok(Box::new(PipeWireSource::open(self.source.as_deref(), spec, self.chunk_frames)?));
+        }
+        let source = self.source.as_deref().context("--source is required when not using --stdin")?;
+        Ok(Box::new(PulseAudioSource::open(source, spec, self.chunk_frames)?))
+    }
+}
 
 fn main() -> Result<()> {
     let args = Args::parse();
+    if let Some(Command::Generate(generate_args)) = &args.command {
+        return generate(generate_args);
+    }
+
+    let mut registry = DecoderRegistry::new();
+    registry.set_passthrough_target(PassthroughTarget {
+        sink: args.passthrough_sink.clone().or_else(|| args.sink.clone()),
+        rate: args.in_layout().1,
+        pipewire: args.pipewire(),
+    });
+    for (codec, backend) in &args.decoders {
+        registry.select(codec, *backend)?;
+    }
+    if let Some(Command::Analyze(analyze_args)) = &args.command {
+        return analyze(&args, analyze_args, &registry);
+    }
 
-    // Declare sinks:
-    let mut decoder_sink: Option<FfmpegDecoderSink> = None;
+    let pcm_uris = Args::output_uris(&args.pcm_out, &args.fifo_out_pcm, &args.wav_out_pcm);
+    let pcm_sink = args.output_sink("pcm", &pcm_uris, args.output_defaults(&args.out_pcm_format, args.out_pcm_rate, args.out_pcm_channels))?;
 
-    let mut pcm_sink: Option<Box<dyn AudioSink + Send>> = match &args.fifo_out_pcm {
-        Some(p) => Some(Box::new(FileSink::open(p, Format::parse(&args.out_pcm_format), args.out_pcm_rate, args.out_pcm_channels)?)), // RDWR as above
-        None => Some(Box::new(PulseAudioSink::open(args.sink.as_deref(), Format::parse(&args.out_pcm_format), args.out_pcm_rate, args.out_pcm_channels, args.chunk_frames)?)),
+    let (in_channels, in_rate) = args.in_layout();
+    let in_spec = Spec { format: Format::parse(&args.in_format), rate: in_rate, channels: in_channels };
+    let pcm_sink: Box<dyn AudioSink + Send> = match args.encode_ac3 {
+        Some(kbps) => Box::new(FfmpegAc3EncoderSink::wrap(pcm_sink, in_spec, kbps)?),
+        None => pcm_sink,
     };
+    let pcm_spec = pcm_sink.specs();
+    anyhow::ensure!(!args.hbr || (pcm_spec.channels, pcm_spec.rate) == (HBR_CHANNELS, HBR_RATE),
+        "--hbr plays PCM as captured, {HBR_CHANNELS}ch @ {HBR_RATE} Hz: the PCM output is {}ch @ {} Hz, set --out-pcm-channels {HBR_CHANNELS} --out-pcm-rate {HBR_RATE}",
+        pcm_spec.channels, pcm_spec.rate);
+
+    let decoded_defaults = args.output_defaults(&args.out_decoded_format, args.out_decoded_rate, args.out_decoded_channels);
+    let decoded_uris = Args::output_uris(&args.decoded_out, &args.fifo_out_decoded, &args.wav_out_decoded);
+    let decoded_sink = args.output_sink("decoded", &decoded_uris, decoded_defaults)?;
+
+    // Per codec outputs, in the order their codecs first appear
+    let mut codec_outs: Vec<(&str, Vec<String>)> = Vec::new();
+    for (codec, uri) in &args.codec_out {
+        match codec_outs.iter_mut().find(|(c, _)| c == codec) {
+            Some((_, uris)) => uris.push(uri.clone()),
+            None => codec_outs.push((codec, vec![uri.clone()])),
+        }
+    }
+    let mut codec_sinks = Vec::new();
+    for (codec, uris) in codec_outs {
+        codec_sinks.push((codec_types(codec)?, open_all(&uris, decoded_defaults)?));
+    }
 
-    let mut decoded_sink: Option<Box<dyn AudioSink + Send>> = match &args.fifo_out_decoded {
-        Some(p) => Some(Box::new(FileSink::open(p, Format::parse(&args.out_decoded_format), args.out_decoded_rate, args.out_decoded_channels)?)),   // RDWR as above
-        None => Some(Box::new(PulseAudioSink::open(args.sink.as_deref(), Format::parse(&args.out_decoded_format), args.out_decoded_rate, args.out_decoded_channels, args.chunk_frames)?)),
+    // Prepare input (FIFO, ALSA or PulseAudio)
+    let source: Box<dyn AudioSource + Send> = match &args.stdin {
+        Some(path) => Box::new(FileSource::open(path, in_spec, args.chunk_frames, args.on_eof)?),
+        None => args.capture_source(in_spec)?,
     };
 
-    // Prepare input (FIFO or PulseAudio)
-    let mut input = Input::open(&args)?;
-
-    let mut mode = Mode::Unknown;
-    let mut chunks_without_61937 = 0usize;
+    let mut pipeline = Pipeline::builder(source)
+        .pcm_sink(pcm_sink)
+        .decoded_sink(decoded_sink);
+    for (stream_types, sink) in codec_sinks {
+        pipeline = pipeline.codec_sink(stream_types, sink);
+    }
+    let pipeline = pipeline
+        .registry(registry)
+        .det_window(args.det_window)
+        .min_confidence(args.min_confidence)
+        .build()?;
 
     eprintln!(
         "Running… source={:?} stdin={:?} outPCM={:?} out6ch={:?} chunk_frames={} det_window={}",
-        args.source, args.stdin, args.fifo_out_pcm, args.fifo_out_decoded, args.chunk_frames, args.det_window
+        args.source, args.stdin, pcm_uris, decoded_uris, args.chunk_frames, args.det_window
     );
 
-    loop {
-        let chunk = input.read_chunk()?;
-        let has_61937 = Iec61937Detector::find_preamble(chunk);
-
-        match mode {
-            Mode::Unknown => {
-                if has_61937.is_some() {
-                    eprintln!("[INIT] Found IEC-61937 (AC-3). Switching to AC-3 decode.");
-                    mode = Mode::Iec61937;
-                    chunks_without_61937 = 0;
-
-                    // open AC3 sink target
-                    decoder_sink = Some(FfmpegDecoderSink::wrap(decoded_sink.take().context("decoded_sink not set")?)?);
-
-                    if let Some(s) = &mut decoder_sink {
-                        s.write(chunk)?;
-                    }
-                } else {
-                    chunks_without_61937 += 1;
-                    if chunks_without_61937 >= args.det_window {
-                        eprintln!("[INIT] Assuming PCM.");
-                        mode = Mode::Pcm;
-
-                        if let Some(s) = &mut pcm_sink {
-                            s.write(chunk)?;
-                        }
-                    }
-                }
-            }
-            Mode::Pcm => {
-                if has_61937.is_some() {
-                    eprintln!("Detected AC-3; switching PCM -> AC-3 decode.");
-
-                    mode = Mode::Iec61937;
-                    chunks_without_61937 = 0;
-
-                    decoder_sink = Some(FfmpegDecoderSink::wrap(decoded_sink.take().context("decoded_sink not set")?)?);
-
-                    if let Some(s) = &mut decoder_sink {
-                        s.write(chunk)?;
-                    }
-                } else if let Some(s) = &mut pcm_sink {
-                    s.write(chunk)?;
-                }
-            }
-            Mode::Iec61937 => {
-                if has_61937.is_some() {
-                    chunks_without_61937 = 0;
-                    if let Some(s) = &mut decoder_sink {
-                        s.write(chunk)?;
-                    }
-                } else {
-                    chunks_without_61937 += 1;
-                    if chunks_without_61937 >= args.det_window {
-                        eprintln!("Lost IEC-61937; switching to PCM.");
-
-                        if let Some(dec) = decoder_sink.take() {
-                            decoded_sink = Some(dec.finish()?)
-                        }
-
-                        decoder_sink = None;
-                        mode = Mode::Pcm;
-
-                        if let Some(s) = &mut pcm_sink {
-                            s.write(chunk)?;
-                        }
-                    } else if let Some(s) = &mut decoder_sink {
-                        // still push trailing words, helps decoder flush
-                        s.write(chunk)?;
-                    }
-                }
-            }
-        }
-    }
-}
\ No newline at end of file
+    pipeline.run()
+}
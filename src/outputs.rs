@@ -0,0 +1,152 @@
+/* Output URIs: one string per sink, its own spec and channel map, several of them teed */
+// scheme://target?format=F32LE&rate=48000&channels=6&map=FL,FR,FC,LFE,SL,SR&drop=oldest&split
+// Options left out take the defaults of the output (PCM or decoded) the URI is given for.
+use std::path::Path;
+use anyhow::{anyhow, Context};
+use libpulse_binding::sample::{Format, Spec};
+use crate::sinks::{position, AudioSink, FileSink, NullSink, PulseAudioSink, RemapSink, TcpSink};
+use crate::tee::{DropPolicy, TeeSink};
+use crate::wav::WavSink;
+
+/// Schemes `OutputUri::open` knows
+pub const SCHEMES: &[&str] = &["pulse", "file", "wav", "alsa", "tcp", "null"];
+
+/// What an output gets when its URI does not say otherwise
+#[derive(Clone, Copy, Debug)]
+pub struct OutputDefaults {
+    pub spec: Spec,
+    pub chunk_frames: usize,
+    /// New WAV file on each mode switch, for wav:// outputs
+    pub wav_split: bool,
+}
+
+/// A parsed output URI
+#[derive(Clone, Debug)]
+pub struct OutputUri {
+    scheme: String,
+    target: String,
+    format: Option<Format>,
+    rate: Option<u32>,
+    channels: Option<u8>,
+    map: Option<Vec<&'static str>>,
+    split: Option<bool>,
+    pub drop: DropPolicy,
+}
+
+impl OutputUri {
+    /// `pulse://[SINK]`, `file:///PATH`, `wav:///PATH`, `alsa://DEVICE`, `tcp://HOST:PORT` or `null://`,
+    /// with options `format`, `rate`, `channels`, `map`, `drop` and `split`
+    pub fn parse(uri: &str) -> anyhow::Result<Self> {
+        let (scheme, rest) = uri.split_once("://").with_context(|| format!("{uri}: expected scheme://target"))?;
+        anyhow::ensure!(SCHEMES.contains(&scheme), "{uri}: unknown output scheme {scheme}, expected one of {}", SCHEMES.join(", "));
+        let (target, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let mut out = Self {
+            scheme: scheme.to_string(),
+            target: target.to_string(),
+            format: None,
+            rate: None,
+            channels: None,
+            map: None,
+            split: None,
+            drop: DropPolicy::default(),
+        };
+        for (key, value) in query.split('&').filter(|q| !q.is_empty()).map(|q| q.split_once('=').unwrap_or((q, ""))) {
+            let bad = |e: &dyn std::fmt::Display| anyhow!("{uri}: bad {key}={value}: {e}");
+            match key {
+                "format" => {
+                    let format = Format::parse(value);
+                    anyhow::ensure!(format != Format::Invalid, bad(&"unknown sample format"));
+                    out.format = Some(format);
+                }
+                "rate" => out.rate = Some(value.parse().map_err(|e| bad(&e))?),
+                "channels" => out.channels = Some(value.parse().map_err(|e| bad(&e))?),
+                "map" => out.map = Some(value.split(',').map(position).collect::<anyhow::Result<_>>().map_err(|e| bad(&e))?),
+                "drop" => out.drop = <DropPolicy as clap::ValueEnum>::from_str(value, true).map_err(|e| bad(&e))?,
+                "split" => out.split = Some(matches!(value, "" | "1" | "true" | "yes")),
+                _ => anyhow::bail!("{uri}: unknown option {key}"),
+            }
+        }
+        if let (Some(map), Some(channels)) = (&out.map, out.channels) {
+            anyhow::ensure!(map.len() == channels as usize, "{uri}: map has {} positions for {channels} channels", map.len());
+        }
+        anyhow::ensure!(out.scheme != "wav" || out.map.is_none(), "{uri}: WAV files store channels in mask order, map= is not supported");
+        Ok(out)
+    }
+
+    /// Spec of the sink, the defaults overridden by the URI options
+    pub fn spec(&self, defaults: Spec) -> Spec {
+        Spec {
+            format: self.format.unwrap_or(defaults.format),
+            rate: self.rate.unwrap_or(defaults.rate),
+            channels: self.map.as_ref().map(|m| m.len() as u8).or(self.channels).unwrap_or(defaults.channels),
+        }
+    }
+
+    pub fn open(&self, defaults: OutputDefaults) -> anyhow::Result<Box<dyn AudioSink + Send>> {
+        let Spec { format, rate, channels } = self.spec(defaults.spec);
+        let target = self.target.as_str();
+        let sink: Box<dyn AudioSink + Send> = match self.scheme.as_str() {
+            "pulse" => {
+                let name = Some(target).filter(|t| !t.is_empty());
+                match &self.map {
+                    // the stream is labelled with the map, PulseAudio routes each channel by it
+                    Some(map) => Box::new(PulseAudioSink::open_mapped(name, format, rate, map, defaults.chunk_frames)?),
+                    None => Box::new(PulseAudioSink::open(name, format, rate, channels, defaults.chunk_frames)?),
+                }
+            }
+            "file" => Box::new(FileSink::open(&target.into(), format, rate, channels)?),
+            "wav" => Box::new(WavSink::create(Path::new(target), format, rate, channels, self.split.unwrap_or(defaults.wav_split))?),
+            #[cfg(feature = "alsa")]
+            "alsa" => Box::new(crate::sinks::AlsaSink::open(target, format, rate, channels, defaults.chunk_frames)?),
+            #[cfg(not(feature = "alsa"))]
+            "alsa" => anyhow::bail!("built without the alsa feature"),
+            "tcp" => Box::new(TcpSink::connect(target, format, rate, channels)?),
+            _ => Box::new(NullSink::new(format, rate, channels)),
+        };
+        match &self.map {
+            Some(map) => Ok(Box::new(RemapSink::wrap(sink, map)?)),
+            None => Ok(sink),
+        }
+    }
+}
+
+/// The sink for a list of output URIs: the only one, or a tee over all of them
+pub fn open_all(uris: &[String], defaults: OutputDefaults) -> anyhow::Result<Box<dyn AudioSink + Send>> {
+    let mut children = uris.iter()
+        .map(|uri| {
+            let output = OutputUri::parse(uri)?;
+            let sink = output.open(defaults).with_context(|| format!("opening {uri}"))?;
+            Ok((uri.clone(), sink, output.drop))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    anyhow::ensure!(!children.is_empty(), "no output URI");
+    if children.len() == 1 {
+        let (_, sink, _) = children.remove(0);
+        return Ok(sink);
+    }
+    Ok(Box::new(TeeSink::new(children)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uri_options_override_the_defaults() {
+        let defaults = Spec { format: Format::S16le, rate: 48_000, channels: 2 };
+        let out = OutputUri::parse("tcp://monitor:4000?rate=44100&map=fl,fr,sl,sr&drop=block").unwrap();
+        assert_eq!((out.scheme.as_str(), out.target.as_str(), out.drop), ("tcp", "monitor:4000", DropPolicy::Block));
+        let spec = out.spec(defaults);
+        assert_eq!((spec.rate, spec.channels), (44_100, 4));
+        assert_eq!(out.map.as_deref(), Some(&["FL", "FR", "SL", "SR"][..]));
+
+        let out = OutputUri::parse("file:///tmp/x.raw").unwrap();
+        assert_eq!((out.target.as_str(), out.spec(defaults).channels), ("/tmp/x.raw", 2));
+        assert_eq!(out.drop, DropPolicy::Oldest);
+        assert!(OutputUri::parse("null://").is_ok());
+
+        for bad in ["/tmp/x.raw", "smb://share", "null://?rate=fast", "null://?map=FL,XX", "null://?channels=2&map=FL", "wav:///x.wav?map=FR,FL", "pulse://?volume=3"] {
+            assert!(OutputUri::parse(bad).is_err(), "{bad}");
+        }
+    }
+}
@@ -0,0 +1,397 @@
+/* Capture -> IEC-61937 detection -> PCM sink or decoder -> decoded sink, as a reusable pipeline */
+use std::collections::HashMap;
+use anyhow::{Context, Result};
+use libpulse_binding::sample::Format;
+use crate::decoders::{AudioDecoder, DecoderRegistry};
+use crate::iec61937_detector::{Endianness, Iec61937Detector, StreamType, WordLayout};
+use crate::iec61937_framer::{Iec61937Burst, Iec61937Framer};
+use crate::sinks::AudioSink;
+use crate::sources::AudioSource;
+
+pub const DEFAULT_DET_WINDOW_CHUNKS: usize = 64;
+pub const DEFAULT_MIN_CONFIDENCE: f32 = 0.75;
+
+// HBR container: the IEC-61937 words are spread over 8 channels at 192 kHz. Each
+// 8ch S16LE frame holds 4 consecutive word pairs, so the interleaved frames read
+// back to back are the IEC-61937 stream itself.
+pub const HBR_CHANNELS: u8 = 8;
+pub const HBR_RATE: u32 = 192_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Unknown,
+    Pcm,
+    Iec61937,
+}
+
+/// A mode change made by the pipeline
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Switch {
+    /// Bursts of this type go to its decoder
+    Decode(StreamType),
+    /// The input goes to the PCM sink
+    Pcm,
+    /// No decoder for this type, the input is muted
+    Muted(StreamType),
+}
+
+/// Where the IEC-61937 words sit in samples of the given capture format
+pub fn word_layout(format: Format) -> Result<WordLayout> {
+    use Endianness::*;
+    let (sample_bytes, word_offset, endianness) = match format {
+        Format::S16le => (2, 0, Little),
+        Format::S16be => (2, 0, Big),
+        Format::S24le => (3, 1, Little),
+        Format::S24be => (3, 0, Big),
+        Format::S24_32le => (4, 1, Little), // 24 bits in the low bytes
+        Format::S24_32be => (4, 1, Big),
+        Format::S32le => (4, 2, Little),
+        Format::S32be => (4, 0, Big),
+        other => anyhow::bail!("capture format {other:?} cannot carry IEC-61937"),
+    };
+    Ok(WordLayout { sample_bytes, word_offset, endianness })
+}
+
+/* --------------------- Pipeline --------------------- */
+
+/// Duration of a burst on the input, in frames of a sink running at `out_rate`
+fn burst_frames(burst: &Iec61937Burst, in_frame_bytes: usize, in_rate: u32, out_rate: u32) -> usize {
+    (burst.bytes.len() / in_frame_bytes) * out_rate as usize / in_rate as usize
+}
+
+/// Send the bursts of one chunk to the decoder (see `AudioDecoder::write_burst`),
+/// byte-swapped bursts normalised to little-endian words.
+fn write_bursts(decoder: &mut dyn AudioDecoder, bursts: &[Iec61937Burst], in_frame_bytes: usize, in_rate: u32) -> Result<()> {
+    for burst in bursts {
+        let frames = burst_frames(burst, in_frame_bytes, in_rate, decoder.specs().rate);
+        decoder.write_burst(burst.preamble.stream_type, &burst.le_bytes(), frames)?;
+    }
+    Ok(())
+}
+
+/// Reads the source chunk by chunk and routes it: PCM as-is to the PCM sink, IEC-61937
+/// bursts through the decoder registered for their type into the decoded sink.
+pub struct Pipeline {
+    source: Box<dyn AudioSource + Send>,
+    switcher: Switcher,
+}
+
+impl Pipeline {
+    pub fn builder(source: Box<dyn AudioSource + Send>) -> PipelineBuilder {
+        PipelineBuilder {
+            source,
+            pcm_sink: None,
+            decoded_sink: None,
+            codec_sinks: Vec::new(),
+            registry: None,
+            det_window: DEFAULT_DET_WINDOW_CHUNKS,
+            min_confidence: DEFAULT_MIN_CONFIDENCE,
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.switcher.mode
+    }
+
+    /// The mode change made by the last `step`, if any
+    pub fn last_switch(&self) -> Option<Switch> {
+        self.switcher.switched
+    }
+
+    /// Read and route one chunk, false once the source has ended
+    pub fn step(&mut self) -> Result<bool> {
+        match self.source.read_chunk()? {
+            Some(chunk) => self.switcher.process(chunk).map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Run until the source ends (the decoder is then drained) or something fails
+    pub fn run(mut self) -> Result<()> {
+        while self.step()? {}
+        eprintln!("Input ended.");
+        self.switcher.finish()?;
+        // what the PCM sink still holds (the AC-3 encoder's last frames)
+        match &mut self.switcher.pcm_sink {
+            Some(s) => s.deactivate(),
+            None => Ok(()),
+        }
+    }
+}
+
+pub struct PipelineBuilder {
+    source: Box<dyn AudioSource + Send>,
+    pcm_sink: Option<Box<dyn AudioSink + Send>>,
+    decoded_sink: Option<Box<dyn AudioSink + Send>>,
+    codec_sinks: Vec<(Vec<StreamType>, Box<dyn AudioSink + Send>)>,
+    registry: Option<DecoderRegistry>,
+    det_window: usize,
+    min_confidence: f32,
+}
+
+impl PipelineBuilder {
+    /// Receives the input untouched while it is PCM
+    pub fn pcm_sink(mut self, sink: Box<dyn AudioSink + Send>) -> Self {
+        self.pcm_sink = Some(sink);
+        self
+    }
+
+    /// Wrapped by the decoder while the input is IEC-61937
+    pub fn decoded_sink(mut self, sink: Box<dyn AudioSink + Send>) -> Self {
+        self.decoded_sink = Some(sink);
+        self
+    }
+
+    /// Wrapped by the decoder instead of the decoded sink while the input is one of `stream_types`
+    pub fn codec_sink(mut self, stream_types: &[StreamType], sink: Box<dyn AudioSink + Send>) -> Self {
+        self.codec_sinks.push((stream_types.to_vec(), sink));
+        self
+    }
+
+    /// Decoders per stream type, `DecoderRegistry::new()` by default
+    pub fn registry(mut self, registry: DecoderRegistry) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    /// Chunks without IEC-61937 before switching to PCM (and vice-versa)
+    pub fn det_window(mut self, chunks: usize) -> Self {
+        self.det_window = chunks;
+        self
+    }
+
+    /// Minimum burst confidence (0..1) to switch into IEC-61937 decoding
+    pub fn min_confidence(mut self, confidence: f32) -> Self {
+        self.min_confidence = confidence;
+        self
+    }
+
+    pub fn build(self) -> Result<Pipeline> {
+        let spec = self.source.specs();
+        let mut decoded_sinks = vec![Some(self.decoded_sink.context("pipeline without decoded sink")?)];
+        let mut codec_slots = HashMap::new();
+        for (stream_types, sink) in self.codec_sinks {
+            codec_slots.extend(stream_types.into_iter().map(|t| (t, decoded_sinks.len())));
+            decoded_sinks.push(Some(sink));
+        }
+        let switcher = Switcher {
+            layout: word_layout(spec.format)?,
+            in_frame_bytes: spec.channels as usize * 2, // frame of 16-bit words, after extract_words
+            in_rate: spec.rate,
+            registry: self.registry.unwrap_or_default(),
+            pcm_sink: Some(self.pcm_sink.context("pipeline without PCM sink")?),
+            decoded_sinks,
+            codec_slots,
+            decoder_slot: 0,
+            decoder_sink: None,
+            decoder_type: None,
+            det_window: self.det_window,
+            min_confidence: self.min_confidence,
+            framer: Iec61937Framer::new(),
+            mode: Mode::Unknown,
+            chunks_without_61937: 0,
+            rejected: None,
+            switched: None,
+        };
+        Ok(Pipeline { source: self.source, switcher })
+    }
+}
+
+/// The mode state machine and the sinks, everything but the source
+struct Switcher {
+    layout: WordLayout,
+    in_frame_bytes: usize,
+    in_rate: u32,
+    registry: DecoderRegistry,
+    pcm_sink: Option<Box<dyn AudioSink + Send>>,
+    decoded_sinks: Vec<Option<Box<dyn AudioSink + Send>>>, // the decoded sink then the codec ones, None while wrapped by decoder_sink
+    codec_slots: HashMap<StreamType, usize>, // index in decoded_sinks, 0 if not listed
+    decoder_slot: usize, // where decoder_sink's sink goes back
+    decoder_sink: Option<Box<dyn AudioDecoder + Send>>,
+    decoder_type: Option<StreamType>, // what decoder_sink decodes
+    det_window: usize,
+    min_confidence: f32,
+    framer: Iec61937Framer,
+    mode: Mode,
+    chunks_without_61937: usize,
+    rejected: Option<StreamType>,
+    switched: Option<Switch>, // made by the chunk being processed
+}
+
+impl Switcher {
+    /// Hand the last burst to the decoder and let it flush its output
+    fn finish(&mut self) -> Result<()> {
+        if let Some(dec) = &mut self.decoder_sink
+            && let Some(burst) = self.framer.flush()
+        {
+            write_bursts(dec.as_mut(), &[burst], self.in_frame_bytes, self.in_rate)?;
+        }
+        self.close_decoder()
+    }
+
+    /// Let the decoder flush its output and put its sink back in its slot
+    fn close_decoder(&mut self) -> Result<()> {
+        if let Some(dec) = self.decoder_sink.take() {
+            self.decoded_sinks[self.decoder_slot] = Some(dec.finish()?);
+            self.decoder_type = None;
+        }
+        Ok(())
+    }
+
+    /// Wrap the sink of `stream_type` in its decoder and feed it the first bursts
+    fn open_decoder(&mut self, stream_type: StreamType, bursts: &[Iec61937Burst]) -> Result<()> {
+        self.decoder_slot = self.codec_slots.get(&stream_type).copied().unwrap_or(0);
+        let sink = self.decoded_sinks[self.decoder_slot].take().context("decoded_sink not set")?;
+        let mut dec = self.registry.open(sink, stream_type)?;
+        write_bursts(dec.as_mut(), bursts, self.in_frame_bytes, self.in_rate)?;
+        self.decoder_sink = Some(dec);
+        self.decoder_type = Some(stream_type);
+        Ok(())
+    }
+
+    fn process(&mut self, chunk: &[u8]) -> Result<()> {
+        let (in_frame_bytes, in_rate) = (self.in_frame_bytes, self.in_rate);
+        self.switched = None;
+        let mut bursts = self.framer.push(&Iec61937Detector::extract_words(chunk, self.layout));
+        if self.mode != Mode::Iec61937 {
+            // a lone sync pattern in loud PCM must not switch us to decoding
+            bursts.retain(|b| b.confidence() >= self.min_confidence);
+        }
+        let has_61937 = !bursts.is_empty();
+        let audio_type = bursts.iter().map(|b| b.preamble.stream_type).find(StreamType::carries_audio);
+
+        // Compressed data we cannot decode is muted instead of being played as PCM noise
+        match audio_type {
+            Some(stream_type) if self.registry.backend(stream_type).is_none() => {
+                if self.rejected != Some(stream_type) {
+                    eprintln!("Rejecting {stream_type}: no decoder for it, muting.");
+                    self.rejected = Some(stream_type);
+                    self.switched = Some(Switch::Muted(stream_type));
+                }
+                if self.decoder_sink.is_some() {
+                    self.close_decoder()?;
+                    if let Some(s) = &mut self.decoded_sinks[self.decoder_slot] {
+                        s.deactivate()?;
+                    }
+                    self.framer.flush();
+                    self.mode = Mode::Unknown;
+                }
+                self.chunks_without_61937 = 0;
+                return Ok(());
+            }
+            Some(_) => self.rejected = None,
+            None => {}
+        }
+        // ... between its bursts too, until det_window chunks without any preamble
+        if let Some(stream_type) = self.rejected {
+            if has_61937 || self.framer.is_locked() {
+                self.chunks_without_61937 = 0;
+                return Ok(());
+            }
+            self.chunks_without_61937 += 1;
+            if self.chunks_without_61937 < self.det_window {
+                return Ok(());
+            }
+            eprintln!("{stream_type} ended; switching to PCM.");
+            self.rejected = None;
+            self.switched = Some(Switch::Pcm);
+            self.mode = Mode::Pcm;
+        }
+
+        // Only PAUSE/NULL bursts and no decoder yet: keep time on the decoded sink, mute PCM
+        if has_61937 && audio_type.is_none() && self.decoder_sink.is_none() {
+            if let Some(s) = &mut self.decoded_sinks[0] {
+                for burst in bursts.iter().filter(|b| b.preamble.stream_type == StreamType::Pause) {
+                    let spec = s.specs();
+                    let frames = burst_frames(burst, in_frame_bytes, in_rate, spec.rate);
+                    s.write(&vec![0u8; frames * spec.frame_size()])?;
+                }
+            }
+            return Ok(());
+        }
+
+        match self.mode {
+            Mode::Unknown => {
+                if has_61937 {
+                    let stream_type = audio_type.context("no audio burst")?;
+                    let backend = self.registry.backend(stream_type).context("no decoder for stream")?;
+                    eprintln!("[INIT] Found IEC-61937 ({stream_type}). Switching to {stream_type} decode ({backend}).");
+                    self.switched = Some(Switch::Decode(stream_type));
+                    self.mode = Mode::Iec61937;
+                    self.chunks_without_61937 = 0;
+                    self.open_decoder(stream_type, &bursts)?;
+                } else {
+                    self.chunks_without_61937 += 1;
+                    if self.chunks_without_61937 >= self.det_window {
+                        eprintln!("[INIT] Assuming PCM.");
+                        self.switched = Some(Switch::Pcm);
+                        self.mode = Mode::Pcm;
+
+                        if let Some(s) = &mut self.pcm_sink {
+                            s.write(chunk)?;
+                        }
+                    }
+                }
+            }
+            Mode::Pcm => {
+                if has_61937 {
+                    let stream_type = audio_type.context("no audio burst")?;
+                    let backend = self.registry.backend(stream_type).context("no decoder for stream")?;
+                    eprintln!("Detected {stream_type}; switching PCM -> {stream_type} decode ({backend}).");
+                    self.switched = Some(Switch::Decode(stream_type));
+                    if let Some(s) = &mut self.pcm_sink {
+                        s.deactivate()?;
+                    }
+
+                    self.mode = Mode::Iec61937;
+                    self.chunks_without_61937 = 0;
+                    self.open_decoder(stream_type, &bursts)?;
+                } else if let Some(s) = &mut self.pcm_sink {
+                    s.write(chunk)?;
+                }
+            }
+            Mode::Iec61937 => {
+                if has_61937 {
+                    self.chunks_without_61937 = 0;
+                    // bursts up to the first one of another codec belong to the open decoder
+                    let other = bursts.iter()
+                        .position(|b| b.preamble.stream_type.carries_audio() && Some(b.preamble.stream_type) != self.decoder_type);
+                    let (current, next) = bursts.split_at(other.unwrap_or(bursts.len()));
+                    if let Some(s) = &mut self.decoder_sink {
+                        write_bursts(s.as_mut(), current, in_frame_bytes, in_rate)?;
+                    }
+                    // without a decoder the new codec is muted from its next chunk on
+                    if let Some(stream_type) = next.first().map(|b| b.preamble.stream_type)
+                        && let Some(backend) = self.registry.backend(stream_type)
+                    {
+                        let previous = self.decoder_type.map_or("IEC-61937".to_string(), |t| t.to_string());
+                        eprintln!("{previous} -> {stream_type}; switching to {stream_type} decode ({backend}).");
+                        self.switched = Some(Switch::Decode(stream_type));
+                        self.close_decoder()?;
+                        if let Some(s) = &mut self.decoded_sinks[self.decoder_slot] {
+                            s.deactivate()?;
+                        }
+                        self.open_decoder(stream_type, next)?;
+                    }
+                } else {
+                    self.chunks_without_61937 += 1;
+                    if self.chunks_without_61937 >= self.det_window {
+                        eprintln!("Lost IEC-61937; switching to PCM.");
+                        self.switched = Some(Switch::Pcm);
+
+                        self.finish()?;
+                        if let Some(s) = &mut self.decoded_sinks[self.decoder_slot] {
+                            s.deactivate()?;
+                        }
+                        self.mode = Mode::Pcm;
+
+                        if let Some(s) = &mut self.pcm_sink {
+                            s.write(chunk)?;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
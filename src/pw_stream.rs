@@ -0,0 +1,310 @@
+/* PipeWire stream on its own thread, shared by PipeWireSource and PipeWireSink: node properties, raw or IEC958 formats */
+// The PipeWire objects are neither Send nor blocking, so the main loop runs on a thread
+// and the samples go through a bounded channel, like the PulseAudio passthrough sink.
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread;
+use std::time::Duration;
+use anyhow::{anyhow, Context};
+use libpulse_binding::sample::{Format, Spec};
+use pipewire as pw;
+use pw::context::Context as PwContext;
+use pw::main_loop::MainLoop;
+use pw::properties::{properties, Properties};
+use pw::spa::param::audio::{AudioFormat, AudioInfoRaw};
+use pw::spa::param::ParamType;
+use pw::spa::pod::serialize::PodSerializer;
+use pw::spa::pod::{Object, Pod, Property, Value};
+use pw::spa::sys as spa_sys;
+use pw::spa::utils::{Direction, Id};
+use pw::stream::{Stream, StreamFlags, StreamRef, StreamState};
+use crate::decoders::channel_positions as positions;
+use crate::iec61937_detector::StreamType;
+
+/// Chunks queued between the pipeline and the PipeWire thread
+const QUEUE_CHUNKS: usize = 8;
+/// How long the session manager gets to link the stream and settle the format
+const LINK_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// What the stream carries
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum StreamFormat {
+    Raw(Spec),
+    /// IEC61937 bursts, seen by the graph as 2ch S16LE frames at `rate`
+    Iec958 { stream_type: StreamType, rate: u32 },
+}
+
+impl StreamFormat {
+    pub(crate) fn spec(&self) -> Spec {
+        match *self {
+            StreamFormat::Raw(spec) => spec,
+            StreamFormat::Iec958 { rate, .. } => Spec { format: Format::S16le, rate, channels: 2 },
+        }
+    }
+}
+
+/// The SPA codec for `stream_type` on an IEC958 node
+pub(crate) fn iec958_codec(stream_type: StreamType) -> Option<u32> {
+    use StreamType::*;
+    Some(match stream_type {
+        Ac3 => spa_sys::SPA_AUDIO_IEC958_CODEC_AC3,
+        EAc3 => spa_sys::SPA_AUDIO_IEC958_CODEC_EAC3,
+        DtsType1 | DtsType2 | DtsType3 => spa_sys::SPA_AUDIO_IEC958_CODEC_DTS,
+        DtsType4 => spa_sys::SPA_AUDIO_IEC958_CODEC_DTSHD,
+        TrueHd => spa_sys::SPA_AUDIO_IEC958_CODEC_TRUEHD,
+        Mpeg1Layer1 | Mpeg1Layer23 | Mpeg2Ext | Mpeg2Layer1Lsf | Mpeg2Layer23Lsf => spa_sys::SPA_AUDIO_IEC958_CODEC_MPEG,
+        Mpeg2Aac | Mpeg2AacLsf => spa_sys::SPA_AUDIO_IEC958_CODEC_MPEG2_AAC,
+        _ => return None,
+    })
+}
+
+fn audio_format(format: Format) -> Option<AudioFormat> {
+    Some(match format {
+        Format::U8 => AudioFormat::U8,
+        Format::S16le => AudioFormat::S16LE,
+        Format::S16be => AudioFormat::S16BE,
+        Format::S24le => AudioFormat::S24LE,
+        Format::S24be => AudioFormat::S24BE,
+        Format::S24_32le => AudioFormat::S24_32LE,
+        Format::S24_32be => AudioFormat::S24_32BE,
+        Format::S32le => AudioFormat::S32LE,
+        Format::S32be => AudioFormat::S32BE,
+        Format::F32le => AudioFormat::F32LE,
+        Format::F32be => AudioFormat::F32BE,
+        _ => return None,
+    })
+}
+
+fn channel_id(position: &str) -> u32 {
+    match position {
+        "MONO" => spa_sys::SPA_AUDIO_CHANNEL_MONO,
+        "FL" => spa_sys::SPA_AUDIO_CHANNEL_FL,
+        "FR" => spa_sys::SPA_AUDIO_CHANNEL_FR,
+        "FC" => spa_sys::SPA_AUDIO_CHANNEL_FC,
+        "LFE" => spa_sys::SPA_AUDIO_CHANNEL_LFE,
+        "SL" => spa_sys::SPA_AUDIO_CHANNEL_SL,
+        "SR" => spa_sys::SPA_AUDIO_CHANNEL_SR,
+        "RL" => spa_sys::SPA_AUDIO_CHANNEL_RL,
+        "RR" => spa_sys::SPA_AUDIO_CHANNEL_RR,
+        "RC" => spa_sys::SPA_AUDIO_CHANNEL_RC,
+        _ => spa_sys::SPA_AUDIO_CHANNEL_UNKNOWN,
+    }
+}
+
+/// Node properties: a stream node of the right class, at the rate and quantum of the pipeline
+fn properties(direction: Direction, format: StreamFormat, chunk_frames: usize, target: Option<&str>) -> Properties {
+    let spec = format.spec();
+    let (class, category, name) = match direction == Direction::Input {
+        true => ("Stream/Input/Audio", "Capture", "pcm-auto-decoder.capture"),
+        false => ("Stream/Output/Audio", "Playback", "pcm-auto-decoder.playback"),
+    };
+    let mut props = properties! {
+        *pw::keys::APP_NAME => "pcm-auto-decoder",
+        *pw::keys::NODE_NAME => name,
+        *pw::keys::MEDIA_TYPE => "Audio",
+        *pw::keys::MEDIA_CATEGORY => category,
+        *pw::keys::MEDIA_CLASS => class,
+        *pw::keys::NODE_LATENCY => format!("{chunk_frames}/{}", spec.rate),
+        // run the graph at our rate rather than resampling in front of the detector
+        *pw::keys::NODE_RATE => format!("1/{}", spec.rate),
+    };
+    match format {
+        StreamFormat::Raw(spec) => {
+            props.insert(*pw::keys::NODE_DESCRIPTION, format!("pcm-auto-decoder {} ({}ch)", category.to_lowercase(), spec.channels));
+            props.insert(*pw::keys::AUDIO_CHANNELS, spec.channels.to_string());
+            props.insert("audio.position", positions(spec.channels).join(","));
+            props.insert(*pw::keys::STREAM_DONT_REMIX, "true");
+        }
+        StreamFormat::Iec958 { stream_type, .. } => {
+            props.insert(*pw::keys::NODE_DESCRIPTION, format!("pcm-auto-decoder {stream_type} passthrough"));
+        }
+    }
+    if let Some(target) = target {
+        props.insert(*pw::keys::TARGET_OBJECT, target);
+    }
+    props
+}
+
+/// The EnumFormat pod offered when connecting
+fn format_pod(format: StreamFormat) -> anyhow::Result<Vec<u8>> {
+    let properties = match format {
+        StreamFormat::Raw(spec) => {
+            let mut info = AudioInfoRaw::new();
+            info.set_format(audio_format(spec.format).with_context(|| format!("{:?} has no PipeWire equivalent", spec.format))?);
+            info.set_rate(spec.rate);
+            info.set_channels(spec.channels as u32);
+            let mut position = [0u32; 64];
+            for (slot, name) in position.iter_mut().zip(positions(spec.channels)) {
+                *slot = channel_id(name);
+            }
+            info.set_position(position);
+            info.into()
+        }
+        StreamFormat::Iec958 { stream_type, rate } => vec![
+            Property::new(spa_sys::SPA_FORMAT_mediaType, Value::Id(Id(spa_sys::SPA_MEDIA_TYPE_audio))),
+            Property::new(spa_sys::SPA_FORMAT_mediaSubtype, Value::Id(Id(spa_sys::SPA_MEDIA_SUBTYPE_iec958))),
+            Property::new(spa_sys::SPA_FORMAT_AUDIO_iec958Codec, Value::Id(Id(iec958_codec(stream_type).with_context(|| format!("no IEC958 codec for {stream_type}"))?))),
+            Property::new(spa_sys::SPA_FORMAT_AUDIO_rate, Value::Int(rate as i32)),
+        ],
+    };
+    let object = Value::Object(Object { type_: spa_sys::SPA_TYPE_OBJECT_Format, id: spa_sys::SPA_PARAM_EnumFormat, properties });
+    let (cursor, _) = PodSerializer::serialize(std::io::Cursor::new(Vec::new()), &object)
+        .map_err(|e| anyhow!("serializing the stream format: {e:?}"))?;
+    Ok(cursor.into_inner())
+}
+
+/// Our end of the samples, driven by the process callback
+enum Io {
+    Playback { rx: Receiver<Vec<u8>>, pending: Vec<u8>, pos: usize, quantum_bytes: usize },
+    Capture { tx: SyncSender<Vec<u8>> },
+}
+
+impl Io {
+    fn process(&mut self, stream: &StreamRef, frame_bytes: usize) {
+        let Some(mut buffer) = stream.dequeue_buffer() else { return };
+        let Some(data) = buffer.datas_mut().first_mut() else { return };
+        match self {
+            Io::Playback { rx, pending, pos, quantum_bytes } => {
+                let Some(out) = data.data() else { return };
+                let size = (*quantum_bytes).min(out.len() - out.len() % frame_bytes);
+                let mut filled = 0;
+                while filled < size {
+                    if *pos == pending.len() {
+                        match rx.try_recv() {
+                            Ok(bytes) => (*pending, *pos) = (bytes, 0),
+                            Err(_) => break,
+                        }
+                        continue;
+                    }
+                    let n = (pending.len() - *pos).min(size - filled);
+                    out[filled..filled + n].copy_from_slice(&pending[*pos..*pos + n]);
+                    (filled, *pos) = (filled + n, *pos + n);
+                }
+                out[filled..size].fill(0); // underrun
+                let chunk = data.chunk_mut();
+                *chunk.offset_mut() = 0;
+                *chunk.stride_mut() = frame_bytes as i32;
+                *chunk.size_mut() = size as u32;
+            }
+            Io::Capture { tx } => {
+                let (offset, size) = (data.chunk().offset() as usize, data.chunk().size() as usize);
+                let Some(bytes) = data.data() else { return };
+                let end = (offset + size).min(bytes.len());
+                if offset >= end {
+                    return;
+                }
+                if let Err(TrySendError::Full(lost)) = tx.try_send(bytes[offset..end].to_vec()) {
+                    eprintln!("PipeWire capture overrun, dropped {} frames", lost.len() / frame_bytes);
+                }
+            }
+        }
+    }
+}
+
+struct StreamData {
+    ready: Option<SyncSender<anyhow::Result<()>>>,
+    io: Io,
+}
+
+/// A connected stream, disconnected on drop
+pub(crate) struct PwStream {
+    quit: pw::channel::Sender<()>,
+    thread: Option<thread::JoinHandle<anyhow::Result<()>>>,
+}
+
+impl PwStream {
+    /// Fails when no node accepts `format` (e.g. an IEC958 codec the device does not enable)
+    pub(crate) fn playback(target: Option<&str>, format: StreamFormat, chunk_frames: usize) -> anyhow::Result<(Self, SyncSender<Vec<u8>>)> {
+        let (tx, rx) = sync_channel(QUEUE_CHUNKS);
+        let quantum_bytes = chunk_frames * format.spec().frame_size();
+        let io = Io::Playback { rx, pending: Vec::new(), pos: 0, quantum_bytes };
+        Ok((Self::spawn(Direction::Output, target, format, chunk_frames, io)?, tx))
+    }
+
+    pub(crate) fn capture(target: Option<&str>, spec: Spec, chunk_frames: usize) -> anyhow::Result<(Self, Receiver<Vec<u8>>)> {
+        let (tx, rx) = sync_channel(QUEUE_CHUNKS);
+        Ok((Self::spawn(Direction::Input, target, StreamFormat::Raw(spec), chunk_frames, Io::Capture { tx })?, rx))
+    }
+
+    fn spawn(direction: Direction, target: Option<&str>, format: StreamFormat, chunk_frames: usize, io: Io) -> anyhow::Result<Self> {
+        let (ready_tx, ready_rx) = sync_channel(1);
+        let (quit, quit_rx) = pw::channel::channel();
+        let target = target.map(str::to_string);
+        let thread = thread::spawn(move || Self::run(direction, target, format, chunk_frames, StreamData { ready: Some(ready_tx), io }, quit_rx));
+        let mut stream = Self { quit, thread: Some(thread) };
+        match ready_rx.recv_timeout(LINK_TIMEOUT) {
+            Ok(result) => result.map(|_| stream),
+            Err(RecvTimeoutError::Timeout) => Err(anyhow!("no PipeWire node linked the stream within {LINK_TIMEOUT:?}")),
+            Err(RecvTimeoutError::Disconnected) => Err(stream.error()),
+        }
+    }
+
+    fn run(direction: Direction, target: Option<String>, format: StreamFormat, chunk_frames: usize, data: StreamData, quit: pw::channel::Receiver<()>) -> anyhow::Result<()> {
+        pw::init();
+        let mainloop = MainLoop::new(None).context("pw_main_loop_new")?;
+        let context = PwContext::new(&mainloop).context("pw_context_new")?;
+        let core = context.connect(None).context("connecting to PipeWire")?;
+        let _quit = quit.attach(mainloop.loop_(), {
+            let mainloop = mainloop.clone();
+            move |()| mainloop.quit()
+        });
+
+        let stream = Stream::new(&core, "pcm-auto-decoder", properties(direction, format, chunk_frames, target.as_deref()))
+            .context("pw_stream_new")?;
+        let failure = Rc::new(RefCell::new(None));
+        let frame_bytes = format.spec().frame_size();
+        let _listener = stream
+            .add_local_listener_with_user_data(data)
+            .state_changed({
+                let (mainloop, failure) = (mainloop.clone(), failure.clone());
+                move |_, data: &mut StreamData, _, state| {
+                    if let StreamState::Error(e) = state {
+                        match data.ready.take() {
+                            Some(ready) => { let _ = ready.send(Err(anyhow!("PipeWire stream: {e}"))); }
+                            None => *failure.borrow_mut() = Some(e),
+                        }
+                        mainloop.quit();
+                    }
+                }
+            })
+            .param_changed(|_, data, id, param| {
+                // a format was agreed on: something is linked and accepts it
+                if id == ParamType::Format.as_raw() && param.is_some() && let Some(ready) = data.ready.take() {
+                    let _ = ready.send(Ok(()));
+                }
+            })
+            .process(move |stream, data| data.io.process(stream, frame_bytes))
+            .register()
+            .context("pw_stream_add_listener")?;
+
+        let values = format_pod(format)?;
+        let mut params = [Pod::from_bytes(&values).context("invalid format pod")?];
+        stream.connect(direction, None, StreamFlags::AUTOCONNECT | StreamFlags::MAP_BUFFERS, &mut params)
+            .context("pw_stream_connect")?;
+        mainloop.run();
+
+        let _ = stream.disconnect();
+        match failure.take() {
+            Some(e) => Err(anyhow!("PipeWire stream: {e}")),
+            None => Ok(()),
+        }
+    }
+
+    /// Why the thread stopped
+    pub(crate) fn error(&mut self) -> anyhow::Error {
+        match self.thread.take().map(|t| t.join()) {
+            Some(Ok(Err(e))) => e,
+            _ => anyhow!("PipeWire stream closed"),
+        }
+    }
+}
+
+impl Drop for PwStream {
+    fn drop(&mut self) {
+        let _ = self.quit.send(());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
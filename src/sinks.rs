@@ -1,30 +1,99 @@
+use std::cell::Cell;
 use std::fs::File;
 use std::io::{Read, Write};
+use std::net::TcpStream;
 use std::path::PathBuf;
-use anyhow::Context;
-use libpulse_binding::channelmap::Map;
+use std::rc::Rc;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread;
+use anyhow::{anyhow, Context};
+use libpulse_binding::channelmap::{Map, Position};
 use libpulse_binding::channelmap::MapDef::AIFF;
+use libpulse_binding::context::{self, Context as PaContext};
 use libpulse_binding::def::BufferAttr;
+use libpulse_binding::format::{Encoding, Info};
+use libpulse_binding::mainloop::standard::{IterateResult, Mainloop};
+use libpulse_binding::proplist::Proplist;
 use libpulse_binding::sample::{Format, Spec};
-use libpulse_binding::stream::Direction;
+use libpulse_binding::stream::{self, Direction, SeekMode, Stream};
 use libpulse_simple_binding::Simple;
 
 pub trait AudioSink {
     fn write(&mut self, bytes: &[u8]) -> anyhow::Result<()>;
     fn specs(& self) -> Spec;
+
+    /// Channel order of the samples `write` takes, ffmpeg's default layout unless the output says otherwise
+    fn channel_positions(&self) -> Vec<&'static str> {
+        crate::decoders::channel_positions(self.specs().channels).to_vec()
+    }
+
+    /// The pipeline switched away from this sink's mode, it gets no writes until it switches back
+    fn deactivate(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// PulseAudio channel positions by the names of `decoders::channel_positions`
+const POSITIONS: [(&str, Position); 12] = [
+    ("MONO", Position::Mono),
+    ("FL", Position::FrontLeft),
+    ("FR", Position::FrontRight),
+    ("FC", Position::FrontCenter),
+    ("LFE", Position::Lfe),
+    ("RL", Position::RearLeft),
+    ("RR", Position::RearRight),
+    ("RC", Position::RearCenter),
+    ("SL", Position::SideLeft),
+    ("SR", Position::SideRight),
+    ("FLC", Position::FrontLeftOfCenter),
+    ("FRC", Position::FrontRightOfCenter),
+];
+
+/// A channel position by name (FL, FR, FC, LFE, RL, RR, RC, SL, SR, FLC, FRC, MONO)
+pub fn position(name: &str) -> anyhow::Result<&'static str> {
+    POSITIONS.iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(n, _)| *n)
+        .ok_or_else(|| anyhow!("unknown channel position {name:?}, expected one of {}",
+            POSITIONS.iter().map(|(n, _)| *n).collect::<Vec<_>>().join(", ")))
+}
+
+/// `positions` as a PulseAudio map
+fn channel_map(positions: &[&str]) -> Map {
+    let mut cm = Map::default();
+    cm.set_len(positions.len() as u8);
+    for (slot, name) in cm.get_mut().iter_mut().zip(positions) {
+        *slot = POSITIONS.iter().find(|(n, _)| n == name).map_or(Position::Invalid, |(_, p)| *p);
+    }
+    cm
 }
 
 /* PulseAudio stereo sink */
-pub(crate) struct PulseAudioSink {
+pub struct PulseAudioSink {
     pa: Simple,
     spec: Spec,
+    positions: Vec<&'static str>, // of the map the stream is opened with
 }
 impl PulseAudioSink {
-    pub(crate) fn open(sink: Option<&str>, format: Format, rate: u32, channels: u8, chunk_frames: usize) -> anyhow::Result<Self> {
-        let ss = Spec { format, rate, channels };
+    /// Channels in the decoders' layout, AIFF for channel counts it does not cover
+    pub fn open(sink: Option<&str>, format: Format, rate: u32, channels: u8, chunk_frames: usize) -> anyhow::Result<Self> {
+        let layout = crate::decoders::channel_positions(channels);
+        let mut cm = channel_map(layout);
+        if layout.is_empty() {
+            cm.init_auto(channels, AIFF);
+        }
+        Self::connect(sink, Spec { format, rate, channels }, cm, chunk_frames)
+    }
+
+    /// One channel per entry of `positions` (see `position`), in that order
+    pub fn open_mapped(sink: Option<&str>, format: Format, rate: u32, positions: &[&str], chunk_frames: usize) -> anyhow::Result<Self> {
+        let channels = positions.len() as u8;
+        Self::connect(sink, Spec { format, rate, channels }, channel_map(positions), chunk_frames)
+    }
+
+    fn connect(sink: Option<&str>, ss: Spec, cm: Map, chunk_frames: usize) -> anyhow::Result<Self> {
         anyhow::ensure!(ss.is_valid(), "Invalid sample spec");
-        let mut cm = Map::default();
-        cm.init_auto(channels, AIFF);
+        let channels = ss.channels;
 
         let frame_bytes = (channels as u32) * 2;
         let frag_bytes  = chunk_frames as u32 * frame_bytes;
@@ -50,7 +119,10 @@ impl PulseAudioSink {
             Some(&attr),
         )
             .context(format!("opening PulseAudio sink with spec={:?}", ss))?;
-        Ok(Self { pa, spec: ss })
+        let positions = cm.get().iter()
+            .map(|position| POSITIONS.iter().find(|(_, p)| p == position).map_or("AUX", |(n, _)| *n))
+            .collect();
+        Ok(Self { pa, spec: ss, positions })
     }
 }
 impl AudioSink for PulseAudioSink {
@@ -61,16 +133,133 @@ impl AudioSink for PulseAudioSink {
     fn specs(& self) -> Spec {
         self.spec
     }
+
+    fn channel_positions(&self) -> Vec<&'static str> {
+        self.positions.clone()
+    }
+}
+
+/* ALSA playback sink */
+#[cfg(feature = "alsa")]
+pub struct AlsaSink {
+    pcm: alsa::pcm::PCM,
+    spec: Spec,
+}
+#[cfg(feature = "alsa")]
+impl AlsaSink {
+    /// Plays on `device` (e.g. `hw:CARD=sndrpihifiberry`, `plug:dmix`), `chunk_frames` per period
+    pub fn open(device: &str, format: Format, rate: u32, channels: u8, chunk_frames: usize) -> anyhow::Result<Self> {
+        let spec = Spec { format, rate, channels };
+        anyhow::ensure!(spec.is_valid(), "Invalid sample spec");
+        let pcm = crate::alsa_pcm::open(device, alsa::Direction::Playback, spec, chunk_frames)?;
+        Ok(Self { pcm, spec })
+    }
+}
+#[cfg(feature = "alsa")]
+impl AudioSink for AlsaSink {
+    fn write(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        let frame_bytes = self.spec.frame_size();
+        let mut pos = 0;
+        while pos + frame_bytes <= bytes.len() {
+            match self.pcm.io_bytes().writei(&bytes[pos..]) {
+                Ok(frames) => pos += frames * frame_bytes,
+                Err(e) => crate::alsa_pcm::recover(&self.pcm, e, "playback")?,
+            }
+        }
+        Ok(())
+    }
+
+    fn specs(& self) -> Spec {
+        self.spec
+    }
+}
+
+/* PipeWire playback sink */
+#[cfg(feature = "pipewire")]
+pub struct PipeWireSink {
+    tx: Option<SyncSender<Vec<u8>>>,
+    stream: crate::pw_stream::PwStream,
+    spec: Spec,
+}
+#[cfg(feature = "pipewire")]
+impl PipeWireSink {
+    /// Plays to `target` (node name or serial), None for the default sink. The channel
+    /// positions follow the decoders' layout for `channels`.
+    pub fn open(target: Option<&str>, format: Format, rate: u32, channels: u8, chunk_frames: usize) -> anyhow::Result<Self> {
+        let spec = Spec { format, rate, channels };
+        anyhow::ensure!(spec.is_valid(), "Invalid sample spec");
+        let (stream, tx) = crate::pw_stream::PwStream::playback(target, crate::pw_stream::StreamFormat::Raw(spec), chunk_frames)?;
+        Ok(Self { tx: Some(tx), stream, spec })
+    }
+
+    /// IEC61937 bursts on an IEC958 node, fails if the device does not enable the codec
+    pub(crate) fn open_iec958(target: Option<&str>, stream_type: crate::iec61937_detector::StreamType, rate: u32) -> anyhow::Result<Self> {
+        let format = crate::pw_stream::StreamFormat::Iec958 { stream_type, rate };
+        let (stream, tx) = crate::pw_stream::PwStream::playback(target, format, 1536)?; // one AC-3 frame
+        Ok(Self { tx: Some(tx), stream, spec: format.spec() })
+    }
+}
+#[cfg(feature = "pipewire")]
+impl AudioSink for PipeWireSink {
+    fn write(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        let sent = self.tx.as_ref().is_some_and(|tx| tx.send(bytes.to_vec()).is_ok());
+        if !sent {
+            self.tx = None;
+            return Err(self.stream.error().context("PipeWire playback"));
+        }
+        Ok(())
+    }
+
+    fn specs(& self) -> Spec {
+        self.spec
+    }
+}
+
+/* JACK playback sink */
+#[cfg(feature = "jack")]
+pub struct JackSink {
+    client: crate::jack_client::JackClient<crate::jack_client::Playback, jack::RingBufferWriter>,
+    spec: Spec,
+}
+#[cfg(feature = "jack")]
+impl JackSink {
+    /// JACK client `name` with one float output port per channel (FL, FR, FC, LFE…), connected
+    /// to `targets` in order. `format` is what `write` takes: S16LE, S32LE or F32LE.
+    pub fn open(name: &str, targets: &[String], format: Format, rate: u32, channels: u8, chunk_frames: usize) -> anyhow::Result<Self> {
+        let spec = Spec { format, rate, channels };
+        anyhow::ensure!(spec.is_valid(), "Invalid sample spec");
+        let client = crate::jack_client::playback(name, spec, chunk_frames, targets)?;
+        Ok(Self { client, spec })
+    }
+}
+#[cfg(feature = "jack")]
+impl AudioSink for JackSink {
+    fn write(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        let mut pos = 0;
+        while pos < bytes.len() {
+            self.client.check("playback")?;
+            let n = self.client.ring.write_buffer(&bytes[pos..]);
+            if n == 0 {
+                std::thread::sleep(self.client.poll);
+            }
+            pos += n;
+        }
+        Ok(())
+    }
+
+    fn specs(& self) -> Spec {
+        self.spec
+    }
 }
 
 /* FIFO/file stereo sink */
-pub(crate) struct FileSink {
+pub struct FileSink {
     f: File,
     spec: Spec
 }
 impl FileSink {
-    pub(crate) fn open(path: &PathBuf, format: Format, rate: u32, channels: u8) -> anyhow::Result<Self> {
-        let f = File::options().read(true).write(true).open(path).context("open fifo_out")?;
+    pub fn open(path: &PathBuf, format: Format, rate: u32, channels: u8) -> anyhow::Result<Self> {
+        let f = File::options().read(true).write(true).create(true).truncate(true).open(path).context("open fifo_out")?;
         Ok(Self { f, spec: Spec {format, rate, channels} })
     }
 }
@@ -80,6 +269,294 @@ impl AudioSink for FileSink {
     }
 
     fn specs(& self) -> Spec {
-        todo!()
+        self.spec
     }
-}
\ No newline at end of file
+}
+
+/* F32LE -> any PCM format the decoders cannot write themselves */
+pub struct ConvertSink {
+    inner: Box<dyn AudioSink + Send>,
+    input: Spec,
+    buf: Vec<u8>,
+}
+impl ConvertSink {
+    /// Whether F32LE samples can be converted to `format`
+    pub fn supports(format: Format) -> bool {
+        use Format::*;
+        matches!(format, U8 | S16le | S16be | S24le | S24be | S24_32le | S24_32be | S32le | S32be | F32le | F32be)
+    }
+
+    /// Takes F32LE at the channels and rate of `inner`, writes it in the format of `inner`
+    pub fn wrap(inner: Box<dyn AudioSink + Send>) -> anyhow::Result<Self> {
+        let spec = inner.specs();
+        anyhow::ensure!(Self::supports(spec.format), "no conversion from F32LE to {:?}", spec.format);
+        Ok(Self { inner, input: Spec { format: Format::F32le, ..spec }, buf: Vec::new() })
+    }
+
+    fn convert(format: Format, sample: f32, out: &mut Vec<u8>) {
+        let int = |bits: u32| {
+            let full_scale = (1i64 << (bits - 1)) as f64;
+            (sample as f64 * full_scale).round().clamp(-full_scale, full_scale - 1.0) as i32
+        };
+        match format {
+            Format::U8 => out.push((int(8) + 128) as u8),
+            Format::S16le => out.extend_from_slice(&(int(16) as i16).to_le_bytes()),
+            Format::S16be => out.extend_from_slice(&(int(16) as i16).to_be_bytes()),
+            Format::S24le => out.extend_from_slice(&int(24).to_le_bytes()[..3]),
+            Format::S24be => out.extend_from_slice(&int(24).to_be_bytes()[1..]),
+            Format::S24_32le => out.extend_from_slice(&int(24).to_le_bytes()),
+            Format::S24_32be => out.extend_from_slice(&int(24).to_be_bytes()),
+            Format::S32le => out.extend_from_slice(&int(32).to_le_bytes()),
+            Format::S32be => out.extend_from_slice(&int(32).to_be_bytes()),
+            Format::F32be => out.extend_from_slice(&sample.to_be_bytes()),
+            _ => out.extend_from_slice(&sample.to_le_bytes()),
+        }
+    }
+}
+impl AudioSink for ConvertSink {
+    fn write(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        let format = self.inner.specs().format;
+        self.buf.clear();
+        for sample in bytes.chunks_exact(4) {
+            Self::convert(format, f32::from_le_bytes([sample[0], sample[1], sample[2], sample[3]]), &mut self.buf);
+        }
+        self.inner.write(&self.buf)
+    }
+
+    fn specs(& self) -> Spec {
+        self.input
+    }
+
+    fn channel_positions(&self) -> Vec<&'static str> {
+        self.inner.channel_positions()
+    }
+
+    fn deactivate(&mut self) -> anyhow::Result<()> {
+        self.inner.deactivate()
+    }
+}
+
+/* Channel reordering: the decoders' layout in, any order of positions out */
+pub struct RemapSink {
+    inner: Box<dyn AudioSink + Send>,
+    sources: Vec<Option<usize>>, // for each output channel, the input channel feeding it
+    buf: Vec<u8>,
+}
+impl RemapSink {
+    /// `inner` takes one channel per entry of `positions`; those missing from the decoders'
+    /// layout for that many channels get silence
+    pub fn wrap(inner: Box<dyn AudioSink + Send>, positions: &[&str]) -> anyhow::Result<Self> {
+        let spec = inner.specs();
+        anyhow::ensure!(positions.len() == spec.channels as usize, "{} positions for {} channels", positions.len(), spec.channels);
+        let layout = crate::decoders::channel_positions(spec.channels);
+        let sources: Vec<Option<usize>> = positions.iter().map(|p| layout.iter().position(|l| l == p)).collect();
+        let unused: Vec<&str> = layout.iter().enumerate().filter(|(i, _)| !sources.contains(&Some(*i))).map(|(_, l)| *l).collect();
+        if !unused.is_empty() {
+            eprintln!("channel map {} drops {}", positions.join(","), unused.join(","));
+        }
+        Ok(Self { inner, sources, buf: Vec::new() })
+    }
+}
+impl AudioSink for RemapSink {
+    fn write(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        let spec = self.inner.specs();
+        let sample_bytes = spec.sample_size();
+        self.buf.clear();
+        for frame in bytes.chunks_exact(spec.frame_size()) {
+            for source in &self.sources {
+                match source {
+                    Some(c) => self.buf.extend_from_slice(&frame[c * sample_bytes..(c + 1) * sample_bytes]),
+                    None => self.buf.resize(self.buf.len() + sample_bytes, 0),
+                }
+            }
+        }
+        self.inner.write(&self.buf)
+    }
+
+    fn specs(& self) -> Spec {
+        self.inner.specs()
+    }
+
+    fn deactivate(&mut self) -> anyhow::Result<()> {
+        self.inner.deactivate()
+    }
+}
+
+/* TCP stream sink: raw samples to a listening socket, e.g. a network monitor */
+pub struct TcpSink {
+    stream: TcpStream,
+    peer: String,
+    spec: Spec,
+}
+impl TcpSink {
+    pub fn connect(addr: &str, format: Format, rate: u32, channels: u8) -> anyhow::Result<Self> {
+        let stream = TcpStream::connect(addr).with_context(|| format!("connecting to {addr}"))?;
+        stream.set_nodelay(true).context("setting TCP_NODELAY")?;
+        Ok(Self { stream, peer: addr.to_string(), spec: Spec { format, rate, channels } })
+    }
+}
+impl AudioSink for TcpSink {
+    fn write(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        self.stream.write_all(bytes).with_context(|| format!("writing to {}", self.peer))
+    }
+
+    fn specs(& self) -> Spec {
+        self.spec
+    }
+}
+
+/* Sink discarding everything, to mute an output */
+pub struct NullSink {
+    spec: Spec,
+}
+impl NullSink {
+    pub fn new(format: Format, rate: u32, channels: u8) -> Self {
+        Self { spec: Spec { format, rate, channels } }
+    }
+}
+impl AudioSink for NullSink {
+    fn write(&mut self, _bytes: &[u8]) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn specs(& self) -> Spec {
+        self.spec
+    }
+}
+
+/* PulseAudio compressed passthrough sink: IEC61937 bursts to an encoded-format stream */
+// pa_simple cannot negotiate encoded formats, so the async API runs on its own thread
+// (its objects are not Send) and bursts are handed over through a channel.
+pub(crate) struct PulsePassthroughSink {
+    tx: Option<SyncSender<Vec<u8>>>,
+    thread: Option<thread::JoinHandle<anyhow::Result<()>>>,
+    spec: Spec,
+}
+impl PulsePassthroughSink {
+    /// Fails when the sink does not accept `encoding` (e.g. no receiver, or not in a digital profile)
+    pub(crate) fn open(sink: Option<&str>, encoding: Encoding, rate: u32) -> anyhow::Result<Self> {
+        let (tx, rx) = sync_channel::<Vec<u8>>(8);
+        let (ready_tx, ready_rx) = sync_channel(1);
+        let sink = sink.map(str::to_string);
+        let thread = thread::spawn(move || -> anyhow::Result<()> {
+            match Self::connect(sink.as_deref(), encoding, rate) {
+                Ok((mainloop, context, stream)) => {
+                    let _ = ready_tx.send(Ok(()));
+                    Self::play(mainloop, context, stream, rx)
+                }
+                Err(e) => {
+                    let _ = ready_tx.send(Err(e));
+                    Ok(())
+                }
+            }
+        });
+        ready_rx.recv().context("passthrough thread died")??;
+
+        // the server sees a "fake" 2ch S16 stream at the IEC61937 rate
+        let spec = Spec { format: Format::S16le, rate, channels: 2 };
+        Ok(Self { tx: Some(tx), thread: Some(thread), spec })
+    }
+
+    fn connect(sink: Option<&str>, encoding: Encoding, rate: u32) -> anyhow::Result<(Mainloop, PaContext, Stream)> {
+        let mut mainloop = Mainloop::new().context("pa_mainloop_new")?;
+        let mut context = PaContext::new(&mainloop, "pcm-auto-decoder").context("pa_context_new")?;
+        context.connect(None, context::FlagSet::NOFLAGS, None).context("connecting to PulseAudio")?;
+        loop {
+            match context.get_state() {
+                context::State::Ready => break,
+                context::State::Failed | context::State::Terminated => anyhow::bail!("PulseAudio connection failed"),
+                _ => Self::iterate(&mut mainloop)?,
+            }
+        }
+
+        let mut format = Info::new().context("pa_format_info_new")?;
+        format.set_encoding(encoding);
+        format.set_rate(rate as i32);
+        format.set_channels(2);
+        let mut proplist = Proplist::new().context("pa_proplist_new")?;
+        let mut stream = Stream::new_extended(&mut context, "passthrough", &[&format], &mut proplist)
+            .context("pa_stream_new_extended")?;
+        stream.connect_playback(sink, None, stream::FlagSet::NOFLAGS, None, None)
+            .context("connecting passthrough stream")?;
+        loop {
+            match stream.get_state() {
+                stream::State::Ready => break,
+                stream::State::Failed | stream::State::Terminated => anyhow::bail!("sink rejected {encoding:?} at {rate} Hz"),
+                _ => Self::iterate(&mut mainloop)?,
+            }
+        }
+        Ok((mainloop, context, stream))
+    }
+
+    fn play(mut mainloop: Mainloop, _context: PaContext, mut stream: Stream, rx: Receiver<Vec<u8>>) -> anyhow::Result<()> {
+        for bytes in rx {
+            let mut pos = 0;
+            while pos < bytes.len() {
+                anyhow::ensure!(stream.get_state() == stream::State::Ready, "passthrough stream lost");
+                let writable = stream.writable_size().unwrap_or(0) & !3; // whole frames only
+                if writable == 0 {
+                    Self::iterate(&mut mainloop)?;
+                    continue;
+                }
+                let n = writable.min(bytes.len() - pos);
+                stream.write(&bytes[pos..pos + n], None, 0, SeekMode::Relative).context("pa_stream_write")?;
+                pos += n;
+            }
+        }
+        Self::drain(&mut mainloop, &mut stream)?;
+        stream.disconnect().context("pa_stream_disconnect")?;
+        Ok(())
+    }
+
+    /// Wait until the server has played everything written, so the last bursts are not cut off
+    fn drain(mainloop: &mut Mainloop, stream: &mut Stream) -> anyhow::Result<()> {
+        let done = Rc::new(Cell::new(None));
+        let flag = Rc::clone(&done);
+        let _op = stream.drain(Some(Box::new(move |success| flag.set(Some(success)))));
+        let success = loop {
+            if let Some(success) = done.get() {
+                break success;
+            }
+            anyhow::ensure!(stream.get_state() == stream::State::Ready, "passthrough stream lost");
+            Self::iterate(mainloop)?;
+        };
+        anyhow::ensure!(success, "pa_stream_drain failed");
+        Ok(())
+    }
+
+    fn iterate(mainloop: &mut Mainloop) -> anyhow::Result<()> {
+        match mainloop.iterate(true) {
+            IterateResult::Success(_) => Ok(()),
+            IterateResult::Quit(_) => anyhow::bail!("PulseAudio mainloop quit"),
+            IterateResult::Err(e) => anyhow::bail!("PulseAudio mainloop: {e}"),
+        }
+    }
+}
+impl AudioSink for PulsePassthroughSink {
+    fn write(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        let mut bytes = bytes.to_vec();
+        bytes.resize(bytes.len().next_multiple_of(4), 0); // a burst trimmed to its payload
+        let sent = self.tx.as_ref().map(|tx| tx.send(bytes).is_ok()).unwrap_or(false);
+        if !sent {
+            // the thread stopped, report why
+            self.tx = None;
+            return match self.thread.take().map(|t| t.join()) {
+                Some(Ok(Err(e))) => Err(e.context("passthrough stream")),
+                _ => Err(anyhow!("passthrough stream closed")),
+            };
+        }
+        Ok(())
+    }
+
+    fn specs(& self) -> Spec {
+        self.spec
+    }
+}
+impl Drop for PulsePassthroughSink {
+    fn drop(&mut self) {
+        self.tx = None; // ends the thread's loop
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,295 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::Duration;
+use anyhow::Context;
+use libpulse_binding::channelmap::Map;
+use libpulse_binding::channelmap::MapDef::ALSA;
+use libpulse_binding::def::BufferAttr;
+use libpulse_binding::sample::Spec;
+use libpulse_binding::stream::Direction;
+use libpulse_simple_binding::Simple;
+use crate::pipeline::word_layout;
+
+/// Where the pipeline reads from, the mirror of `AudioSink`
+pub trait AudioSource {
+    /// Next chunk of samples in `specs()`, `None` once the stream has ended.
+    /// Errors are fatal for the pipeline.
+    fn read_chunk(&mut self) -> anyhow::Result<Option<&[u8]>>;
+    fn specs(&self) -> Spec;
+}
+
+fn frame_bytes(spec: Spec) -> anyhow::Result<usize> {
+    Ok(spec.channels as usize * word_layout(spec.format)?.sample_bytes)
+}
+
+/* PulseAudio capture */
+pub struct PulseAudioSource {
+    pa: Simple,
+    spec: Spec,
+    buf: Vec<u8>,
+}
+impl PulseAudioSource {
+    /// Capture from `source`, read `chunk_frames` at a time
+    pub fn open(source: &str, spec: Spec, chunk_frames: usize) -> anyhow::Result<Self> {
+        anyhow::ensure!(spec.is_valid(), "Invalid capture spec");
+        let buf = vec![0u8; chunk_frames * frame_bytes(spec)?];
+        let mut cm = Map::default();
+        cm.init_auto(spec.channels, ALSA);
+
+        let attr = BufferAttr {
+            maxlength: u32::MAX,         // ok to leave MAX here
+            tlength:   u32::MAX,         // ignored for record
+            prebuf:    u32::MAX,         // ignored for record
+            minreq:    u32::MAX,         // ignored for record
+            fragsize:  buf.len() as u32, // THIS matters: when PA wakes your record stream
+        };
+
+        let pa = Simple::new(
+            None,
+            "pcm-auto-decoder",
+            Direction::Record,
+            Some(source),
+            "capture",
+            &spec,
+            Some(&cm),
+            Some(&attr),
+        )
+            .context("opening PulseAudio capture")?;
+        Ok(Self { pa, spec, buf })
+    }
+}
+impl AudioSource for PulseAudioSource {
+    fn read_chunk(&mut self) -> anyhow::Result<Option<&[u8]>> {
+        self.pa.read(&mut self.buf).context("pa_simple_read")?;
+        Ok(Some(&self.buf))
+    }
+
+    fn specs(&self) -> Spec {
+        self.spec
+    }
+}
+
+/* ALSA capture */
+#[cfg(feature = "alsa")]
+pub struct AlsaSource {
+    pcm: alsa::pcm::PCM,
+    spec: Spec,
+    buf: Vec<u8>,
+}
+#[cfg(feature = "alsa")]
+impl AlsaSource {
+    /// Capture from `device` (e.g. `hw:CARD=sndrpihifiberry`), one period per chunk
+    pub fn open(device: &str, spec: Spec, chunk_frames: usize) -> anyhow::Result<Self> {
+        anyhow::ensure!(spec.is_valid(), "Invalid capture spec");
+        let buf = vec![0u8; chunk_frames * frame_bytes(spec)?];
+        let pcm = crate::alsa_pcm::open(device, alsa::Direction::Capture, spec, chunk_frames)?;
+        Ok(Self { pcm, spec, buf })
+    }
+}
+#[cfg(feature = "alsa")]
+impl AudioSource for AlsaSource {
+    fn read_chunk(&mut self) -> anyhow::Result<Option<&[u8]>> {
+        let frame_bytes = self.spec.frame_size();
+        let mut got = 0;
+        while got < self.buf.len() {
+            match self.pcm.io_bytes().readi(&mut self.buf[got..]) {
+                Ok(frames) => got += frames * frame_bytes,
+                Err(e) => crate::alsa_pcm::recover(&self.pcm, e, "capture")?,
+            }
+        }
+        Ok(Some(&self.buf))
+    }
+
+    fn specs(&self) -> Spec {
+        self.spec
+    }
+}
+
+/* PipeWire capture */
+#[cfg(feature = "pipewire")]
+pub struct PipeWireSource {
+    rx: std::sync::mpsc::Receiver<Vec<u8>>,
+    stream: crate::pw_stream::PwStream,
+    spec: Spec,
+    buf: Vec<u8>,
+    pending: Vec<u8>,
+    pos: usize,
+}
+#[cfg(feature = "pipewire")]
+impl PipeWireSource {
+    /// Capture from `target` (node name or serial), None for the default source
+    pub fn open(target: Option<&str>, spec: Spec, chunk_frames: usize) -> anyhow::Result<Self> {
+        anyhow::ensure!(spec.is_valid(), "Invalid capture spec");
+        let buf = vec![0u8; chunk_frames * frame_bytes(spec)?];
+        let (stream, rx) = crate::pw_stream::PwStream::capture(target, spec, chunk_frames)?;
+        Ok(Self { rx, stream, spec, buf, pending: Vec::new(), pos: 0 })
+    }
+}
+#[cfg(feature = "pipewire")]
+impl AudioSource for PipeWireSource {
+    /// Chunks of the pipeline size, whatever the graph quantum is
+    fn read_chunk(&mut self) -> anyhow::Result<Option<&[u8]>> {
+        let mut got = 0;
+        while got < self.buf.len() {
+            if self.pos == self.pending.len() {
+                match self.rx.recv() {
+                    Ok(bytes) => (self.pending, self.pos) = (bytes, 0),
+                    Err(_) => return Err(self.stream.error().context("PipeWire capture")),
+                }
+                continue;
+            }
+            let n = (self.pending.len() - self.pos).min(self.buf.len() - got);
+            self.buf[got..got + n].copy_from_slice(&self.pending[self.pos..self.pos + n]);
+            (got, self.pos) = (got + n, self.pos + n);
+        }
+        Ok(Some(&self.buf))
+    }
+
+    fn specs(&self) -> Spec {
+        self.spec
+    }
+}
+
+/* JACK capture */
+#[cfg(feature = "jack")]
+pub struct JackSource {
+    client: crate::jack_client::JackClient<crate::jack_client::Capture, jack::RingBufferReader>,
+    spec: Spec,
+    buf: Vec<u8>,
+}
+#[cfg(feature = "jack")]
+impl JackSource {
+    /// JACK client `name` with one input port per channel carrying the S/PDIF words, connected
+    /// to `targets` in order. The ports' floats come back as S16LE, which `spec` must be.
+    pub fn open(name: &str, targets: &[String], spec: Spec, chunk_frames: usize) -> anyhow::Result<Self> {
+        anyhow::ensure!(spec.is_valid(), "Invalid capture spec");
+        let buf = vec![0u8; chunk_frames * frame_bytes(spec)?];
+        let client = crate::jack_client::capture(name, spec, chunk_frames, targets)?;
+        Ok(Self { client, spec, buf })
+    }
+}
+#[cfg(feature = "jack")]
+impl AudioSource for JackSource {
+    fn read_chunk(&mut self) -> anyhow::Result<Option<&[u8]>> {
+        while self.client.ring.space() < self.buf.len() {
+            self.client.check("capture")?;
+            sleep(self.client.poll);
+        }
+        self.client.ring.read_buffer(&mut self.buf);
+        Ok(Some(&self.buf))
+    }
+
+    fn specs(&self) -> Spec {
+        self.spec
+    }
+}
+
+/// What a `FileSource` does once it has read everything
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EofPolicy {
+    /// End the stream, the pipeline then flushes the decoder and stops
+    Exit,
+    /// Open the path again, for a FIFO this waits for the next writer
+    Reopen,
+    /// Start over from the beginning of the file, for soak testing
+    Loop,
+}
+
+/* FIFO/file source */
+pub struct FileSource {
+    path: PathBuf,
+    f: File,
+    spec: Spec,
+    frame_bytes: usize,
+    buf: Vec<u8>,
+    on_eof: EofPolicy,
+    read_since_open: bool, // a file to loop over must not be empty
+    ended: bool,
+}
+impl FileSource {
+    /// File or FIFO holding samples in `spec`, read `chunk_frames` at a time
+    pub fn open(path: &Path, spec: Spec, chunk_frames: usize, on_eof: EofPolicy) -> anyhow::Result<Self> {
+        let frame_bytes = frame_bytes(spec)?;
+        let f = Self::open_file(path)?;
+        Ok(Self { path: path.to_path_buf(), f, spec, frame_bytes, buf: vec![0u8; chunk_frames * frame_bytes], on_eof, read_since_open: false, ended: false })
+    }
+
+    fn open_file(path: &Path) -> anyhow::Result<File> {
+        File::options().read(true).open(path).with_context(|| format!("open {}", path.display()))
+    }
+
+    /// Apply the EOF policy, false when the stream is over
+    fn rewind(&mut self) -> anyhow::Result<bool> {
+        match self.on_eof {
+            EofPolicy::Exit => return Ok(false),
+            EofPolicy::Reopen => {
+                eprintln!("Input stream lost !");
+                sleep(Duration::from_millis(500));
+                self.f = Self::open_file(&self.path)?;
+            }
+            EofPolicy::Loop => {
+                anyhow::ensure!(self.read_since_open, "{} has no samples to loop over", self.path.display());
+                self.f.seek(SeekFrom::Start(0)).with_context(|| format!("rewind {}", self.path.display()))?;
+            }
+        }
+        self.read_since_open = false;
+        Ok(true)
+    }
+}
+impl AudioSource for FileSource {
+    /// A full chunk, or the trailing whole frames at EOF before the policy applies
+    fn read_chunk(&mut self) -> anyhow::Result<Option<&[u8]>> {
+        if self.ended {
+            return Ok(None);
+        }
+        let mut got = 0usize;
+        while got < self.buf.len() {
+            let n = self.f.read(&mut self.buf[got..]).context("read input")?;
+            if n == 0 {
+                // EOF: hand out the partial chunk first, a torn frame is dropped
+                got -= got % self.frame_bytes;
+                if got > 0 {
+                    break;
+                }
+                if !self.rewind()? {
+                    self.ended = true;
+                    return Ok(None);
+                }
+                continue;
+            }
+            got += n;
+            self.read_since_open = true;
+        }
+        Ok(Some(&self.buf[..got]))
+    }
+
+    fn specs(&self) -> Spec {
+        self.spec
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libpulse_binding::sample::Format;
+
+    #[test]
+    fn file_source_partial_chunk_and_eof_policies() {
+        let path = std::env::temp_dir().join(format!("pad-source-{}.raw", std::process::id()));
+        std::fs::write(&path, (0..10 * 4 + 3).map(|i| i as u8).collect::<Vec<u8>>()).unwrap(); // 10 frames and a torn one
+        let spec = Spec { format: Format::S16le, rate: 48_000, channels: 2 };
+
+        let mut source = FileSource::open(&path, spec, 4, EofPolicy::Exit).unwrap();
+        let lens: Vec<usize> = std::iter::from_fn(|| source.read_chunk().unwrap().map(<[u8]>::len)).collect();
+        assert_eq!(lens, [16, 16, 8]);
+        assert!(source.read_chunk().unwrap().is_none());
+
+        let mut source = FileSource::open(&path, spec, 4, EofPolicy::Loop).unwrap();
+        let lens: Vec<usize> = (0..4).map(|_| source.read_chunk().unwrap().unwrap().len()).collect();
+        assert_eq!(lens, [16, 16, 8, 16]);
+        assert_eq!(source.read_chunk().unwrap().unwrap()[0], 16);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
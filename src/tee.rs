@@ -0,0 +1,310 @@
+/* Tee sink: every write duplicated to several child sinks, each behind its own queue and thread */
+// A child that falls behind only fills its own queue; what happens then is its DropPolicy.
+// A child whose sink fails is dropped from the tee, the others keep playing.
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use anyhow::anyhow;
+use libpulse_binding::sample::Spec;
+use crate::sinks::AudioSink;
+
+/// Data chunks queued per child, control messages come on top
+const QUEUE_CHUNKS: usize = 8;
+
+/// What a child does with a write when its queue is full
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// Wait for room: the child paces the whole tee
+    Block,
+    /// Drop the incoming chunk
+    Newest,
+    /// Drop the oldest queued chunk to make room
+    #[default]
+    Oldest,
+}
+
+enum Message {
+    Data(Vec<u8>),
+    Deactivate,
+}
+
+#[derive(Default)]
+struct State {
+    messages: VecDeque<Message>,
+    closed: bool,         // no more writes, drain and exit
+    failed: Option<String>,
+}
+
+impl State {
+    fn queued_data(&self) -> usize {
+        self.messages.iter().filter(|m| matches!(m, Message::Data(_))).count()
+    }
+}
+
+/// Between the tee and one child thread
+#[derive(Default)]
+struct Queue {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+impl Queue {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Next message for the child, None once closed and drained
+    fn pop(&self) -> Option<Message> {
+        let mut state = self.lock();
+        loop {
+            if let Some(message) = state.messages.pop_front() {
+                self.changed.notify_all();
+                return Some(message);
+            }
+            if state.closed {
+                return None;
+            }
+            state = self.changed.wait(state).unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+    }
+}
+
+struct Child {
+    name: String,
+    policy: DropPolicy,
+    queue: Arc<Queue>,
+    dropped: usize,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Child {
+    fn spawn(name: String, mut sink: Box<dyn AudioSink + Send>, policy: DropPolicy) -> anyhow::Result<Self> {
+        let queue = Arc::new(Queue::default());
+        let thread_queue = Arc::clone(&queue);
+        let thread = thread::Builder::new().name(format!("tee {name}")).spawn(move || {
+            while let Some(message) = thread_queue.pop() {
+                let result = match message {
+                    Message::Data(bytes) => sink.write(&bytes),
+                    Message::Deactivate => sink.deactivate(),
+                };
+                if let Err(e) = result {
+                    let mut state = thread_queue.lock();
+                    state.failed = Some(format!("{e:#}"));
+                    state.closed = true;
+                    state.messages.clear();
+                    thread_queue.changed.notify_all();
+                    return;
+                }
+            }
+        })?;
+        Ok(Self { name, policy, queue, dropped: 0, thread: Some(thread) })
+    }
+
+    /// Queue a message, applying the drop policy to data; control messages neither wait
+    /// nor evict anything. Err once the child failed.
+    fn push(&mut self, message: Message) -> Result<(), String> {
+        let queue = Arc::clone(&self.queue);
+        let mut state = queue.lock();
+        let is_data = matches!(message, Message::Data(_));
+        while is_data && state.queued_data() >= QUEUE_CHUNKS && state.failed.is_none() {
+            match self.policy {
+                DropPolicy::Newest => {
+                    self.report_drop();
+                    return Ok(());
+                }
+                DropPolicy::Oldest => {
+                    if let Some(at) = state.messages.iter().position(|m| matches!(m, Message::Data(_))) {
+                        state.messages.remove(at);
+                    }
+                    self.report_drop();
+                    break;
+                }
+                DropPolicy::Block => state = queue.changed.wait(state).unwrap_or_else(|poisoned| poisoned.into_inner()),
+            }
+        }
+        if let Some(e) = &state.failed {
+            return Err(e.clone());
+        }
+        state.messages.push_back(message);
+        queue.changed.notify_all();
+        Ok(())
+    }
+
+    fn report_drop(&mut self) {
+        self.dropped += 1;
+        if self.dropped.is_power_of_two() {
+            eprintln!("tee: {} is behind, {} chunks dropped", self.name, self.dropped);
+        }
+    }
+}
+
+impl Drop for Child {
+    /// Let the child play what is queued, then stop its thread
+    fn drop(&mut self) {
+        self.queue.lock().closed = true;
+        self.queue.changed.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Duplicates every write to its children, which must all take the same spec and channel map
+pub struct TeeSink {
+    children: Vec<Child>,
+    spec: Spec,
+    positions: Vec<&'static str>,
+}
+
+impl TeeSink {
+    /// `children` are (name for the logs, sink, drop policy)
+    pub fn new(children: Vec<(String, Box<dyn AudioSink + Send>, DropPolicy)>) -> anyhow::Result<Self> {
+        let (_, first, _) = children.first().ok_or_else(|| anyhow!("tee without outputs"))?;
+        let (spec, positions) = (first.specs(), first.channel_positions());
+        for (name, sink, _) in &children {
+            anyhow::ensure!(sink.specs() == spec, "tee output {name} takes {:?}, the first one {spec:?}", sink.specs());
+            anyhow::ensure!(sink.channel_positions() == positions, "tee output {name} is mapped {}, the first one {}",
+                sink.channel_positions().join(","), positions.join(","));
+        }
+        let children = children.into_iter()
+            .map(|(name, sink, policy)| Child::spawn(name, sink, policy))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { children, spec, positions })
+    }
+
+    fn send(&mut self, message: impl Fn() -> Message) -> anyhow::Result<()> {
+        self.children.retain_mut(|child| match child.push(message()) {
+            Ok(()) => true,
+            Err(e) => {
+                eprintln!("tee: dropping output {}: {e}", child.name);
+                false
+            }
+        });
+        anyhow::ensure!(!self.children.is_empty(), "all tee outputs failed");
+        Ok(())
+    }
+}
+
+impl AudioSink for TeeSink {
+    fn write(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        self.send(|| Message::Data(bytes.to_vec()))
+    }
+
+    fn specs(&self) -> Spec {
+        self.spec
+    }
+
+    fn channel_positions(&self) -> Vec<&'static str> {
+        self.positions.clone()
+    }
+
+    fn deactivate(&mut self) -> anyhow::Result<()> {
+        self.send(|| Message::Deactivate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use libpulse_binding::sample::Format;
+
+    /// Records what it is given, `delay` per write
+    struct Slow(Arc<Mutex<Vec<u8>>>, Duration);
+    impl AudioSink for Slow {
+        fn write(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+            thread::sleep(self.1);
+            self.0.lock().unwrap().extend_from_slice(bytes);
+            Ok(())
+        }
+
+        fn specs(&self) -> Spec {
+            Spec { format: Format::S16le, rate: 48_000, channels: 2 }
+        }
+    }
+
+    #[test]
+    fn slow_child_drops_without_stalling_the_others() {
+        let (fast, slow) = (Arc::default(), Arc::default());
+        let mut tee = TeeSink::new(vec![
+            ("fast".into(), Box::new(Slow(Arc::clone(&fast), Duration::ZERO)), DropPolicy::Block),
+            ("slow".into(), Box::new(Slow(Arc::clone(&slow), Duration::from_millis(20))), DropPolicy::Oldest),
+        ]).unwrap();
+        for i in 0..64u8 {
+            tee.write(&[i; 4]).unwrap();
+        }
+        drop(tee);
+
+        assert_eq!(fast.lock().unwrap().len(), 64 * 4);
+        let slow = slow.lock().unwrap();
+        assert!(slow.len() < 64 * 4);
+        assert_eq!(slow.last(), Some(&63)); // the newest chunks survive
+    }
+
+    /// Logs the first byte of each write and None for a deactivate, each write waits for a permit
+    struct Gated(Arc<Mutex<Vec<Option<u8>>>>, mpsc::Receiver<()>);
+    impl AudioSink for Gated {
+        fn write(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+            self.1.recv()?;
+            self.0.lock().unwrap().push(Some(bytes[0]));
+            Ok(())
+        }
+
+        fn specs(&self) -> Spec {
+            Spec { format: Format::S16le, rate: 48_000, channels: 2 }
+        }
+
+        fn deactivate(&mut self) -> anyhow::Result<()> {
+            self.0.lock().unwrap().push(None);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn deactivate_does_not_evict_queued_data() {
+        let (log, (permits, gate)) = (Arc::default(), mpsc::channel());
+        let mut tee = TeeSink::new(vec![("gated".into(), Box::new(Gated(Arc::clone(&log), gate)), DropPolicy::Oldest)]).unwrap();
+        tee.write(&[0; 4]).unwrap();
+        while !tee.children[0].queue.lock().messages.is_empty() {
+            thread::yield_now(); // chunk 0 is in the sink, waiting for its permit
+        }
+        for i in 1..=QUEUE_CHUNKS as u8 {
+            tee.write(&[i; 4]).unwrap();
+        }
+        tee.deactivate().unwrap();
+        for _ in 0..=QUEUE_CHUNKS {
+            permits.send(()).unwrap();
+        }
+        drop(tee);
+
+        let expected: Vec<Option<u8>> = (0..=QUEUE_CHUNKS as u8).map(Some).chain([None]).collect();
+        assert_eq!(*log.lock().unwrap(), expected);
+    }
+
+    /// Takes anything, wired as `self.0`
+    struct Mapped(Vec<&'static str>);
+    impl AudioSink for Mapped {
+        fn write(&mut self, _: &[u8]) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn specs(&self) -> Spec {
+            Spec { format: Format::S16le, rate: 48_000, channels: 2 }
+        }
+
+        fn channel_positions(&self) -> Vec<&'static str> {
+            self.0.clone()
+        }
+    }
+
+    #[test]
+    fn children_share_the_channel_map() {
+        let tee = |second: Vec<&'static str>| TeeSink::new(vec![
+            ("first".into(), Box::new(Mapped(vec!["FL", "FR"])), DropPolicy::Block),
+            ("second".into(), Box::new(Mapped(second)), DropPolicy::Block),
+        ]);
+        assert!(tee(vec!["FL", "FR"]).is_ok());
+        assert!(tee(vec!["FR", "FL"]).is_err());
+    }
+}
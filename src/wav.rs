@@ -0,0 +1,230 @@
+/* WAV recording sink: WAVE_FORMAT_EXTENSIBLE with the decoders' channel mask, RF64 past 4 GiB */
+// The header reserves a JUNK chunk the size of a ds64 chunk (EBU Tech 3306), so a take
+// that outgrows 32-bit sizes is turned into RF64 in place when its sizes are patched.
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use anyhow::Context;
+use libpulse_binding::sample::{Format, Spec};
+use crate::decoders::channel_positions;
+use crate::sinks::AudioSink;
+
+/// RIFF/RF64 header, JUNK/ds64 chunk, fmt chunk and data chunk header
+const HEADER_BYTES: usize = 12 + 8 + 28 + 8 + 40 + 8;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xfffe;
+const WAVE_FORMAT_PCM: u32 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u32 = 3;
+/// Tail of the KSDATAFORMAT_SUBTYPE_* GUIDs, after the format tag
+const SUBTYPE_TAIL: [u8; 12] = [0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xaa, 0x00, 0x38, 0x9b, 0x71];
+
+/// Container bits, valid bits and subtype of the formats a WAV file stores as-is
+fn wav_format(format: Format) -> Option<(u16, u16, u32)> {
+    match format {
+        Format::U8 => Some((8, 8, WAVE_FORMAT_PCM)),
+        Format::S16le => Some((16, 16, WAVE_FORMAT_PCM)),
+        Format::S24le => Some((24, 24, WAVE_FORMAT_PCM)),
+        Format::S32le => Some((32, 32, WAVE_FORMAT_PCM)),
+        Format::F32le => Some((32, 32, WAVE_FORMAT_IEEE_FLOAT)),
+        _ => None,
+    }
+}
+
+/// dwChannelMask of ffmpeg's default layout, the order the decoders write channels in
+pub fn channel_mask(channels: u8) -> u32 {
+    channel_positions(channels).iter().fold(0, |mask, position| mask | match *position {
+        "FL" => 0x1,
+        "FR" => 0x2,
+        "FC" | "MONO" => 0x4,
+        "LFE" => 0x8,
+        "RL" => 0x10,
+        "RR" => 0x20,
+        "RC" => 0x100,
+        "SL" => 0x200,
+        "SR" => 0x400,
+        _ => 0,
+    })
+}
+
+/// The header of a take holding `data_bytes`, or of one still being recorded (sizes set to
+/// 0xFFFFFFFF, which readers take as "up to the end of the file")
+fn header(spec: Spec, data_bytes: Option<u64>) -> Vec<u8> {
+    let (bits, valid_bits, subtype) = wav_format(spec.format).unwrap_or((16, 16, WAVE_FORMAT_PCM));
+    let block_align = spec.channels as u16 * bits / 8;
+    let riff_bytes = data_bytes.map(|d| (HEADER_BYTES - 8) as u64 + d + d % 2);
+    let rf64 = riff_bytes.is_some_and(|r| r > u32::MAX as u64);
+    let size32 = |size: Option<u64>| match size {
+        Some(size) if !rf64 => size as u32,
+        _ => u32::MAX,
+    };
+
+    let mut h = Vec::with_capacity(HEADER_BYTES);
+    h.extend_from_slice(if rf64 { b"RF64" } else { b"RIFF" });
+    h.extend_from_slice(&size32(riff_bytes).to_le_bytes());
+    h.extend_from_slice(b"WAVE");
+    h.extend_from_slice(if rf64 { b"ds64" } else { b"JUNK" });
+    h.extend_from_slice(&28u32.to_le_bytes());
+    if let (true, Some(riff_bytes), Some(data_bytes)) = (rf64, riff_bytes, data_bytes) {
+        h.extend_from_slice(&riff_bytes.to_le_bytes());
+        h.extend_from_slice(&data_bytes.to_le_bytes());
+        h.extend_from_slice(&(data_bytes / block_align as u64).to_le_bytes());
+        h.extend_from_slice(&0u32.to_le_bytes()); // no table
+    } else {
+        h.extend_from_slice(&[0; 28]);
+    }
+    h.extend_from_slice(b"fmt ");
+    h.extend_from_slice(&40u32.to_le_bytes());
+    h.extend_from_slice(&WAVE_FORMAT_EXTENSIBLE.to_le_bytes());
+    h.extend_from_slice(&(spec.channels as u16).to_le_bytes());
+    h.extend_from_slice(&spec.rate.to_le_bytes());
+    h.extend_from_slice(&(spec.rate * block_align as u32).to_le_bytes());
+    h.extend_from_slice(&block_align.to_le_bytes());
+    h.extend_from_slice(&bits.to_le_bytes());
+    h.extend_from_slice(&22u16.to_le_bytes());
+    h.extend_from_slice(&valid_bits.to_le_bytes());
+    h.extend_from_slice(&channel_mask(spec.channels).to_le_bytes());
+    h.extend_from_slice(&subtype.to_le_bytes());
+    h.extend_from_slice(&SUBTYPE_TAIL);
+    h.extend_from_slice(b"data");
+    h.extend_from_slice(&size32(data_bytes).to_le_bytes());
+    h
+}
+
+/// Records what it is given to WAV files. The sizes are patched on `finish` (and on drop),
+/// with `split` each mode switch closes the take and the next write starts `<name>-2.wav`, `-3`…
+pub struct WavSink {
+    path: PathBuf,
+    spec: Spec,
+    split: bool,
+    take: usize,
+    file: Option<BufWriter<File>>,
+    data_bytes: u64,
+}
+
+impl WavSink {
+    pub fn create(path: &Path, format: Format, rate: u32, channels: u8, split: bool) -> anyhow::Result<Self> {
+        let spec = Spec { format, rate, channels };
+        anyhow::ensure!(spec.is_valid(), "Invalid sample spec");
+        anyhow::ensure!(wav_format(format).is_some(), "WAV cannot store {format:?} samples, use U8, S16LE, S24LE, S32LE or F32LE");
+        let mut sink = Self { path: path.to_path_buf(), spec, split, take: 0, file: None, data_bytes: 0 };
+        sink.open_take()?;
+        Ok(sink)
+    }
+
+    /// Path of take `take`, counting from 1
+    fn take_path(&self, take: usize) -> PathBuf {
+        if take == 1 {
+            return self.path.clone();
+        }
+        let stem = self.path.file_stem().unwrap_or_default().to_string_lossy();
+        let name = match self.path.extension() {
+            Some(ext) => format!("{stem}-{take}.{}", ext.to_string_lossy()),
+            None => format!("{stem}-{take}"),
+        };
+        self.path.with_file_name(name)
+    }
+
+    fn open_take(&mut self) -> anyhow::Result<()> {
+        self.take += 1;
+        let path = self.take_path(self.take);
+        let mut file = BufWriter::new(File::create(&path).with_context(|| format!("creating {}", path.display()))?);
+        file.write_all(&header(self.spec, None)).context("writing WAV header")?;
+        self.file = Some(file);
+        self.data_bytes = 0;
+        Ok(())
+    }
+
+    /// Write the final sizes of the current take, switching it to RF64 if it needs 64-bit sizes
+    pub fn finish(&mut self) -> anyhow::Result<()> {
+        let Some(file) = &mut self.file else { return Ok(()) };
+        if self.data_bytes % 2 == 1 {
+            file.write_all(&[0]).context("writing WAV pad byte")?;
+        }
+        file.seek(SeekFrom::Start(0)).context("seeking to WAV header")?;
+        file.write_all(&header(self.spec, Some(self.data_bytes))).context("patching WAV header")?;
+        file.seek(SeekFrom::Start(HEADER_BYTES as u64 + self.data_bytes)).context("seeking to WAV data end")?;
+        file.flush().context("flushing WAV file")
+    }
+}
+
+impl AudioSink for WavSink {
+    fn write(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        if self.file.is_none() {
+            self.open_take()?;
+        }
+        let file = self.file.as_mut().context("no WAV take open")?;
+        file.write_all(bytes).context("writing WAV data")?;
+        self.data_bytes += bytes.len() as u64;
+        Ok(())
+    }
+
+    fn specs(&self) -> Spec {
+        self.spec
+    }
+
+    fn deactivate(&mut self) -> anyhow::Result<()> {
+        if self.file.is_none() || self.data_bytes == 0 {
+            return Ok(());
+        }
+        self.finish()?;
+        if self.split {
+            eprintln!("Closed {} ({} bytes).", self.take_path(self.take).display(), self.data_bytes);
+            self.file = None;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for WavSink {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            eprintln!("WAV sink: {e:#}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u32_at(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn extensible_header_and_rf64() {
+        let spec = Spec { format: Format::F32le, rate: 48_000, channels: 6 };
+        let h = header(spec, Some(4800));
+        assert_eq!(h.len(), HEADER_BYTES);
+        assert_eq!((&h[..4], u32_at(&h, 4), &h[12..16]), (&b"RIFF"[..], 96 + 4800, &b"JUNK"[..]));
+        assert_eq!(u32::from_le_bytes([h[56], h[57], 0, 0]), 0xfffe);
+        assert_eq!(u32_at(&h, 76), 0x60f); // FL FR FC LFE SL SR
+        assert_eq!(u32_at(&h, 80), WAVE_FORMAT_IEEE_FLOAT);
+        assert_eq!(u32_at(&h, 100), 4800);
+        assert_eq!(channel_mask(8), 0x63f);
+
+        let big = 5 << 30;
+        let h = header(spec, Some(big));
+        assert_eq!((&h[..4], u32_at(&h, 4), &h[12..16], u32_at(&h, 100)), (&b"RF64"[..], u32::MAX, &b"ds64"[..], u32::MAX));
+        assert_eq!(u64::from_le_bytes(h[28..36].try_into().unwrap()), big);
+        assert_eq!(u64::from_le_bytes(h[36..44].try_into().unwrap()), big / 24);
+    }
+
+    #[test]
+    fn takes_are_patched_and_split() {
+        let path = std::env::temp_dir().join(format!("pad-wav-{}.wav", std::process::id()));
+        let mut sink = WavSink::create(&path, Format::S16le, 48_000, 2, true).unwrap();
+        sink.write(&[1; 400]).unwrap();
+        sink.deactivate().unwrap();
+        sink.deactivate().unwrap();
+        sink.write(&[2; 40]).unwrap();
+        drop(sink);
+
+        let second = path.with_file_name(format!("pad-wav-{}-2.wav", std::process::id()));
+        for (path, data) in [(&path, 400), (&second, 40)] {
+            let bytes = std::fs::read(path).unwrap();
+            assert_eq!(bytes.len(), HEADER_BYTES + data);
+            assert_eq!((u32_at(&bytes, 4), u32_at(&bytes, 100)), (96 + data as u32, data as u32));
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}